@@ -0,0 +1,549 @@
+//! A local admin endpoint for a running node. Requests and responses are JSON-RPC 2.0 objects,
+//! one per line, exchanged over a Unix domain socket.
+
+use crate::node::{Node, NodeStats};
+use crate::node_id::{Key, NodeId};
+use crate::structures::FoundNode;
+use crate::{error_log, DEFAULT_TTL};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The request was valid, but the node failed to carry it out.
+pub const OPERATION_FAILED: i64 = -32000;
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct Request {
+    pub jsonrpc: String,
+    #[serde(default)]
+    pub id: JsonValue,
+    pub method: String,
+    #[serde(default)]
+    pub params: JsonValue,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: JsonValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<JsonValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct AddPeerResult {
+    pub address: SocketAddr,
+    pub node_id: NodeId,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct PingResult {
+    pub address: SocketAddr,
+    pub node_id: NodeId,
+    pub rtt_ms: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct PeerInfo {
+    pub active: bool,
+    pub address: SocketAddr,
+    pub failed_requests: u32,
+    pub first_seen: u64,
+    pub last_seen: Option<u64>,
+    pub node_id: NodeId,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct StoreValueResult {
+    pub key: Key,
+    pub stored_count: usize,
+}
+
+/// A value as returned by `get_value`. The data is decoded as UTF-8, replacing invalid bytes.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct ValueInfo {
+    pub expires_at: u64,
+    pub key: Key,
+    pub published_at: u64,
+    pub publisher: NodeId,
+    pub value: String,
+}
+
+#[derive(Deserialize)]
+struct AddressParams {
+    address: SocketAddr,
+}
+
+#[derive(Deserialize)]
+struct KeyParams {
+    key: Key,
+}
+
+#[derive(Deserialize)]
+struct StoreValueParams {
+    key: Key,
+    #[serde(default)]
+    ttl: Option<u64>,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct FindNodeParams {
+    node_id: NodeId,
+}
+
+/// Serves admin requests for a node over a Unix domain socket. The methods are `add_peer`,
+/// `ping`, `store_value`, `get_value`, `list_peers`, `find_node`, `stats` and `shutdown`.
+pub struct AdminServer {
+    accept_thread: JoinHandle<()>,
+    closed: Arc<AtomicBool>,
+    path: String,
+}
+
+impl AdminServer {
+    /// Binds the socket, replacing one left behind by a node that did not shut down cleanly.
+    /// `on_shutdown` is called when a client asks the node to shut down.
+    pub fn bind<F>(path: &str, node: Arc<Node>, on_shutdown: F) -> Result<Self, String>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let listener = match UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(error) if error.kind() == ErrorKind::AddrInUse => {
+                // A socket nobody accepts on was left behind by a node that did not shut down
+                // cleanly, so it is safe to replace
+                if UnixStream::connect(path).is_ok() {
+                    return Err(format!("Admin socket {} is in use by another node", path));
+                }
+
+                fs::remove_file(path).map_err(|error| {
+                    format!("Failed to remove stale admin socket {}: {}", path, error)
+                })?;
+
+                UnixListener::bind(path)
+                    .map_err(|error| format!("Failed to bind admin socket {}: {}", path, error))?
+            }
+            Err(error) => return Err(format!("Failed to bind admin socket {}: {}", path, error)),
+        };
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let closed_clone = closed.clone();
+        let on_shutdown: Arc<dyn Fn() + Send + Sync> = Arc::new(on_shutdown);
+
+        let accept_thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if closed_clone.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        error_log(format!("Failed to accept admin connection: {}", error));
+                        continue;
+                    }
+                };
+
+                let node = node.clone();
+                let on_shutdown = on_shutdown.clone();
+
+                thread::spawn(move || {
+                    if let Err(error) = serve_connection(stream, &node, on_shutdown.as_ref()) {
+                        error_log(error);
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            accept_thread,
+            closed,
+            path: path.to_string(),
+        })
+    }
+
+    /// Stops accepting connections and removes the socket file.
+    pub fn close(self) {
+        self.closed.store(true, Ordering::Relaxed);
+
+        // Wake the accepting thread so it sees the socket is closed
+        let _ = UnixStream::connect(&self.path);
+        let _ = self.accept_thread.join();
+
+        if let Err(error) = fs::remove_file(&self.path) {
+            error_log(format!(
+                "Failed to remove admin socket {}: {}",
+                self.path, error
+            ));
+        }
+    }
+}
+
+/// Sends requests to a node's admin socket, one at a time.
+pub struct AdminClient {
+    next_id: u64,
+    reader: BufReader<UnixStream>,
+}
+
+impl AdminClient {
+    pub fn connect(path: &str) -> Result<Self, String> {
+        let stream = UnixStream::connect(path)
+            .map_err(|error| format!("Failed to connect to admin socket {}: {}", path, error))?;
+
+        Ok(Self {
+            next_id: 1,
+            reader: BufReader::new(stream),
+        })
+    }
+
+    /// Calls a method and returns its result, or the error the node answered with.
+    pub fn call(&mut self, method: &str, params: JsonValue) -> Result<JsonValue, String> {
+        let request = Request {
+            jsonrpc: "2.0".to_string(),
+            id: JsonValue::from(self.next_id),
+            method: method.to_string(),
+            params,
+        };
+        self.next_id += 1;
+
+        let request = serde_json::to_string(&request)
+            .map_err(|error| format!("Failed to serialize admin request: {}", error))?;
+
+        writeln!(self.reader.get_mut(), "{}", request)
+            .map_err(|error| format!("Failed to send admin request: {}", error))?;
+
+        let mut line = String::new();
+        let read = self
+            .reader
+            .read_line(&mut line)
+            .map_err(|error| format!("Failed to read admin response: {}", error))?;
+
+        if read == 0 {
+            return Err("The node closed the admin connection".to_string());
+        }
+
+        let response: Response = serde_json::from_str(&line)
+            .map_err(|error| format!("Invalid admin response: {}", error))?;
+
+        match response.error {
+            Some(error) => Err(error.message),
+            None => Ok(response.result.unwrap_or(JsonValue::Null)),
+        }
+    }
+}
+
+fn serve_connection(
+    stream: UnixStream,
+    node: &Node,
+    on_shutdown: &(dyn Fn() + Send + Sync),
+) -> Result<(), String> {
+    let mut writer = stream
+        .try_clone()
+        .map_err(|error| format!("Failed to clone admin connection: {}", error))?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|error| format!("Failed to read admin request: {}", error))?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = handle_line(&line, node, on_shutdown);
+        let response = serde_json::to_string(&response)
+            .map_err(|error| format!("Failed to serialize admin response: {}", error))?;
+
+        writeln!(writer, "{}", response)
+            .map_err(|error| format!("Failed to reply to admin request: {}", error))?;
+    }
+
+    Ok(())
+}
+
+fn handle_line(line: &str, node: &Node, on_shutdown: &(dyn Fn() + Send + Sync)) -> Response {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(error) if error.is_syntax() || error.is_eof() => {
+            return error_response(JsonValue::Null, PARSE_ERROR, format!("{}", error));
+        }
+        Err(error) => {
+            return error_response(JsonValue::Null, INVALID_REQUEST, format!("{}", error));
+        }
+    };
+
+    if request.jsonrpc != "2.0" {
+        return error_response(
+            request.id,
+            INVALID_REQUEST,
+            format!("Unsupported JSON-RPC version \"{}\"", request.jsonrpc),
+        );
+    }
+
+    match call(node, &request.method, request.params, on_shutdown) {
+        Ok(result) => Response {
+            jsonrpc: "2.0".to_string(),
+            id: request.id,
+            result: Some(result),
+            error: None,
+        },
+        Err(error) => Response {
+            jsonrpc: "2.0".to_string(),
+            id: request.id,
+            result: None,
+            error: Some(error),
+        },
+    }
+}
+
+/// Runs an admin method against the node. The socket server and the node's terminal both go
+/// through here, so they offer the same methods.
+pub fn call(
+    node: &Node,
+    method: &str,
+    params: JsonValue,
+    on_shutdown: &(dyn Fn() + Send + Sync),
+) -> Result<JsonValue, RpcError> {
+    match method {
+        "add_peer" => {
+            let params: AddressParams = parse_params(method, params)?;
+            let node_id = node.ping(&params.address).map_err(operation_failed)?;
+
+            to_result(AddPeerResult {
+                address: params.address,
+                node_id,
+            })
+        }
+        "find_node" => {
+            let params: FindNodeParams = parse_params(method, params)?;
+            let nodes: Vec<FoundNode> =
+                node.find_node(&params.node_id).map_err(operation_failed)?;
+
+            to_result(nodes)
+        }
+        "get_value" => {
+            let params: KeyParams = parse_params(method, params)?;
+            let value = node.get(&params.key).map_err(operation_failed)?;
+
+            to_result(ValueInfo {
+                expires_at: value.expires_at(),
+                key: params.key,
+                published_at: value.published_at,
+                publisher: value.publisher,
+                value: String::from_utf8_lossy(&value.data).into_owned(),
+            })
+        }
+        "list_peers" => {
+            let peers: Vec<PeerInfo> = node
+                .peers()
+                .into_iter()
+                .map(|peer| PeerInfo {
+                    active: peer.active,
+                    address: peer.address,
+                    failed_requests: peer.failed_requests,
+                    first_seen: peer.first_seen,
+                    last_seen: peer.last_seen,
+                    node_id: peer.node_id,
+                })
+                .collect();
+
+            to_result(peers)
+        }
+        "ping" => {
+            let params: AddressParams = parse_params(method, params)?;
+            let sent_at = Instant::now();
+            let node_id = node.ping(&params.address).map_err(operation_failed)?;
+
+            to_result(PingResult {
+                address: params.address,
+                node_id,
+                rtt_ms: sent_at.elapsed().as_millis() as u64,
+            })
+        }
+        "shutdown" => {
+            on_shutdown();
+            Ok(JsonValue::Null)
+        }
+        "stats" => to_result::<NodeStats>(node.stats()),
+        "store_value" => {
+            let params: StoreValueParams = parse_params(method, params)?;
+            let stored_count = node
+                .put(
+                    &params.key,
+                    params.value.into_bytes(),
+                    params.ttl.unwrap_or(DEFAULT_TTL),
+                )
+                .map_err(operation_failed)?;
+
+            to_result(StoreValueResult {
+                key: params.key,
+                stored_count,
+            })
+        }
+        method => Err(RpcError {
+            code: METHOD_NOT_FOUND,
+            message: format!("Unknown method \"{}\"", method),
+        }),
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(method: &str, params: JsonValue) -> Result<T, RpcError> {
+    // Methods without parameters may omit them entirely
+    let params = match params {
+        JsonValue::Null => JsonValue::Object(Default::default()),
+        params => params,
+    };
+
+    serde_json::from_value(params).map_err(|error| RpcError {
+        code: INVALID_PARAMS,
+        message: format!("Invalid parameters for {}: {}", method, error),
+    })
+}
+
+fn to_result<T: Serialize>(result: T) -> Result<JsonValue, RpcError> {
+    serde_json::to_value(result).map_err(|error| RpcError {
+        code: OPERATION_FAILED,
+        message: format!("Failed to serialize result: {}", error),
+    })
+}
+
+fn operation_failed(message: String) -> RpcError {
+    RpcError {
+        code: OPERATION_FAILED,
+        message,
+    }
+}
+
+fn error_response(id: JsonValue, code: i64, message: String) -> Response {
+    Response {
+        jsonrpc: "2.0".to_string(),
+        id,
+        result: None,
+        error: Some(RpcError { code, message }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryNetwork;
+    use serde_json::json;
+    use std::env;
+    use std::sync::mpsc;
+
+    fn request(stream: &mut BufReader<UnixStream>, line: &str) -> Response {
+        writeln!(stream.get_mut(), "{}", line).unwrap();
+
+        let mut response = String::new();
+        stream.read_line(&mut response).unwrap();
+
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[test]
+    fn test_admin_server_answers_json_rpc_requests() {
+        let path = env::temp_dir().join(format!("admin-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let network = MemoryNetwork::new();
+        let transport = network
+            .bind(&SocketAddr::from(([10, 0, 0, 1], 16700)))
+            .unwrap();
+        let node = Arc::new(
+            Node::builder()
+                .transport(Arc::new(transport))
+                .build()
+                .unwrap(),
+        );
+
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+        let admin_server = AdminServer::bind(path, node.clone(), move || {
+            let _ = shutdown_tx.send(());
+        })
+        .unwrap();
+
+        let mut stream = BufReader::new(UnixStream::connect(path).unwrap());
+        let key = Key::random();
+
+        let response = request(
+            &mut stream,
+            &json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "store_value",
+                "params": {"key": key, "value": "hello"},
+            })
+            .to_string(),
+        );
+        assert_eq!(response.id, json!(1));
+        assert_eq!(
+            response.result.unwrap(),
+            json!({"key": key, "stored_count": 0})
+        );
+
+        let response = request(
+            &mut stream,
+            &json!({"jsonrpc": "2.0", "id": 2, "method": "get_value", "params": {"key": key}})
+                .to_string(),
+        );
+        let value: ValueInfo = serde_json::from_value(response.result.unwrap()).unwrap();
+        assert_eq!(value.value, "hello");
+        assert_eq!(value.publisher, node.node_id());
+
+        let response = request(
+            &mut stream,
+            r#"{"jsonrpc": "2.0", "id": "stats", "method": "stats"}"#,
+        );
+        let stats: NodeStats = serde_json::from_value(response.result.unwrap()).unwrap();
+        assert_eq!(stats.node_id, node.node_id());
+        assert_eq!(stats.value_count, 1);
+
+        let response = request(
+            &mut stream,
+            r#"{"jsonrpc": "2.0", "id": 3, "method": "get_value", "params": {"key": "abc"}}"#,
+        );
+        assert_eq!(response.error.unwrap().code, INVALID_PARAMS);
+
+        let response = request(
+            &mut stream,
+            r#"{"jsonrpc": "2.0", "id": 4, "method": "nope"}"#,
+        );
+        assert_eq!(response.error.unwrap().code, METHOD_NOT_FOUND);
+
+        let response = request(&mut stream, "not json");
+        assert_eq!(response.id, JsonValue::Null);
+        assert_eq!(response.error.unwrap().code, PARSE_ERROR);
+
+        let mut client = AdminClient::connect(path).unwrap();
+        assert_eq!(
+            client.call("get_value", json!({"key": "abc"})).unwrap_err(),
+            "Invalid parameters for get_value: Invalid ID \"abc\", expected 40 hex characters."
+        );
+        assert_eq!(
+            client.call("shutdown", JsonValue::Null).unwrap(),
+            JsonValue::Null
+        );
+        shutdown_rx.recv().unwrap();
+
+        admin_server.close();
+        assert!(fs::metadata(path).is_err());
+    }
+}
@@ -1,10 +1,104 @@
+use client_server_test::{
+    LogLevel, Timeouts, ALPHA, BUCKET_SIZE, MAX_FAILED_REQUESTS, REPLACEMENT_CACHE_SIZE,
+    REQUEST_ATTEMPTS,
+};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Settings that can also be given in a config file, or in the environment as `DHT_<SETTING>`.
+pub const SETTINGS: &[&str] = &[
+    "alpha",
+    "bind_address",
+    "bootstrap",
+    "bucket_size",
+    "control_socket",
+    "daemon",
+    "data_dir",
+    "log_level",
+    "max_failed_requests",
+    "max_request_timeout_ms",
+    "min_request_timeout_ms",
+    "pid_file",
+    "port",
+    "replacement_cache_size",
+    "request_attempts",
+    "request_timeout_ms",
+    "state_file",
+    "transport",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TransportKind {
+    Tcp,
+    Udp,
+}
+
+impl TransportKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransportKind::Tcp => "tcp",
+            TransportKind::Udp => "udp",
+        }
+    }
+}
+
 pub struct Arguments {
+    pub alpha: usize,
     pub bind_address: String,
+    pub bootstrap_peers: Vec<SocketAddr>,
+    pub bucket_size: usize,
+    pub check_config: bool,
+    pub config_file: Option<String>,
+    pub control_socket: Option<String>,
+    pub daemon: bool,
+    pub data_dir: Option<String>,
+    pub export_state: Option<String>,
+    pub import_state: Option<String>,
+    pub log_level: LogLevel,
+    pub max_failed_requests: u32,
+    pub pid_file: String,
     pub port: u16,
+    pub replacement_cache_size: usize,
+    pub request_attempts: u32,
+    pub request_timeouts: Timeouts,
     pub state_file: String,
+    pub transport: TransportKind,
+}
+
+impl Default for Arguments {
+    fn default() -> Self {
+        Self {
+            alpha: ALPHA,
+            bind_address: String::from("0.0.0.0"),
+            bootstrap_peers: Vec::new(),
+            bucket_size: BUCKET_SIZE,
+            check_config: false,
+            config_file: None,
+            control_socket: None,
+            daemon: false,
+            data_dir: None,
+            export_state: None,
+            import_state: None,
+            log_level: LogLevel::Trace,
+            max_failed_requests: MAX_FAILED_REQUESTS,
+            pid_file: String::from("node.pid"),
+            port: 16600,
+            replacement_cache_size: REPLACEMENT_CACHE_SIZE,
+            request_attempts: REQUEST_ATTEMPTS,
+            request_timeouts: Timeouts::default(),
+            state_file: String::from("state.bin"),
+            transport: TransportKind::Udp,
+        }
+    }
 }
 
 pub fn parse_arguments(args: Vec<String>) -> Result<Arguments, String> {
+    parse_arguments_over(args, Arguments::default())
+}
+
+/// Parses the command line on top of settings that were already loaded, so flags take precedence
+/// over the config file and environment.
+pub fn parse_arguments_over(args: Vec<String>, base: Arguments) -> Result<Arguments, String> {
     let binary_name = args[0].clone();
     let args: Vec<String> = args
         .into_iter()
@@ -19,9 +113,9 @@ pub fn parse_arguments(args: Vec<String>) -> Result<Arguments, String> {
         })
         .collect();
 
-    let mut port: u16 = 16600;
-    let mut state_file = String::from("state.bin");
-    let mut bind_address = String::from("0.0.0.0");
+    let mut arguments = base;
+    // Bootstrap peers given on the command line replace any from the config, rather than adding
+    let mut bootstrap_peers = Vec::new();
 
     let mut current_index = 0;
 
@@ -34,7 +128,87 @@ pub fn parse_arguments(args: Vec<String>) -> Result<Arguments, String> {
                     return Err("No bind address provided.".to_string());
                 }
 
-                bind_address = args[current_index + 1].clone();
+                arguments.bind_address = args[current_index + 1].clone();
+
+                current_index += 1;
+            }
+            "--bootstrap" => {
+                if current_index + 1 >= args.len() {
+                    return Err("No bootstrap peer provided.".to_string());
+                }
+
+                let bootstrap_peer = match args[current_index + 1].parse() {
+                    Ok(bootstrap_peer) => bootstrap_peer,
+                    Err(error) => {
+                        return Err(format!(
+                            "Invalid bootstrap peer provided \"{}\", {}.",
+                            args[current_index + 1],
+                            error
+                        ));
+                    }
+                };
+
+                bootstrap_peers.push(bootstrap_peer);
+
+                current_index += 1;
+            }
+            "--check-config" => {
+                arguments.check_config = true;
+            }
+            "-c" | "--config" => {
+                if current_index + 1 >= args.len() {
+                    return Err("No config file provided.".to_string());
+                }
+
+                arguments.config_file = Some(args[current_index + 1].clone());
+
+                current_index += 1;
+            }
+            "--alpha"
+            | "--bucket-size"
+            | "--log-level"
+            | "--max-failed-requests"
+            | "--max-request-timeout-ms"
+            | "--min-request-timeout-ms"
+            | "--replacement-cache-size"
+            | "--request-attempts"
+            | "--request-timeout-ms" => {
+                if current_index + 1 >= args.len() {
+                    return Err(format!("No value provided for {}.", arg));
+                }
+
+                let setting = arg.trim_start_matches('-').replace('-', "_");
+                set_setting(&mut arguments, &setting, &args[current_index + 1])?;
+
+                current_index += 1;
+            }
+            "--control-socket" => {
+                if current_index + 1 >= args.len() {
+                    return Err("No control socket provided.".to_string());
+                }
+
+                arguments.control_socket = Some(args[current_index + 1].clone());
+
+                current_index += 1;
+            }
+            "-d" | "--daemon" | "--no-terminal" => {
+                arguments.daemon = true;
+            }
+            "--data-dir" => {
+                if current_index + 1 >= args.len() {
+                    return Err("No data directory provided.".to_string());
+                }
+
+                arguments.data_dir = Some(args[current_index + 1].clone());
+
+                current_index += 1;
+            }
+            "--export-state" => {
+                if current_index + 1 >= args.len() {
+                    return Err("No export file provided.".to_string());
+                }
+
+                arguments.export_state = Some(args[current_index + 1].clone());
 
                 current_index += 1;
             }
@@ -44,25 +218,63 @@ pub fn parse_arguments(args: Vec<String>) -> Result<Arguments, String> {
                 println!(
                     "  -b, --bind-address <address>  Bind address for the server. Default: 0.0.0.0"
                 );
+                println!("  --alpha <n>                   Lookup queries kept in flight at once. Default: {}", ALPHA);
+                println!("  --bootstrap <ip:port>         Peer to join the network through. Can be repeated.");
+                println!("  --bucket-size <n>             Peers per routing table bucket, Kademlia's k. Default: {}", BUCKET_SIZE);
+                println!("  -c, --config <file>           TOML config file to read settings from. Default: $DHT_CONFIG");
+                println!("  --check-config                Check the merged settings, print them as a config file, then exit.");
+                println!("  --control-socket <path>       Unix socket to accept JSON-RPC admin requests on. Default: node.sock with --daemon");
+                println!("  -d, --daemon, --no-terminal   Run without reading commands from stdin and write a pid file.");
+                println!("  --data-dir <directory>        Keep identity, peers, values and logs in separate files here instead of the state file.");
+                println!("  --export-state <file>         Write the state file out as .json or .toml, then exit.");
                 println!("  -h, --help                    Display this help message.");
+                println!("  --import-state <file>         Replace the state file with a .json or .toml export, then exit.");
+                println!("  --log-level <level>           Most detailed logs to show: error, debug or trace. Default: trace");
+                println!("  --max-failed-requests <n>     Failed requests in a row before a peer is evicted. Default: {}", MAX_FAILED_REQUESTS);
+                println!("  --max-request-timeout-ms <ms> Longest a request waits for a response. Default: {}", Timeouts::default().max.as_millis());
+                println!("  --min-request-timeout-ms <ms> Shortest a request waits for a response. Default: {}", Timeouts::default().min.as_millis());
+                println!("  --pid-file <file>             Pid file to write with --daemon. Default: node.pid");
                 println!("  -p, --port <port>             Port for the server to listen on. Default: 16600");
-                println!("  --state-file <file>           File to read and write state to. Default: state.toml");
-                println!("  --peer-file <file>            File to read and write peers to. Default: peers.bin");
+                println!("  --replacement-cache-size <n>  Peers each bucket keeps in reserve. Default: {}", REPLACEMENT_CACHE_SIZE);
+                println!("  --request-attempts <n>        Times a request is sent before it fails. Default: {}", REQUEST_ATTEMPTS);
+                println!("  --request-timeout-ms <ms>     Request timeout for peers without a measured RTT. Default: {}", Timeouts::default().initial.as_millis());
+                println!("  --state-file <file>           File to read and write state to. Default: state.bin");
+                println!("  --transport <udp|tcp>         Transport to talk to other nodes over. Default: udp");
+                println!("\nSettings are read from the config file, then from DHT_<SETTING> environment variables such as");
+                println!("DHT_BUCKET_SIZE, then from the options above, with later sources taking precedence.");
 
                 std::process::exit(0);
             }
+            "--import-state" => {
+                if current_index + 1 >= args.len() {
+                    return Err("No import file provided.".to_string());
+                }
+
+                arguments.import_state = Some(args[current_index + 1].clone());
+
+                current_index += 1;
+            }
+            "--pid-file" => {
+                if current_index + 1 >= args.len() {
+                    return Err("No pid file provided.".to_string());
+                }
+
+                arguments.pid_file = args[current_index + 1].clone();
+
+                current_index += 1;
+            }
             "-p" | "--port" => {
                 if current_index + 1 >= args.len() {
                     return Err("No port number provided.".to_string());
                 }
 
-                port = match args[current_index + 1].parse() {
+                arguments.port = match args[current_index + 1].parse() {
                     Ok(port) => port,
                     Err(error) => {
                         return Err(format!(
                             "Invalid port number provided \"{}\", {}.",
                             args[current_index + 1],
-                            error.to_string()
+                            error
                         ));
                     }
                 };
@@ -74,7 +286,25 @@ pub fn parse_arguments(args: Vec<String>) -> Result<Arguments, String> {
                     return Err("No state file provided.".to_string());
                 }
 
-                state_file = args[current_index + 1].clone();
+                arguments.state_file = args[current_index + 1].clone();
+
+                current_index += 1;
+            }
+            "--transport" => {
+                if current_index + 1 >= args.len() {
+                    return Err("No transport provided.".to_string());
+                }
+
+                arguments.transport = match args[current_index + 1].as_str() {
+                    "tcp" => TransportKind::Tcp,
+                    "udp" => TransportKind::Udp,
+                    other => {
+                        return Err(format!(
+                            "Invalid transport provided \"{}\", expected udp or tcp.",
+                            other
+                        ));
+                    }
+                };
 
                 current_index += 1;
             }
@@ -86,11 +316,81 @@ pub fn parse_arguments(args: Vec<String>) -> Result<Arguments, String> {
         current_index += 1;
     }
 
-    Ok(Arguments {
-        bind_address,
-        port,
-        state_file,
-    })
+    if arguments.export_state.is_some() && arguments.import_state.is_some() {
+        return Err(
+            "Only one of --export-state and --import-state can be used at a time.".to_string(),
+        );
+    }
+
+    if !bootstrap_peers.is_empty() {
+        arguments.bootstrap_peers = bootstrap_peers;
+    }
+
+    Ok(arguments)
+}
+
+/// Sets one of `SETTINGS` from its text form, as found in the environment or a config file.
+pub fn set_setting(arguments: &mut Arguments, setting: &str, value: &str) -> Result<(), String> {
+    match setting {
+        "alpha" => arguments.alpha = parse_setting(setting, value)?,
+        "bind_address" => arguments.bind_address = value.to_string(),
+        "bootstrap" => {
+            arguments.bootstrap_peers = value
+                .split(',')
+                .map(str::trim)
+                .filter(|peer| !peer.is_empty())
+                .map(|peer| parse_setting(setting, peer))
+                .collect::<Result<_, _>>()?;
+        }
+        "bucket_size" => arguments.bucket_size = parse_setting(setting, value)?,
+        "control_socket" => arguments.control_socket = Some(value.to_string()),
+        "daemon" => arguments.daemon = parse_setting(setting, value)?,
+        "data_dir" => arguments.data_dir = Some(value.to_string()),
+        "log_level" => arguments.log_level = value.parse()?,
+        "max_failed_requests" => arguments.max_failed_requests = parse_setting(setting, value)?,
+        "max_request_timeout_ms" => {
+            arguments.request_timeouts.max = Duration::from_millis(parse_setting(setting, value)?)
+        }
+        "min_request_timeout_ms" => {
+            arguments.request_timeouts.min = Duration::from_millis(parse_setting(setting, value)?)
+        }
+        "pid_file" => arguments.pid_file = value.to_string(),
+        "port" => arguments.port = parse_setting(setting, value)?,
+        "replacement_cache_size" => {
+            arguments.replacement_cache_size = parse_setting(setting, value)?
+        }
+        "request_attempts" => arguments.request_attempts = parse_setting(setting, value)?,
+        "request_timeout_ms" => {
+            arguments.request_timeouts.initial =
+                Duration::from_millis(parse_setting(setting, value)?)
+        }
+        "state_file" => arguments.state_file = value.to_string(),
+        "transport" => {
+            arguments.transport = match value {
+                "tcp" => TransportKind::Tcp,
+                "udp" => TransportKind::Udp,
+                other => {
+                    return Err(format!(
+                        "Invalid transport provided \"{}\", expected udp or tcp.",
+                        other
+                    ));
+                }
+            };
+        }
+        _ => return Err(format!("Unknown setting \"{}\"", setting)),
+    }
+
+    Ok(())
+}
+
+fn parse_setting<T>(setting: &str, value: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|error| format!("Invalid {} provided \"{}\", {}.", setting, value, error))
 }
 
 #[cfg(test)]
@@ -157,6 +457,120 @@ mod tests {
         assert_eq!(config.bind_address, "0.0.0.0");
         assert_eq!(config.port, 16600);
         assert_eq!(config.state_file, "state.bin");
+        assert_eq!(config.transport, TransportKind::Udp);
+        assert!(config.bootstrap_peers.is_empty());
+        assert!(!config.daemon);
+        assert_eq!(config.control_socket, None);
+        assert_eq!(config.data_dir, None);
+    }
+
+    #[test]
+    fn test_parse_arguments_export_and_import_state() {
+        let args = vec![
+            String::from("binary_name"),
+            String::from("--export-state=state.json"),
+        ];
+
+        let config = parse_arguments(args).unwrap();
+
+        assert_eq!(config.export_state, Some(String::from("state.json")));
+        assert_eq!(config.import_state, None);
+
+        let args = vec![
+            String::from("binary_name"),
+            String::from("--export-state=state.json"),
+            String::from("--import-state=state.toml"),
+        ];
+
+        assert!(parse_arguments(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_arguments_daemon() {
+        for flag in ["-d", "--daemon", "--no-terminal"] {
+            let args = vec![String::from("binary_name"), String::from(flag)];
+
+            assert!(parse_arguments(args).unwrap().daemon);
+        }
+
+        let args = vec![
+            String::from("binary_name"),
+            String::from("--daemon"),
+            String::from("--pid-file=override.pid"),
+            String::from("--control-socket=override.sock"),
+        ];
+
+        let config = parse_arguments(args).unwrap();
+
+        assert_eq!(config.pid_file, "override.pid");
+        assert_eq!(config.control_socket, Some(String::from("override.sock")));
+    }
+
+    #[test]
+    fn test_parse_arguments_bootstrap_repeated() {
+        let args = vec![
+            String::from("binary_name"),
+            String::from("--bootstrap=127.0.0.1:16600"),
+            String::from("--bootstrap"),
+            String::from("[::1]:16601"),
+        ];
+
+        let config = parse_arguments(args).unwrap();
+
+        assert_eq!(
+            config.bootstrap_peers,
+            vec![
+                "127.0.0.1:16600".parse::<SocketAddr>().unwrap(),
+                "[::1]:16601".parse::<SocketAddr>().unwrap(),
+            ]
+        );
+
+        let args = vec![
+            String::from("binary_name"),
+            String::from("--bootstrap=localhost"),
+        ];
+
+        assert!(parse_arguments(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_arguments_transport() {
+        let args = vec![String::from("binary_name"), String::from("--transport=tcp")];
+
+        assert_eq!(parse_arguments(args).unwrap().transport, TransportKind::Tcp);
+
+        let args = vec![
+            String::from("binary_name"),
+            String::from("--transport=quic"),
+        ];
+
+        assert!(parse_arguments(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_arguments_node_parameters() {
+        let args = vec![
+            String::from("binary_name"),
+            String::from("--alpha=5"),
+            String::from("--bucket-size"),
+            String::from("16"),
+            String::from("--log-level=error"),
+            String::from("--request-timeout-ms=250"),
+        ];
+
+        let config = parse_arguments(args).unwrap();
+
+        assert_eq!(config.alpha, 5);
+        assert_eq!(config.bucket_size, 16);
+        assert_eq!(config.log_level, LogLevel::Error);
+        assert_eq!(config.request_timeouts.initial, Duration::from_millis(250));
+
+        let args = vec![String::from("binary_name"), String::from("--alpha=-1")];
+
+        assert_eq!(
+            parse_arguments(args).err().unwrap(),
+            "Invalid alpha provided \"-1\", invalid digit found in string."
+        );
     }
 
     #[test]
@@ -0,0 +1,127 @@
+//! Runs a single command against a running node through its admin socket and prints the result,
+//! so nodes can be managed from scripts and cron.
+
+use client_server_test::admin::AdminClient;
+use client_server_test::commands::{admin_request, format_result};
+use std::env;
+
+#[derive(Debug, PartialEq)]
+struct Arguments {
+    command: Vec<String>,
+    json: bool,
+    socket: String,
+}
+
+fn main() {
+    let arguments = parse_arguments(env::args().collect()).unwrap_or_else(|error| fail(error));
+
+    let (method, params) = admin_request(&arguments.command).unwrap_or_else(|error| fail(error));
+
+    let result = AdminClient::connect(&arguments.socket)
+        .and_then(|mut client| client.call(method, params))
+        .unwrap_or_else(|error| fail(error));
+
+    let output = if arguments.json {
+        serde_json::to_string_pretty(&result)
+            .map_err(|error| format!("Failed to format result: {}", error))
+    } else {
+        format_result(method, result)
+    };
+
+    println!("{}", output.unwrap_or_else(|error| fail(error)));
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("dhtctl: {}", message);
+    std::process::exit(1);
+}
+
+fn print_help(binary_name: &str) {
+    println!("Usage: {} [options] <command> [arguments]", binary_name);
+    println!("\nCommands:");
+    println!("  peers                            List the peers in the routing table.");
+    println!("  put <key> <value> [ttl_seconds]  Store a value on the network.");
+    println!("  get <key>                        Look up a value.");
+    println!("  ping <ip:port>                   Ping a node, adding it as a peer if it responds.");
+    println!("  find-node <node_id>              Find the nodes closest to an ID.");
+    println!("  stats                            Show a summary of the node.");
+    println!("  shutdown                         Stop the node.");
+    println!("\nOptions:");
+    println!("  -h, --help                       Display this help message.");
+    println!("  --json                           Print the result as JSON instead of a table.");
+    println!("  -s, --socket <path>              Admin socket of the node. Default: node.sock");
+}
+
+fn parse_arguments(args: Vec<String>) -> Result<Arguments, String> {
+    let binary_name = args.first().cloned().unwrap_or_default();
+
+    let mut command = Vec::new();
+    let mut json = false;
+    let mut socket = String::from("node.sock");
+
+    let mut current_index = 1;
+
+    while current_index < args.len() {
+        let arg = &args[current_index];
+
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print_help(&binary_name);
+                std::process::exit(0);
+            }
+            "--json" => {
+                json = true;
+            }
+            "-s" | "--socket" => {
+                if current_index + 1 >= args.len() {
+                    return Err("No admin socket provided.".to_string());
+                }
+
+                socket = args[current_index + 1].clone();
+
+                current_index += 1;
+            }
+            _ => command.push(arg.clone()),
+        }
+
+        current_index += 1;
+    }
+
+    if command.is_empty() {
+        return Err(format!(
+            "No command provided, run {} --help for usage.",
+            binary_name
+        ));
+    }
+
+    Ok(Arguments {
+        command,
+        json,
+        socket,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_arguments_accepts_options_around_the_command() {
+        let arguments =
+            parse_arguments(args(&["dhtctl", "get", "--json", "-s", "a.sock", "abc"])).unwrap();
+
+        assert_eq!(
+            arguments,
+            Arguments {
+                command: args(&["get", "abc"]),
+                json: true,
+                socket: String::from("a.sock"),
+            }
+        );
+        assert!(parse_arguments(args(&["dhtctl", "--json"])).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use crate::shutdown::Shutdown;
+use crate::utilities;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// The source of time for everything that expires, refreshes or times out, so that tests can
+/// control time instead of waiting for it.
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for timeouts and deadlines.
+    fn now(&self) -> Instant;
+
+    /// Seconds since the Unix epoch, for timestamps that are saved or sent to other nodes.
+    fn unix_timestamp(&self) -> u64;
+
+    /// Blocks until the clock reaches `deadline`, returning `false` if the node shuts down first.
+    fn wait_until(&self, deadline: Instant, shutdown: &Arc<Shutdown>) -> bool;
+
+    /// How long to block on something other than the clock, such as a channel, before checking
+    /// whether `deadline` has passed.
+    fn timeout_until(&self, deadline: Instant) -> Duration {
+        deadline.saturating_duration_since(self.now())
+    }
+}
+
+/// The real time of the machine.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_timestamp(&self) -> u64 {
+        utilities::unix_timestamp()
+    }
+
+    fn wait_until(&self, deadline: Instant, shutdown: &Arc<Shutdown>) -> bool {
+        !shutdown.wait_timeout(deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// How often waits that `ManualClock` cannot wake check whether it has been advanced.
+const MANUAL_CLOCK_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A clock that stands still until it is advanced, for testing time based behaviour without
+/// sleeping. Advancing it wakes the threads waiting on it.
+pub struct ManualClock {
+    elapsed: Mutex<Duration>,
+    started_at: Instant,
+    unix_start: u64,
+    waiters: Mutex<Vec<Weak<Shutdown>>>,
+}
+
+impl ManualClock {
+    /// Starts the clock at the given Unix timestamp.
+    pub fn new(unix_timestamp: u64) -> Self {
+        Self {
+            elapsed: Mutex::new(Duration::ZERO),
+            started_at: Instant::now(),
+            unix_start: unix_timestamp,
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+
+        for waiter in self.waiters.lock().unwrap().iter() {
+            if let Some(shutdown) = waiter.upgrade() {
+                shutdown.wake();
+            }
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.started_at + *self.elapsed.lock().unwrap()
+    }
+
+    fn unix_timestamp(&self) -> u64 {
+        self.unix_start + self.elapsed.lock().unwrap().as_secs()
+    }
+
+    fn wait_until(&self, deadline: Instant, shutdown: &Arc<Shutdown>) -> bool {
+        {
+            let mut waiters = self.waiters.lock().unwrap();
+            waiters.retain(|waiter| waiter.strong_count() > 0);
+
+            if !waiters
+                .iter()
+                .any(|waiter| waiter.as_ptr() == Arc::as_ptr(shutdown))
+            {
+                waiters.push(Arc::downgrade(shutdown));
+            }
+        }
+
+        !shutdown.wait_while(|| self.now() < deadline)
+    }
+
+    /// Advancing the clock cannot wake a thread blocked on a channel, so such waits are cut short
+    /// to notice it.
+    fn timeout_until(&self, deadline: Instant) -> Duration {
+        deadline
+            .saturating_duration_since(self.now())
+            .min(MANUAL_CLOCK_CHECK_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_manual_clock_only_moves_when_advanced() {
+        let clock = ManualClock::new(1_000);
+        let started_at = clock.now();
+
+        assert_eq!(clock.now(), started_at);
+        assert_eq!(clock.unix_timestamp(), 1_000);
+
+        clock.advance(Duration::from_millis(1_500));
+
+        assert_eq!(clock.now() - started_at, Duration::from_millis(1_500));
+        assert_eq!(clock.unix_timestamp(), 1_001);
+    }
+
+    #[test]
+    fn test_manual_clock_wakes_waiters_when_advanced() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let shutdown = Arc::new(Shutdown::new());
+        let deadline = clock.now() + Duration::from_secs(60);
+
+        let clock_clone = clock.clone();
+        let shutdown_clone = shutdown.clone();
+        let wait_thread = thread::spawn(move || clock_clone.wait_until(deadline, &shutdown_clone));
+
+        clock.advance(Duration::from_secs(30));
+        clock.advance(Duration::from_secs(30));
+
+        assert!(wait_thread.join().unwrap());
+
+        // Shutting down ends a wait the clock would never finish on its own
+        let clock_clone = clock.clone();
+        let shutdown_clone = shutdown.clone();
+        let wait_thread = thread::spawn(move || {
+            clock_clone.wait_until(deadline + Duration::from_secs(60), &shutdown_clone)
+        });
+
+        shutdown.signal();
+
+        assert!(!wait_thread.join().unwrap());
+    }
+}
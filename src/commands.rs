@@ -0,0 +1,351 @@
+//! The commands operators run against a node, either typed into its terminal or passed to
+//! dhtctl. Each command maps to an admin method, so both go through the same handlers.
+
+use crate::admin::{self, AddPeerResult, PeerInfo, PingResult, StoreValueResult, ValueInfo};
+use crate::node::{Node, NodeStats};
+use crate::node_id::{Key, NodeId};
+use crate::structures::FoundNode;
+use chrono::DateTime;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value as JsonValue};
+use std::net::SocketAddr;
+
+/// Runs a line of input against the node and returns the formatted result. Blank lines do
+/// nothing.
+pub fn run(
+    node: &Node,
+    line: &str,
+    on_shutdown: &(dyn Fn() + Send + Sync),
+) -> Result<String, String> {
+    let command: Vec<String> = line.split_whitespace().map(String::from).collect();
+
+    if command.is_empty() {
+        return Ok(String::new());
+    }
+
+    let (method, params) = admin_request(&command)?;
+
+    admin::call(node, method, params, on_shutdown)
+        .map_err(|error| error.message)
+        .and_then(|result| format_result(method, result))
+        .map_err(|error| format!("Failed to run command {}, {}", command[0], error))
+}
+
+/// Turns a command into the admin method to call and its parameters, checking the arguments
+/// before anything is sent to the node. The terminal's original command names are kept as
+/// aliases.
+pub fn admin_request(command: &[String]) -> Result<(&'static str, JsonValue), String> {
+    let usage = |arguments: &str| Err(format!("Usage: {} {}", command[0], arguments));
+
+    match command[0].as_str() {
+        "peers" | "list_peers" => Ok(("list_peers", JsonValue::Null)),
+        "put" | "store_value" => {
+            if command.len() < 3 || command.len() > 4 {
+                return usage("<key> <value> [ttl_seconds]");
+            }
+
+            let key: Key = command[1].parse()?;
+            let mut params = json!({"key": key, "value": command[2]});
+
+            if let Some(ttl) = command.get(3) {
+                let ttl: u64 = ttl
+                    .parse()
+                    .map_err(|error| format!("Invalid TTL \"{}\", {}.", ttl, error))?;
+                params["ttl"] = json!(ttl);
+            }
+
+            Ok(("store_value", params))
+        }
+        "get" | "get_value" => {
+            if command.len() != 2 {
+                return usage("<key>");
+            }
+
+            let key: Key = command[1].parse()?;
+
+            Ok(("get_value", json!({"key": key})))
+        }
+        "ping" | "add_peer" => {
+            if command.len() != 2 {
+                return usage("<ip:port>");
+            }
+
+            let address: SocketAddr = command[1]
+                .parse()
+                .map_err(|error| format!("Failed to parse address: {}", error))?;
+
+            let method = match command[0].as_str() {
+                "ping" => "ping",
+                _ => "add_peer",
+            };
+
+            Ok((method, json!({"address": address})))
+        }
+        "find-node" => {
+            if command.len() != 2 {
+                return usage("<node_id>");
+            }
+
+            let node_id: NodeId = command[1].parse()?;
+
+            Ok(("find_node", json!({"node_id": node_id})))
+        }
+        "stats" => Ok(("stats", JsonValue::Null)),
+        "shutdown" | "exit" => Ok(("shutdown", JsonValue::Null)),
+        other => Err(format!("Invalid command: {}", other)),
+    }
+}
+
+/// Formats the result of an admin method for people to read.
+pub fn format_result(method: &str, result: JsonValue) -> Result<String, String> {
+    match method {
+        "add_peer" => {
+            let peer: AddPeerResult = parse_result(result)?;
+            Ok(format!("Added peer {} ({})", peer.node_id, peer.address))
+        }
+        "find_node" => {
+            let nodes: Vec<FoundNode> = parse_result(result)?;
+            let rows = nodes
+                .into_iter()
+                .map(|node| vec![node.node_id.to_string(), node.address.to_string()])
+                .collect();
+
+            Ok(table(&["NODE ID", "ADDRESS"], rows))
+        }
+        "get_value" => {
+            let value: ValueInfo = parse_result(result)?;
+
+            Ok(table(
+                &[],
+                vec![
+                    vec!["Key".to_string(), value.key.to_string()],
+                    vec!["Value".to_string(), value.value],
+                    vec!["Publisher".to_string(), value.publisher.to_string()],
+                    vec!["Published".to_string(), timestamp(value.published_at)],
+                    vec!["Expires".to_string(), timestamp(value.expires_at)],
+                ],
+            ))
+        }
+        "list_peers" => {
+            let peers: Vec<PeerInfo> = parse_result(result)?;
+            let rows = peers
+                .into_iter()
+                .map(|peer| {
+                    vec![
+                        peer.node_id.to_string(),
+                        peer.address.to_string(),
+                        peer.active.to_string(),
+                        peer.failed_requests.to_string(),
+                        timestamp(peer.first_seen),
+                        peer.last_seen
+                            .map(timestamp)
+                            .unwrap_or_else(|| "Never".to_string()),
+                    ]
+                })
+                .collect();
+
+            Ok(table(
+                &[
+                    "NODE ID",
+                    "ADDRESS",
+                    "ACTIVE",
+                    "FAILED",
+                    "FIRST SEEN",
+                    "LAST SEEN",
+                ],
+                rows,
+            ))
+        }
+        "ping" => {
+            let ping: PingResult = parse_result(result)?;
+            Ok(format!(
+                "Pong from {} ({}) in {} ms",
+                ping.node_id, ping.address, ping.rtt_ms
+            ))
+        }
+        "shutdown" | "exit" => Ok("Node is shutting down".to_string()),
+        "stats" => {
+            let stats: NodeStats = parse_result(result)?;
+
+            Ok(table(
+                &[],
+                vec![
+                    vec!["Node ID".to_string(), stats.node_id.to_string()],
+                    vec!["Address".to_string(), stats.address.to_string()],
+                    vec!["Peers".to_string(), stats.peer_count.to_string()],
+                    vec![
+                        "Active peers".to_string(),
+                        stats.active_peer_count.to_string(),
+                    ],
+                    vec!["Values".to_string(), stats.value_count.to_string()],
+                    vec!["Uptime".to_string(), format!("{}s", stats.uptime_seconds)],
+                ],
+            ))
+        }
+        "store_value" => {
+            let stored: StoreValueResult = parse_result(result)?;
+            Ok(format!(
+                "Stored {} on {} peers",
+                stored.key, stored.stored_count
+            ))
+        }
+        _ => serde_json::to_string_pretty(&result)
+            .map_err(|error| format!("Failed to format result: {}", error)),
+    }
+}
+
+fn parse_result<T: DeserializeOwned>(result: JsonValue) -> Result<T, String> {
+    serde_json::from_value(result).map_err(|error| format!("Unexpected result: {}", error))
+}
+
+fn timestamp(timestamp: u64) -> String {
+    match DateTime::from_timestamp(timestamp as i64, 0) {
+        Some(date_time) => date_time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// Lines up rows in columns under the headers. Tables without headers list one field per row.
+fn table(headers: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut lines: Vec<Vec<String>> = Vec::new();
+
+    if !headers.is_empty() {
+        lines.push(headers.iter().map(|header| header.to_string()).collect());
+    }
+
+    lines.extend(rows);
+
+    let column_count = lines.iter().map(|line| line.len()).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..column_count)
+        .map(|column| {
+            lines
+                .iter()
+                .filter_map(|line| line.get(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    lines
+        .iter()
+        .map(|line| {
+            line.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<String>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryNetwork;
+    use std::sync::Arc;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_admin_request_checks_arguments() {
+        let key = "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3";
+
+        assert_eq!(
+            admin_request(&args(&["put", key, "hello", "60"])).unwrap(),
+            (
+                "store_value",
+                json!({"key": key, "value": "hello", "ttl": 60})
+            )
+        );
+        assert_eq!(
+            admin_request(&args(&["find-node", key])).unwrap(),
+            ("find_node", json!({"node_id": key}))
+        );
+        assert!(admin_request(&args(&["get", "abc"])).is_err());
+        assert!(admin_request(&args(&["ping", "localhost"])).is_err());
+        assert_eq!(
+            admin_request(&args(&["put", key])).unwrap_err(),
+            "Usage: put <key> <value> [ttl_seconds]"
+        );
+        assert_eq!(
+            admin_request(&args(&["store_value", key])).unwrap_err(),
+            "Usage: store_value <key> <value> [ttl_seconds]"
+        );
+        assert_eq!(
+            admin_request(&args(&["add_peer", "127.0.0.1:1234"])).unwrap(),
+            ("add_peer", json!({"address": "127.0.0.1:1234"}))
+        );
+        assert_eq!(
+            admin_request(&args(&["nope"])).unwrap_err(),
+            "Invalid command: nope"
+        );
+    }
+
+    #[test]
+    fn test_run_goes_through_the_admin_methods() {
+        let network = MemoryNetwork::new();
+        let transport = network
+            .bind(&SocketAddr::from(([10, 0, 0, 1], 16800)))
+            .unwrap();
+        let node = Node::builder()
+            .transport(Arc::new(transport))
+            .build()
+            .unwrap();
+
+        let (shutdown_tx, shutdown_rx) = std::sync::mpsc::channel();
+        let on_shutdown = move || {
+            let _ = shutdown_tx.send(());
+        };
+        let key = Key::random();
+
+        assert_eq!(run(&node, "  \n", &on_shutdown).unwrap(), "");
+        assert_eq!(
+            run(&node, &format!("store_value {} hello\n", key), &on_shutdown).unwrap(),
+            format!("Stored {} on 0 peers", key)
+        );
+
+        let output = run(&node, &format!("get {}", key), &on_shutdown).unwrap();
+        assert!(output.contains("hello"));
+        assert!(output.contains(&node.node_id().to_string()));
+
+        assert_eq!(
+            run(&node, "get_value abc", &on_shutdown).unwrap_err(),
+            "Invalid ID \"abc\", expected 40 hex characters."
+        );
+        assert_eq!(
+            run(&node, &format!("get {}", Key::random()), &on_shutdown)
+                .unwrap_err()
+                .split(',')
+                .next()
+                .unwrap(),
+            "Failed to run command get"
+        );
+
+        run(&node, "exit", &on_shutdown).unwrap();
+        shutdown_rx.recv().unwrap();
+
+        node.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_table_aligns_columns() {
+        let output = table(
+            &["NODE ID", "ADDRESS"],
+            vec![
+                vec!["a".to_string(), "127.0.0.1:1".to_string()],
+                vec!["abcdefghi".to_string(), "x".to_string()],
+            ],
+        );
+
+        assert_eq!(
+            output,
+            "NODE ID    ADDRESS\na          127.0.0.1:1\nabcdefghi  x"
+        );
+    }
+}
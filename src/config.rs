@@ -0,0 +1,275 @@
+//! Layers the node's settings: built-in defaults, then a TOML config file, then `DHT_*`
+//! environment variables, then command line flags.
+
+use crate::arguments::{parse_arguments, parse_arguments_over, set_setting, Arguments, SETTINGS};
+use client_server_test::{Node, NodeBuilder};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+
+const ENVIRONMENT_PREFIX: &str = "DHT_";
+const CONFIG_VARIABLE: &str = "DHT_CONFIG";
+
+/// The merged settings in config file form, so `--check-config` output can be saved and reused.
+#[derive(Serialize)]
+struct Settings<'a> {
+    alpha: usize,
+    bind_address: &'a str,
+    bootstrap: Vec<String>,
+    bucket_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    control_socket: Option<&'a str>,
+    daemon: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data_dir: Option<&'a str>,
+    log_level: String,
+    max_failed_requests: u32,
+    max_request_timeout_ms: u64,
+    min_request_timeout_ms: u64,
+    pid_file: &'a str,
+    port: u16,
+    replacement_cache_size: usize,
+    request_attempts: u32,
+    request_timeout_ms: u64,
+    state_file: &'a str,
+    transport: &'static str,
+}
+
+/// Reads the settings from the config file named by `--config` or `DHT_CONFIG`, the environment
+/// and the command line.
+pub fn load(args: Vec<String>, variables: &HashMap<String, String>) -> Result<Arguments, String> {
+    // Parsed once first to find the config file and to report bad flags before anything is read
+    let command_line = parse_arguments(args.clone())?;
+
+    let mut arguments = Arguments::default();
+
+    if let Some(config_file) = command_line
+        .config_file
+        .as_ref()
+        .or_else(|| variables.get(CONFIG_VARIABLE))
+    {
+        apply_config_file(&mut arguments, config_file)?;
+    }
+
+    apply_environment(&mut arguments, variables)?;
+
+    parse_arguments_over(args, arguments)
+}
+
+fn apply_config_file(arguments: &mut Arguments, config_file: &str) -> Result<(), String> {
+    let contents = fs::read_to_string(config_file)
+        .map_err(|error| format!("Failed to read config file \"{}\": {}", config_file, error))?;
+
+    let table: toml::Table = toml::from_str(&contents)
+        .map_err(|error| format!("Failed to parse config file \"{}\": {}", config_file, error))?;
+
+    for (setting, value) in table {
+        setting_text(&value)
+            .and_then(|value| set_setting(arguments, &setting, &value))
+            .map_err(|error| format!("Config file \"{}\": {}", config_file, error))?;
+    }
+
+    Ok(())
+}
+
+fn apply_environment(
+    arguments: &mut Arguments,
+    variables: &HashMap<String, String>,
+) -> Result<(), String> {
+    for setting in SETTINGS {
+        let variable = format!("{}{}", ENVIRONMENT_PREFIX, setting.to_uppercase());
+
+        if let Some(value) = variables.get(&variable) {
+            set_setting(arguments, setting, value)
+                .map_err(|error| format!("Environment variable {}: {}", variable, error))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Turns a config file value into the text form used by the environment and command line. Arrays
+/// become comma separated lists.
+fn setting_text(value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(text) => Ok(text.clone()),
+        toml::Value::Integer(integer) => Ok(integer.to_string()),
+        toml::Value::Boolean(boolean) => Ok(boolean.to_string()),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(setting_text)
+            .collect::<Result<Vec<String>, String>>()
+            .map(|values| values.join(",")),
+        other => Err(format!("Unsupported value {}", other)),
+    }
+}
+
+/// A node builder with everything but the transport configured from the settings.
+pub fn node_builder(arguments: &Arguments) -> NodeBuilder {
+    let node_builder = Node::builder()
+        .alpha(arguments.alpha)
+        .bootstrap_peers(arguments.bootstrap_peers.clone())
+        .bucket_size(arguments.bucket_size)
+        .max_failed_requests(arguments.max_failed_requests)
+        .replacement_cache_size(arguments.replacement_cache_size)
+        .request_attempts(arguments.request_attempts)
+        .request_timeouts(arguments.request_timeouts);
+
+    match &arguments.data_dir {
+        Some(data_dir) => node_builder.data_dir(data_dir),
+        None => node_builder.state_file(&arguments.state_file),
+    }
+}
+
+pub fn socket_address(arguments: &Arguments) -> Result<SocketAddr, String> {
+    format!("{}:{}", arguments.bind_address, arguments.port)
+        .parse()
+        .map_err(|error| format!("Failed to parse address: {}", error))
+}
+
+/// Checks the merged settings without binding or loading anything, and returns them as a config
+/// file.
+pub fn check(arguments: &Arguments) -> Result<String, String> {
+    socket_address(arguments)?;
+    node_builder(arguments).validate()?;
+
+    let settings = Settings {
+        alpha: arguments.alpha,
+        bind_address: &arguments.bind_address,
+        bootstrap: arguments
+            .bootstrap_peers
+            .iter()
+            .map(|peer| peer.to_string())
+            .collect(),
+        bucket_size: arguments.bucket_size,
+        control_socket: arguments.control_socket.as_deref(),
+        daemon: arguments.daemon,
+        data_dir: arguments.data_dir.as_deref(),
+        log_level: arguments.log_level.to_string(),
+        max_failed_requests: arguments.max_failed_requests,
+        max_request_timeout_ms: arguments.request_timeouts.max.as_millis() as u64,
+        min_request_timeout_ms: arguments.request_timeouts.min.as_millis() as u64,
+        pid_file: &arguments.pid_file,
+        port: arguments.port,
+        replacement_cache_size: arguments.replacement_cache_size,
+        request_attempts: arguments.request_attempts,
+        request_timeout_ms: arguments.request_timeouts.initial.as_millis() as u64,
+        state_file: &arguments.state_file,
+        transport: arguments.transport.name(),
+    };
+
+    toml::to_string(&settings).map_err(|error| format!("Failed to format settings: {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::time::Duration;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn variables(variables: &[(&str, &str)]) -> HashMap<String, String> {
+        variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_load_layers_file_environment_and_flags() {
+        let config_file =
+            env::temp_dir().join(format!("config-layers-{}.toml", std::process::id()));
+        let config_file = config_file.to_str().unwrap();
+
+        fs::write(
+            config_file,
+            r#"
+bootstrap = ["10.0.0.1:16600", "10.0.0.2:16600"]
+bucket_size = 8
+log_level = "debug"
+port = 17000
+request_timeout_ms = 500
+"#,
+        )
+        .unwrap();
+
+        let arguments = load(
+            args(&["binary_name", "--config", config_file, "--port=18000"]),
+            &variables(&[("DHT_BUCKET_SIZE", "12"), ("DHT_PORT", "17500")]),
+        )
+        .unwrap();
+
+        // The flag beats the environment, which beats the file
+        assert_eq!(arguments.port, 18000);
+        assert_eq!(arguments.bucket_size, 12);
+        assert_eq!(arguments.log_level.to_string(), "debug");
+        assert_eq!(arguments.bootstrap_peers.len(), 2);
+        assert_eq!(
+            arguments.request_timeouts.initial,
+            Duration::from_millis(500)
+        );
+
+        let arguments = load(
+            args(&["binary_name", "--bootstrap=10.0.0.3:16600"]),
+            &variables(&[("DHT_CONFIG", config_file)]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            arguments.bootstrap_peers,
+            vec!["10.0.0.3:16600".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(arguments.port, 17000);
+
+        fs::write(config_file, "buckets = 8\n").unwrap();
+
+        assert!(load(
+            args(&["binary_name", "--config", config_file]),
+            &HashMap::new()
+        )
+        .err()
+        .unwrap()
+        .contains("Unknown setting \"buckets\""));
+        assert!(
+            load(args(&["binary_name"]), &variables(&[("DHT_ALPHA", "many")]))
+                .err()
+                .unwrap()
+                .starts_with("Environment variable DHT_ALPHA")
+        );
+    }
+
+    #[test]
+    fn test_check_prints_a_config_that_loads_back() {
+        let arguments = load(
+            args(&[
+                "binary_name",
+                "--bootstrap=10.0.0.1:16600",
+                "--max-failed-requests=5",
+                "--transport=tcp",
+            ]),
+            &HashMap::new(),
+        )
+        .unwrap();
+
+        let config_file = env::temp_dir().join(format!("config-check-{}.toml", std::process::id()));
+        let config_file = config_file.to_str().unwrap();
+        fs::write(config_file, check(&arguments).unwrap()).unwrap();
+
+        let reloaded = load(args(&["binary_name", "-c", config_file]), &HashMap::new()).unwrap();
+
+        assert_eq!(check(&reloaded).unwrap(), check(&arguments).unwrap());
+        assert_eq!(reloaded.max_failed_requests, 5);
+
+        let invalid = load(
+            args(&["binary_name", "--min-request-timeout-ms=5000"]),
+            &HashMap::new(),
+        )
+        .unwrap();
+
+        assert!(check(&invalid).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+
+use crate::peers::ID_BITS;
+
+/// The XOR distance between two 160-bit identifiers. Distances compare as unsigned big-endian
+/// integers, so sorting by `Distance` sorts from closest to farthest.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Distance([u8; 20]);
+
+impl Distance {
+    pub fn between(a: &[u8; 20], b: &[u8; 20]) -> Self {
+        let mut distance: [u8; 20] = [0; 20];
+
+        for (index, byte) in distance.iter_mut().enumerate() {
+            *byte = a[index] ^ b[index];
+        }
+
+        Self(distance)
+    }
+
+    pub fn leading_zeros(&self) -> usize {
+        let mut zeros = 0;
+
+        for byte in self.0 {
+            zeros += byte.leading_zeros() as usize;
+
+            if byte != 0 {
+                break;
+            }
+        }
+
+        zeros
+    }
+
+    /// The routing table bucket a peer at this distance belongs in. Bucket 0 holds the farthest
+    /// half of the ID space and bucket 159 the closest. Returns `None` for a zero distance, since
+    /// a node never stores itself.
+    pub fn bucket_index(&self) -> Option<usize> {
+        let leading_zeros = self.leading_zeros();
+
+        if leading_zeros >= ID_BITS {
+            return None;
+        }
+
+        Some(leading_zeros)
+    }
+}
+
+impl fmt::Display for Distance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node_id::NodeId;
+
+    fn distance(a: &str, b: &str) -> Distance {
+        a.parse::<NodeId>().unwrap() ^ b.parse::<NodeId>().unwrap()
+    }
+
+    #[test]
+    fn test_distance_between_vectors() {
+        let vectors = [
+            (
+                "0000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000000",
+            ),
+            (
+                "ffffffffffffffffffffffffffffffffffffffff",
+                "0000000000000000000000000000000000000000",
+                "ffffffffffffffffffffffffffffffffffffffff",
+            ),
+            (
+                "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3",
+                "a94a8fe5ccb19ba61c4c0873d391e987982fbbd2",
+                "0000000000000000000000000000000000000001",
+            ),
+            (
+                "0123456789abcdef0123456789abcdef01234567",
+                "fedcba9876543210fedcba9876543210fedcba98",
+                "ffffffffffffffffffffffffffffffffffffffff",
+            ),
+            (
+                "8000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000001",
+                "8000000000000000000000000000000000000001",
+            ),
+        ];
+
+        for (a, b, expected) in vectors {
+            assert_eq!(distance(a, b).to_string(), expected);
+            assert_eq!(distance(b, a).to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_distance_leading_zeros_and_bucket_index() {
+        let vectors = [
+            ("ffffffffffffffffffffffffffffffffffffffff", 0, Some(0)),
+            ("8000000000000000000000000000000000000000", 0, Some(0)),
+            ("4000000000000000000000000000000000000000", 1, Some(1)),
+            ("00ff000000000000000000000000000000000000", 8, Some(8)),
+            ("0000000000000000000000000000000000000010", 155, Some(155)),
+            ("0000000000000000000000000000000000000001", 159, Some(159)),
+            ("0000000000000000000000000000000000000000", 160, None),
+        ];
+
+        for (id, leading_zeros, bucket_index) in vectors {
+            let distance = distance(id, "0000000000000000000000000000000000000000");
+
+            assert_eq!(distance.leading_zeros(), leading_zeros, "{}", id);
+            assert_eq!(distance.bucket_index(), bucket_index, "{}", id);
+        }
+    }
+
+    #[test]
+    fn test_distance_ordering() {
+        let target = "0000000000000000000000000000000000000000";
+
+        let mut distances = [
+            distance("0100000000000000000000000000000000000000", target),
+            distance("0000000000000000000000000000000000000002", target),
+            distance("ff00000000000000000000000000000000000000", target),
+            distance("00000000000000000000000000000000000000ff", target),
+            distance("0000000000000000000000000000000000000001", target),
+        ];
+
+        distances.sort();
+
+        assert_eq!(
+            distances
+                .iter()
+                .map(|distance| distance.to_string())
+                .collect::<Vec<String>>(),
+            vec![
+                "0000000000000000000000000000000000000001",
+                "0000000000000000000000000000000000000002",
+                "00000000000000000000000000000000000000ff",
+                "0100000000000000000000000000000000000000",
+                "ff00000000000000000000000000000000000000",
+            ]
+        );
+    }
+}
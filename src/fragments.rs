@@ -0,0 +1,370 @@
+use crate::debug_log;
+use crate::node_id::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The largest datagram we send or accept. This stays under the 1280 byte IPv6 minimum MTU so
+/// datagrams are not fragmented at the IP layer.
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+/// The largest message that can be split across datagrams.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+const MAX_PENDING_MESSAGES: usize = 256;
+/// How many partial messages one sender can have at a time, so a single sender cannot crowd out
+/// everyone else's.
+const MAX_PENDING_MESSAGES_PER_SENDER: usize = 16;
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Fragment {
+    count: u16,
+    data: Vec<u8>,
+    index: u16,
+    message_id: NodeId,
+}
+
+/// How much of a datagram is left for message data once a fragment's other fields are encoded.
+/// The data's length prefix has a fixed size, so this is the same for every fragment.
+fn fragment_data_size() -> usize {
+    let empty_fragment = Fragment {
+        count: 0,
+        data: Vec::new(),
+        index: 0,
+        message_id: NodeId::random(),
+    };
+
+    let header_size = bincode::serialized_size(&empty_fragment)
+        .expect("An empty fragment should serialize.") as usize;
+
+    MAX_DATAGRAM_SIZE - header_size
+}
+
+fn max_fragments() -> usize {
+    MAX_MESSAGE_SIZE.div_ceil(fragment_data_size())
+}
+
+/// Splits a serialized message into datagrams no larger than `MAX_DATAGRAM_SIZE`.
+pub fn split_message(message_id: &NodeId, message: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(format!(
+            "Message of {} bytes exceeds the maximum message size of {} bytes",
+            message.len(),
+            MAX_MESSAGE_SIZE
+        ));
+    }
+
+    let chunks: Vec<&[u8]> = if message.is_empty() {
+        vec![message]
+    } else {
+        message.chunks(fragment_data_size()).collect()
+    };
+    let count = chunks.len() as u16;
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let datagram = bincode::serialize(&Fragment {
+                count,
+                data: chunk.to_vec(),
+                index: index as u16,
+                message_id: *message_id,
+            })
+            .map_err(|error| format!("Failed to serialize fragment: {}", error))?;
+
+            if datagram.len() > MAX_DATAGRAM_SIZE {
+                return Err(format!(
+                    "Datagram of {} bytes exceeds the maximum datagram size of {} bytes",
+                    datagram.len(),
+                    MAX_DATAGRAM_SIZE
+                ));
+            }
+
+            Ok(datagram)
+        })
+        .collect()
+}
+
+struct PartialMessage {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    started_at: Instant,
+}
+
+/// Collects fragments from each sender until every fragment of a message has arrived.
+pub struct Reassembler {
+    messages: HashMap<(SocketAddr, NodeId), PartialMessage>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self {
+            messages: HashMap::new(),
+        }
+    }
+
+    /// Adds a received datagram, returning the full message once its last fragment arrives.
+    pub fn add_datagram(
+        &mut self,
+        src: &SocketAddr,
+        datagram: &[u8],
+    ) -> Result<Option<Vec<u8>>, String> {
+        if datagram.len() > MAX_DATAGRAM_SIZE {
+            return Err(format!(
+                "Received datagram of {} bytes from {}, exceeding the maximum of {} bytes",
+                datagram.len(),
+                src,
+                MAX_DATAGRAM_SIZE
+            ));
+        }
+
+        let fragment: Fragment = bincode::deserialize(datagram)
+            .map_err(|error| format!("Failed to deserialize fragment: {}", error))?;
+
+        let count = fragment.count as usize;
+
+        if count == 0 || count > max_fragments() || fragment.index as usize >= count {
+            return Err(format!(
+                "Received invalid fragment {} of {} from {}",
+                fragment.index, fragment.count, src
+            ));
+        }
+
+        if count == 1 {
+            return Ok(Some(fragment.data));
+        }
+
+        self.purge_stale();
+
+        let message_key = (*src, fragment.message_id);
+
+        if !self.messages.contains_key(&message_key) {
+            let sender_messages = self
+                .messages
+                .keys()
+                .filter(|(sender, _)| sender == src)
+                .count();
+
+            // Make room by dropping the oldest partial message, rather than refusing new ones
+            if sender_messages >= MAX_PENDING_MESSAGES_PER_SENDER {
+                self.drop_oldest(|sender| sender == src);
+            } else if self.messages.len() >= MAX_PENDING_MESSAGES {
+                self.drop_oldest(|_| true);
+            }
+        }
+
+        let message = self
+            .messages
+            .entry(message_key)
+            .or_insert_with(|| PartialMessage {
+                fragments: vec![None; count],
+                received: 0,
+                started_at: Instant::now(),
+            });
+
+        if message.fragments.len() != count {
+            return Err(format!(
+                "Received fragment with mismatched count {} from {}",
+                count, src
+            ));
+        }
+
+        let slot = &mut message.fragments[fragment.index as usize];
+
+        if slot.is_none() {
+            *slot = Some(fragment.data);
+            message.received += 1;
+        }
+
+        if message.received < count {
+            return Ok(None);
+        }
+
+        let message = self
+            .messages
+            .remove(&message_key)
+            .expect("Completed message should be pending.");
+
+        Ok(Some(
+            message.fragments.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    /// Drops the partial message that was started first among those from matching senders.
+    fn drop_oldest(&mut self, is_sender: impl Fn(&SocketAddr) -> bool) {
+        let oldest = self
+            .messages
+            .iter()
+            .filter(|((sender, _), _)| is_sender(sender))
+            .min_by_key(|(_, message)| message.started_at)
+            .map(|(message_key, _)| *message_key);
+
+        if let Some(message_key) = oldest {
+            debug_log(format!(
+                "Dropping a partial message from {}, too many partial messages",
+                message_key.0
+            ));
+
+            self.messages.remove(&message_key);
+        }
+    }
+
+    fn purge_stale(&mut self) {
+        self.messages
+            .retain(|_, message| message.started_at.elapsed() < REASSEMBLY_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn src() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 16600))
+    }
+
+    #[test]
+    fn test_split_message_fits_datagrams() {
+        let message_id = NodeId::random();
+        let message: Vec<u8> = (0..MAX_MESSAGE_SIZE).map(|byte| byte as u8).collect();
+
+        let datagrams = split_message(&message_id, &message).unwrap();
+
+        assert_eq!(datagrams.len(), max_fragments());
+        assert!(datagrams
+            .iter()
+            .all(|datagram| datagram.len() <= MAX_DATAGRAM_SIZE));
+        // Every datagram but the last is filled exactly
+        assert_eq!(datagrams[0].len(), MAX_DATAGRAM_SIZE);
+    }
+
+    #[test]
+    fn test_split_message_rejects_oversized_messages() {
+        let message = vec![0; MAX_MESSAGE_SIZE + 1];
+
+        assert!(split_message(&NodeId::random(), &message).is_err());
+    }
+
+    #[test]
+    fn test_reassembles_out_of_order_fragments() {
+        let message_id = NodeId::random();
+        let message: Vec<u8> = (0..5000).map(|byte| byte as u8).collect();
+
+        let mut datagrams = split_message(&message_id, &message).unwrap();
+        datagrams.reverse();
+
+        let mut reassembler = Reassembler::new();
+        let last = datagrams.pop().unwrap();
+
+        for datagram in &datagrams {
+            assert_eq!(reassembler.add_datagram(&src(), datagram).unwrap(), None);
+        }
+
+        // Duplicate fragments are ignored
+        assert_eq!(
+            reassembler.add_datagram(&src(), &datagrams[0]).unwrap(),
+            None
+        );
+
+        assert_eq!(
+            reassembler.add_datagram(&src(), &last).unwrap(),
+            Some(message)
+        );
+        assert!(reassembler.messages.is_empty());
+    }
+
+    #[test]
+    fn test_flooding_sender_cannot_block_other_senders() {
+        let mut reassembler = Reassembler::new();
+        let flooder = SocketAddr::from(([127, 0, 0, 2], 16600));
+
+        let message: Vec<u8> = (0..5000).map(|byte| byte as u8).collect();
+        let datagrams = split_message(&NodeId::random(), &message).unwrap();
+
+        assert_eq!(
+            reassembler.add_datagram(&src(), &datagrams[0]).unwrap(),
+            None
+        );
+
+        // First fragments of messages that are never finished, under made up message IDs
+        for _ in 0..MAX_PENDING_MESSAGES * 2 {
+            let first_fragment = &split_message(&NodeId::random(), &message).unwrap()[0];
+
+            assert_eq!(
+                reassembler.add_datagram(&flooder, first_fragment).unwrap(),
+                None
+            );
+        }
+
+        assert_eq!(
+            reassembler.messages.len(),
+            MAX_PENDING_MESSAGES_PER_SENDER + 1
+        );
+
+        let mut reassembled = None;
+
+        for datagram in &datagrams[1..] {
+            reassembled = reassembler.add_datagram(&src(), datagram).unwrap();
+        }
+
+        assert_eq!(reassembled, Some(message));
+    }
+
+    #[test]
+    fn test_full_table_drops_the_oldest_partial_message() {
+        let mut reassembler = Reassembler::new();
+        let message: Vec<u8> = (0..5000).map(|byte| byte as u8).collect();
+        let first_fragment = |sender: u16| {
+            let datagram = split_message(&NodeId::random(), &message)
+                .unwrap()
+                .remove(0);
+
+            (SocketAddr::from(([127, 0, 0, 1], sender)), datagram)
+        };
+
+        let (oldest_sender, datagram) = first_fragment(0);
+        reassembler.add_datagram(&oldest_sender, &datagram).unwrap();
+
+        for sender in 1..=MAX_PENDING_MESSAGES as u16 {
+            let (sender, datagram) = first_fragment(sender);
+            assert_eq!(reassembler.add_datagram(&sender, &datagram).unwrap(), None);
+        }
+
+        assert_eq!(reassembler.messages.len(), MAX_PENDING_MESSAGES);
+        assert!(!reassembler
+            .messages
+            .keys()
+            .any(|(sender, _)| *sender == oldest_sender));
+    }
+
+    #[test]
+    fn test_single_fragment_message() {
+        let datagrams = split_message(&NodeId::random(), b"ping").unwrap();
+
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(
+            Reassembler::new()
+                .add_datagram(&src(), &datagrams[0])
+                .unwrap(),
+            Some(b"ping".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_fragments() {
+        let mut reassembler = Reassembler::new();
+
+        let invalid_index = bincode::serialize(&Fragment {
+            count: 2,
+            data: vec![],
+            index: 2,
+            message_id: NodeId::random(),
+        })
+        .unwrap();
+
+        assert!(reassembler.add_datagram(&src(), &invalid_index).is_err());
+        assert!(reassembler.add_datagram(&src(), b"garbage").is_err());
+    }
+}
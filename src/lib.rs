@@ -0,0 +1,39 @@
+//! A Kademlia distributed hash table node that can be embedded in other programs. Build a `Node`
+//! with `Node::builder()`, then use it to look up nodes and store or retrieve values.
+
+pub mod admin;
+pub mod clock;
+pub mod commands;
+mod distance;
+mod fragments;
+mod logging;
+mod lookup;
+mod maintenance;
+mod messages;
+mod node;
+pub mod node_id;
+mod node_state;
+mod peers;
+mod pending_requests;
+mod rtt;
+pub mod shutdown;
+#[cfg(test)]
+mod simulation;
+mod state_export;
+mod storage;
+pub mod structures;
+pub mod transport;
+mod utilities;
+mod values;
+
+pub use logging::{
+    debug_log, error_log, fatal_log, log_to_file, recv_log, send_log, set_log_level, LogLevel,
+};
+pub use lookup::ALPHA;
+pub use messages::REQUEST_ATTEMPTS;
+pub use node::{Node, NodeBuilder, NodeStats};
+pub use node_state::StatePath;
+pub use peers::{BUCKET_SIZE, MAX_FAILED_REQUESTS, REPLACEMENT_CACHE_SIZE};
+pub use rtt::Timeouts;
+pub use state_export::{export_state, import_state};
+pub use values::{DEFAULT_TTL, MAX_TTL};
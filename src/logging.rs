@@ -0,0 +1,138 @@
+use colored::Colorize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Trace as u8);
+
+/// How much is logged. Each level includes the ones before it: `error` logs only errors, `debug`
+/// adds the node's progress and `trace` adds every packet sent and received.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Error,
+    Debug,
+    Trace,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "error" => Ok(LogLevel::Error),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!(
+                "Invalid log level \"{}\", expected error, debug or trace",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogLevel::Error => "error",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Sets the most detailed level that is logged. Default: trace
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Also appends every log line, without colours, to this file.
+pub fn log_to_file(path: &str) -> Result<(), String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|error| format!("Failed to open log file \"{}\": {}", path, error))?;
+
+    *LOG_FILE.lock().unwrap() = Some(file);
+
+    Ok(())
+}
+
+fn write_to_log_file(readable_time: &str, level: &str, message: &str) {
+    if let Some(file) = LOG_FILE.lock().unwrap().as_mut() {
+        let _ = writeln!(file, "{} {} {}", readable_time, level, message);
+    }
+}
+
+pub fn debug_log(message: String) {
+    if !enabled(LogLevel::Debug) {
+        return;
+    }
+
+    let readable_time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    write_to_log_file(&readable_time, "DEBUG", &message);
+    println!(
+        "{} {} {}",
+        readable_time.bright_black(),
+        " DEBUG ".bold().black().on_bright_blue(),
+        message
+    );
+}
+
+pub fn error_log(message: String) {
+    let readable_time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    write_to_log_file(&readable_time, "ERROR", &message);
+    eprintln!(
+        "{} {} {}",
+        readable_time.bright_black(),
+        " ERROR ".bold().black().on_red(),
+        message
+    );
+}
+
+pub fn fatal_log(message: String) -> ! {
+    error_log(message);
+    std::process::exit(1);
+}
+
+pub fn recv_log(message: String) {
+    if !enabled(LogLevel::Trace) {
+        return;
+    }
+
+    let readable_time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    write_to_log_file(&readable_time, "RECV", &message);
+
+    eprintln!(
+        "{} {} {}",
+        readable_time.bright_black(),
+        " RECV ".bold().black().on_bright_magenta(),
+        message
+    );
+}
+
+pub fn send_log(message: String) {
+    if !enabled(LogLevel::Trace) {
+        return;
+    }
+
+    let readable_time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    write_to_log_file(&readable_time, "SEND", &message);
+
+    println!(
+        "{} {} {}",
+        readable_time.bright_black(),
+        " SEND ".bold().black().on_bright_green(),
+        message
+    );
+}
@@ -0,0 +1,534 @@
+use crate::distance::Distance;
+use crate::messages::send_request;
+use crate::node_id::{Key, NodeId, TransactionId};
+use crate::peers::{PeerManager, BUCKET_SIZE};
+use crate::pending_requests::PendingRequests;
+use crate::transport::Transport;
+use crate::{debug_log, error_log, structures};
+use std::collections::HashSet;
+use std::sync::atomic::AtomicBool;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+pub const ALPHA: usize = 3;
+
+/// How a lookup runs. `bucket_size` is Kademlia's k, the number of closest nodes a lookup
+/// converges on, and `alpha` is how many queries are kept in flight at once.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LookupParameters {
+    pub alpha: usize,
+    pub bucket_size: usize,
+}
+
+impl Default for LookupParameters {
+    fn default() -> Self {
+        Self {
+            alpha: ALPHA,
+            bucket_size: BUCKET_SIZE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LookupKind {
+    Node,
+    Value,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum LookupResult {
+    Nodes(Vec<structures::FoundNode>),
+    Value(structures::Value),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum CandidateState {
+    Pending,
+    InFlight,
+    Responded,
+    Failed,
+}
+
+struct Candidate {
+    distance: Distance,
+    node: structures::FoundNode,
+    state: CandidateState,
+}
+
+/// Tracks the shortlist of an iterative Kademlia lookup without performing any I/O. Callers ask
+/// for the next nodes to query, then report back each response or failure until the lookup is
+/// finished.
+pub struct Lookup {
+    candidates: Vec<Candidate>,
+    local_node_id: NodeId,
+    parameters: LookupParameters,
+    seen: HashSet<NodeId>,
+    target_id: NodeId,
+}
+
+impl Lookup {
+    pub fn new(
+        local_node_id: &NodeId,
+        target_id: &NodeId,
+        initial_nodes: Vec<structures::FoundNode>,
+        parameters: LookupParameters,
+    ) -> Self {
+        let mut lookup = Self {
+            candidates: Vec::new(),
+            local_node_id: *local_node_id,
+            parameters,
+            seen: HashSet::new(),
+            target_id: *target_id,
+        };
+
+        lookup.add_nodes(initial_nodes);
+
+        lookup
+    }
+
+    pub fn next_queries(&mut self) -> Vec<structures::FoundNode> {
+        let mut in_flight = self
+            .candidates
+            .iter()
+            .filter(|candidate| candidate.state == CandidateState::InFlight)
+            .count();
+        let mut queries = Vec::new();
+
+        for candidate in self
+            .candidates
+            .iter_mut()
+            .filter(|candidate| candidate.state != CandidateState::Failed)
+            .take(self.parameters.bucket_size)
+        {
+            if in_flight >= self.parameters.alpha {
+                break;
+            }
+
+            if candidate.state == CandidateState::Pending {
+                candidate.state = CandidateState::InFlight;
+                in_flight += 1;
+
+                queries.push(candidate.node.clone());
+            }
+        }
+
+        queries
+    }
+
+    pub fn on_response(&mut self, node_id: &NodeId, nodes: Vec<structures::FoundNode>) {
+        self.set_state(node_id, CandidateState::Responded);
+        self.add_nodes(nodes);
+    }
+
+    pub fn on_failure(&mut self, node_id: &NodeId) {
+        self.set_state(node_id, CandidateState::Failed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        let closest_responded = self
+            .candidates
+            .iter()
+            .filter(|candidate| candidate.state != CandidateState::Failed)
+            .take(self.parameters.bucket_size)
+            .all(|candidate| candidate.state == CandidateState::Responded);
+
+        let has_outstanding = self.candidates.iter().any(|candidate| {
+            candidate.state == CandidateState::Pending
+                || candidate.state == CandidateState::InFlight
+        });
+
+        closest_responded || !has_outstanding
+    }
+
+    pub fn closest(&self) -> Vec<structures::FoundNode> {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.state == CandidateState::Responded)
+            .take(self.parameters.bucket_size)
+            .map(|candidate| candidate.node.clone())
+            .collect()
+    }
+
+    fn add_nodes(&mut self, nodes: Vec<structures::FoundNode>) {
+        for node in nodes {
+            if node.node_id == self.local_node_id || self.seen.contains(&node.node_id) {
+                continue;
+            }
+
+            let distance = node.node_id ^ self.target_id;
+            let index = self
+                .candidates
+                .partition_point(|candidate| candidate.distance < distance);
+
+            self.seen.insert(node.node_id);
+            self.candidates.insert(
+                index,
+                Candidate {
+                    distance,
+                    node,
+                    state: CandidateState::Pending,
+                },
+            );
+        }
+    }
+
+    fn set_state(&mut self, node_id: &NodeId, state: CandidateState) {
+        if let Some(candidate) = self
+            .candidates
+            .iter_mut()
+            .find(|candidate| candidate.node.node_id == *node_id)
+        {
+            candidate.state = state;
+        }
+    }
+}
+
+pub fn find_node(
+    is_running: Arc<AtomicBool>,
+    parameters: LookupParameters,
+    local_node_id: &NodeId,
+    target_id: &NodeId,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
+) -> Result<Vec<structures::FoundNode>, String> {
+    match run_lookup(
+        is_running,
+        parameters,
+        LookupKind::Node,
+        local_node_id,
+        target_id,
+        peer_manager,
+        pending_requests,
+        transport,
+    )? {
+        LookupResult::Nodes(nodes) => Ok(nodes),
+        LookupResult::Value(_) => Err("Received value from node lookup".to_string()),
+    }
+}
+
+pub fn find_value(
+    is_running: Arc<AtomicBool>,
+    parameters: LookupParameters,
+    local_node_id: &NodeId,
+    key: &Key,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
+) -> Result<structures::Value, String> {
+    match run_lookup(
+        is_running,
+        parameters,
+        LookupKind::Value,
+        local_node_id,
+        key,
+        peer_manager,
+        pending_requests,
+        transport,
+    )? {
+        LookupResult::Value(value) => Ok(value),
+        LookupResult::Nodes(_) => Err(format!("Unable to find value for key: {}", key)),
+    }
+}
+
+/// Stores a value on the nodes closest to its key, returning how many of them accepted it.
+#[allow(clippy::too_many_arguments)]
+pub fn store_value(
+    is_running: Arc<AtomicBool>,
+    parameters: LookupParameters,
+    local_node_id: &NodeId,
+    key: &Key,
+    value: &structures::Value,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
+) -> Result<usize, String> {
+    let nodes = find_node(
+        is_running.clone(),
+        parameters,
+        local_node_id,
+        key,
+        peer_manager.clone(),
+        pending_requests.clone(),
+        transport.clone(),
+    )?;
+
+    let store_threads: Vec<JoinHandle<bool>> = nodes
+        .into_iter()
+        .map(|node| {
+            let packet = structures::Packet {
+                node_id: *local_node_id,
+                message: structures::Message::Request(structures::Request::Store(
+                    *key,
+                    value.clone(),
+                )),
+                transaction_id: TransactionId::random(),
+            };
+
+            let key = *key;
+            let peer_manager = peer_manager.clone();
+            let pending_requests = pending_requests.clone();
+            let transport = transport.clone();
+
+            thread::spawn(move || {
+                let response = send_request(
+                    &packet,
+                    &node.address,
+                    peer_manager.clone(),
+                    pending_requests,
+                    transport,
+                );
+
+                match response {
+                    Ok(_) => {
+                        debug_log(format!("Stored {} on {}", key, node.node_id));
+                        true
+                    }
+                    Err(error) => {
+                        debug_log(format!(
+                            "Failed to store {} on {}: {}",
+                            key, node.node_id, error
+                        ));
+                        peer_manager.lock().unwrap().record_failure(&node.node_id);
+                        false
+                    }
+                }
+            })
+        })
+        .collect();
+
+    Ok(store_threads
+        .into_iter()
+        .map(|store_thread| store_thread.join().unwrap_or(false))
+        .filter(|stored| *stored)
+        .count())
+}
+
+/// Runs an iterative lookup against the network, keeping up to `alpha` queries in flight until the
+/// closest `bucket_size` nodes have all responded or, for value lookups, a node returns the value.
+#[allow(clippy::too_many_arguments)]
+pub fn run_lookup(
+    is_running: Arc<AtomicBool>,
+    parameters: LookupParameters,
+    kind: LookupKind,
+    local_node_id: &NodeId,
+    target_id: &NodeId,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
+) -> Result<LookupResult, String> {
+    peer_manager.lock().unwrap().touch_bucket(target_id);
+
+    let initial_nodes = peer_manager
+        .lock()
+        .unwrap()
+        .nearby_peers(target_id)
+        .iter()
+        .map(|peer| structures::FoundNode {
+            address: peer.address,
+            node_id: peer.node_id,
+        })
+        .collect();
+
+    let mut lookup = Lookup::new(local_node_id, target_id, initial_nodes, parameters);
+
+    let (result_tx, result_rx) =
+        mpsc::channel::<(structures::FoundNode, Result<structures::Packet, String>)>();
+    let mut in_flight = 0;
+
+    loop {
+        for node in lookup.next_queries() {
+            let request = match kind {
+                LookupKind::Node => structures::Request::FindNode(*target_id),
+                LookupKind::Value => structures::Request::FindValue(*target_id),
+            };
+
+            let packet = structures::Packet {
+                node_id: *local_node_id,
+                message: structures::Message::Request(request),
+                transaction_id: TransactionId::random(),
+            };
+
+            let peer_manager = peer_manager.clone();
+            let pending_requests = pending_requests.clone();
+            let result_tx = result_tx.clone();
+            let transport = transport.clone();
+
+            in_flight += 1;
+
+            thread::spawn(move || {
+                let response = send_request(
+                    &packet,
+                    &node.address,
+                    peer_manager,
+                    pending_requests,
+                    transport,
+                );
+
+                let _ = result_tx.send((node, response));
+            });
+        }
+
+        if in_flight == 0 || lookup.is_finished() {
+            break;
+        }
+
+        let (node, response) = result_rx
+            .recv()
+            .map_err(|error| format!("Failed to receive lookup result: {}", error))?;
+
+        in_flight -= 1;
+
+        if !is_running.load(std::sync::atomic::Ordering::Relaxed) {
+            return Err("Aborting lookup due to shutdown".to_string());
+        }
+
+        let response = match response {
+            Ok(response) => response,
+            Err(_) => {
+                peer_manager.lock().unwrap().record_failure(&node.node_id);
+                lookup.on_failure(&node.node_id);
+                continue;
+            }
+        };
+
+        match (kind, response.message) {
+            (
+                LookupKind::Node,
+                structures::Message::Response(structures::Response::FindNode(nodes)),
+            )
+            | (
+                LookupKind::Value,
+                structures::Message::Response(structures::Response::FindValue(
+                    structures::FoundValue::Nodes(nodes),
+                )),
+            ) => lookup.on_response(&node.node_id, nodes),
+            (
+                LookupKind::Value,
+                structures::Message::Response(structures::Response::FindValue(
+                    structures::FoundValue::Value(value),
+                )),
+            ) => return Ok(LookupResult::Value(value)),
+            _ => {
+                error_log(format!(
+                    "Received unexpected lookup response from {} ({})",
+                    node.node_id, node.address
+                ));
+                lookup.on_failure(&node.node_id);
+            }
+        }
+    }
+
+    Ok(LookupResult::Nodes(lookup.closest()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn id(value: &str) -> NodeId {
+        value.parse().unwrap()
+    }
+
+    fn found_node(node_id: &str, port: u16) -> structures::FoundNode {
+        structures::FoundNode {
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+            node_id: id(node_id),
+        }
+    }
+
+    const LOCAL_ID: &str = "0000000000000000000000000000000000000000";
+    const TARGET_ID: &str = "ff00000000000000000000000000000000000000";
+
+    #[test]
+    fn test_lookup_queries_closest_alpha_nodes_first() {
+        let mut lookup = Lookup::new(
+            &id(LOCAL_ID),
+            &id(TARGET_ID),
+            vec![
+                found_node("0100000000000000000000000000000000000000", 1),
+                found_node("f000000000000000000000000000000000000000", 2),
+                found_node("ff00000000000000000000000000000000000001", 3),
+                found_node("8000000000000000000000000000000000000000", 4),
+            ],
+            LookupParameters::default(),
+        );
+
+        let queries: Vec<String> = lookup
+            .next_queries()
+            .into_iter()
+            .map(|node| node.node_id.to_string())
+            .collect();
+
+        assert_eq!(
+            queries,
+            vec![
+                "ff00000000000000000000000000000000000001",
+                "f000000000000000000000000000000000000000",
+                "8000000000000000000000000000000000000000",
+            ]
+        );
+        assert!(lookup.next_queries().is_empty());
+    }
+
+    #[test]
+    fn test_lookup_converges_on_returned_nodes() {
+        let mut lookup = Lookup::new(
+            &id(LOCAL_ID),
+            &id(TARGET_ID),
+            vec![found_node("0100000000000000000000000000000000000000", 1)],
+            LookupParameters::default(),
+        );
+
+        let queries = lookup.next_queries();
+        assert_eq!(queries.len(), 1);
+        assert!(!lookup.is_finished());
+
+        lookup.on_response(
+            &queries[0].node_id,
+            vec![
+                found_node(LOCAL_ID, 0),
+                found_node("ff00000000000000000000000000000000000001", 2),
+            ],
+        );
+
+        let queries = lookup.next_queries();
+        assert_eq!(queries.len(), 1);
+        assert_eq!(
+            queries[0].node_id,
+            id("ff00000000000000000000000000000000000001")
+        );
+
+        lookup.on_response(&queries[0].node_id, vec![]);
+
+        assert!(lookup.is_finished());
+        assert_eq!(
+            lookup
+                .closest()
+                .into_iter()
+                .map(|node| node.node_id.to_string())
+                .collect::<Vec<String>>(),
+            vec![
+                "ff00000000000000000000000000000000000001",
+                "0100000000000000000000000000000000000000",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lookup_finishes_when_all_nodes_fail() {
+        let mut lookup = Lookup::new(
+            &id(LOCAL_ID),
+            &id(TARGET_ID),
+            vec![found_node("0100000000000000000000000000000000000000", 1)],
+            LookupParameters::default(),
+        );
+
+        let queries = lookup.next_queries();
+        lookup.on_failure(&queries[0].node_id);
+
+        assert!(lookup.is_finished());
+        assert!(lookup.closest().is_empty());
+    }
+}
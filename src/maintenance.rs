@@ -0,0 +1,210 @@
+use crate::lookup::{find_node, store_value, LookupParameters};
+use crate::messages::ping_peer;
+use crate::node_id::{Key, NodeId};
+use crate::peers::PeerManager;
+use crate::pending_requests::PendingRequests;
+use crate::shutdown::Shutdown;
+use crate::transport::Transport;
+use crate::values::ValueStore;
+use crate::{debug_log, error_log, structures};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const BUCKET_REFRESH_AGE: u64 = 60 * 60;
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+const STALE_PEER_AGE: u64 = 15 * 60;
+
+/// Keeps the routing table healthy by refreshing buckets that have not seen a lookup in an hour
+/// and re-pinging peers that have gone quiet, evicting the ones that keep failing. Stored values
+/// are purged once expired and republished or replicated when due.
+#[allow(clippy::too_many_arguments)]
+pub fn start_maintenance(
+    is_running: Arc<AtomicBool>,
+    shutdown: Arc<Shutdown>,
+    parameters: LookupParameters,
+    local_node_id: NodeId,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    value_store: Arc<Mutex<ValueStore>>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let clock = pending_requests.clock();
+
+        while clock.wait_until(clock.now() + MAINTENANCE_INTERVAL, &shutdown) {
+            refresh_buckets(
+                is_running.clone(),
+                parameters,
+                &local_node_id,
+                peer_manager.clone(),
+                pending_requests.clone(),
+                transport.clone(),
+            );
+
+            ping_stale_peers(
+                &local_node_id,
+                peer_manager.clone(),
+                pending_requests.clone(),
+                transport.clone(),
+            );
+
+            maintain_values(
+                is_running.clone(),
+                parameters,
+                &local_node_id,
+                peer_manager.clone(),
+                value_store.clone(),
+                pending_requests.clone(),
+                transport.clone(),
+            );
+        }
+    })
+}
+
+fn refresh_buckets(
+    is_running: Arc<AtomicBool>,
+    parameters: LookupParameters,
+    local_node_id: &NodeId,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
+) {
+    let targets = peer_manager
+        .lock()
+        .unwrap()
+        .stale_bucket_targets(BUCKET_REFRESH_AGE);
+
+    if targets.is_empty() {
+        return;
+    }
+
+    debug_log(format!("Refreshing {} stale buckets", targets.len()));
+
+    for target_id in targets {
+        if !is_running.load(std::sync::atomic::Ordering::Relaxed) {
+            return;
+        }
+
+        if let Err(error) = find_node(
+            is_running.clone(),
+            parameters,
+            local_node_id,
+            &target_id,
+            peer_manager.clone(),
+            pending_requests.clone(),
+            transport.clone(),
+        ) {
+            error_log(format!(
+                "Failed to refresh bucket for {}: {}",
+                target_id, error
+            ));
+        }
+    }
+}
+
+fn ping_stale_peers(
+    local_node_id: &NodeId,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
+) {
+    let stale_peers = peer_manager.lock().unwrap().stale_peers(STALE_PEER_AGE);
+
+    let ping_threads: Vec<JoinHandle<()>> = stale_peers
+        .into_iter()
+        .map(|peer| {
+            let local_node_id = *local_node_id;
+            let peer_manager = peer_manager.clone();
+            let pending_requests = pending_requests.clone();
+            let transport = transport.clone();
+
+            thread::spawn(move || {
+                let response = ping_peer(
+                    &local_node_id,
+                    &peer.address,
+                    peer_manager.clone(),
+                    pending_requests,
+                    transport,
+                );
+
+                if response.is_ok() {
+                    return;
+                }
+
+                if let Some(evicted_peer) =
+                    peer_manager.lock().unwrap().record_failure(&peer.node_id)
+                {
+                    debug_log(format!(
+                        "Evicted unresponsive peer {} ({})",
+                        evicted_peer.node_id, evicted_peer.address
+                    ));
+                }
+            })
+        })
+        .collect();
+
+    for ping_thread in ping_threads {
+        let _ = ping_thread.join();
+    }
+}
+
+/// Purges expired values, then takes the values we published that are due to be republished and
+/// the values others published that are due to be replicated. Returns how many values were
+/// purged along with the due values.
+pub fn take_due_values(
+    value_store: &mut ValueStore,
+    local_node_id: &NodeId,
+) -> Result<(usize, Vec<(Key, structures::Value)>), String> {
+    let expired_count = value_store.purge_expired()?;
+
+    let mut due_values = value_store.take_due_for_republish(local_node_id)?;
+    due_values.extend(value_store.take_due_for_replication(local_node_id)?);
+
+    Ok((expired_count, due_values))
+}
+
+fn maintain_values(
+    is_running: Arc<AtomicBool>,
+    parameters: LookupParameters,
+    local_node_id: &NodeId,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    value_store: Arc<Mutex<ValueStore>>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
+) {
+    let due = take_due_values(&mut value_store.lock().unwrap(), local_node_id);
+
+    let (expired_count, due_values) = match due {
+        Ok(due) => due,
+        Err(error) => {
+            error_log(format!("Failed to maintain stored values: {}", error));
+            return;
+        }
+    };
+
+    if expired_count > 0 {
+        debug_log(format!("Purged {} expired values", expired_count));
+    }
+
+    for (key, value) in due_values {
+        if !is_running.load(std::sync::atomic::Ordering::Relaxed) {
+            return;
+        }
+
+        match store_value(
+            is_running.clone(),
+            parameters,
+            local_node_id,
+            &key,
+            &value,
+            peer_manager.clone(),
+            pending_requests.clone(),
+            transport.clone(),
+        ) {
+            Ok(stored_count) => debug_log(format!("Republished {} to {} peers", key, stored_count)),
+            Err(error) => error_log(format!("Failed to republish {}: {}", key, error)),
+        }
+    }
+}
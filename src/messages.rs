@@ -1,88 +1,142 @@
-use crate::peers::PeerManager;
-use crate::utilities::random_sha1_to_string;
+use crate::clock::Clock;
+use crate::node_id::{NodeId, TransactionId};
+use crate::peers::{PeerManager, PeerStatus};
+use crate::pending_requests::PendingRequests;
+use crate::transport::Transport;
 use crate::values::ValueStore;
-use crate::{error_log, recv_log, send_log, structures};
-use std::collections::VecDeque;
+use crate::{debug_log, error_log, recv_log, send_log, structures};
 use std::net::SocketAddr;
-use std::sync::atomic::AtomicBool;
 use std::sync::{mpsc, Arc, Mutex};
-use std::thread::{sleep, JoinHandle};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+pub const REQUEST_ATTEMPTS: u32 = 3;
 
 pub fn process_incoming_requests(
-    is_running: Arc<AtomicBool>,
-    local_node_id: String,
+    local_node_id: NodeId,
     peer_manager: Arc<Mutex<PeerManager>>,
     value_store: Arc<Mutex<ValueStore>>,
-    response_queue: Arc<Mutex<VecDeque<structures::Packet>>>,
-    receive_rx: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
-    send_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
 ) -> JoinHandle<()> {
     let peer_manager_clone = peer_manager.clone();
     let value_store_clone = value_store.clone();
 
     std::thread::spawn(move || {
-        while is_running.load(std::sync::atomic::Ordering::Relaxed) {
-            receive_rx.try_iter().for_each(|(src, data)| {
-                let packet: structures::Packet = match bincode::deserialize(data.as_slice()) {
-                    Ok(packet) => packet,
-                    Err(error) => {
-                        error_log(format!("Failed to deserialize packet: {}", error));
-                        return;
-                    }
-                };
-
-                let peer =
-                    match peer_manager_clone
-                        .lock()
-                        .unwrap()
-                        .add_peer(&src, &packet.node_id, true)
-                    {
-                        Ok(peer) => peer,
-                        Err(error) => {
-                            error_log(error);
-                            return;
-                        }
-                    };
-
-                recv_log(format!(
-                    "Received {:?} from peer {} ({})",
-                    &packet.message, &peer.node_id, &peer.address
-                ));
-
-                if let structures::Message::Response(_) = packet.message {
-                    let mut queue = response_queue.lock().unwrap();
-
-                    queue.push_back(packet);
-
-                    return;
+        // Receiving stops once the transport is closed
+        while let Some((src, data)) = transport.receive() {
+            let packet: structures::Packet = match bincode::deserialize(data.as_slice()) {
+                Ok(packet) => packet,
+                Err(error) => {
+                    error_log(format!("Failed to deserialize packet: {}", error));
+                    continue;
+                }
+            };
+
+            recv_log(format!(
+                "Received {:?} from peer {} ({})",
+                &packet.message, &packet.node_id, &src
+            ));
+
+            let node_id = packet.node_id;
+            let is_response = matches!(packet.message, structures::Message::Response(_));
+
+            // Only responses to requests we are waiting on are accepted, so peers cannot be
+            // added to the routing table by unsolicited responses
+            if is_response {
+                if let Err(error) = pending_requests.expects(&src, &packet) {
+                    debug_log(error);
+                    continue;
                 }
+            }
 
-                let send_tx = send_tx.clone();
+            let peer_status = match peer_manager_clone.lock().unwrap().add_peer(&src, &node_id) {
+                Ok(peer_status) => peer_status,
+                Err(error) => {
+                    error_log(error);
+                    continue;
+                }
+            };
 
-                handle_request(
-                    &local_node_id,
-                    &packet,
-                    &peer,
+            if let PeerStatus::Cached {
+                stale_peer: Some(stale_peer),
+                ..
+            } = &peer_status
+            {
+                check_stale_peer(
+                    local_node_id,
+                    stale_peer.clone(),
                     peer_manager_clone.clone(),
-                    value_store_clone.clone(),
-                    send_tx,
+                    pending_requests.clone(),
+                    transport.clone(),
                 );
-            });
+            }
+
+            // The response is delivered once the peer is recorded, so a caller woken by it
+            // already sees the peer in the routing table
+            if is_response {
+                if let Err(error) = pending_requests.complete(&src, packet) {
+                    debug_log(error);
+                }
+
+                continue;
+            }
 
-            sleep(std::time::Duration::from_millis(100));
+            let peer = peer_status.peer().clone();
+
+            handle_request(
+                &local_node_id,
+                &packet,
+                &peer,
+                peer_manager_clone.clone(),
+                value_store_clone.clone(),
+                transport.clone(),
+            );
         }
     })
 }
 
+/// Pings the least recently seen peer of a full bucket in the background, evicting it in favour
+/// of a replacement cache entry if it does not respond.
+fn check_stale_peer(
+    local_node_id: NodeId,
+    stale_peer: structures::Peer,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
+) {
+    std::thread::spawn(move || {
+        let response = ping_peer(
+            &local_node_id,
+            &stale_peer.address,
+            peer_manager.clone(),
+            pending_requests,
+            transport,
+        );
+
+        if response.is_err() {
+            debug_log(format!(
+                "Evicting unresponsive peer {} ({})",
+                stale_peer.node_id, stale_peer.address
+            ));
+        }
+
+        peer_manager
+            .lock()
+            .unwrap()
+            .resolve_eviction(&stale_peer.node_id, response.is_ok());
+    });
+}
+
 fn handle_request(
-    local_node_id: &str,
+    local_node_id: &NodeId,
     packet: &structures::Packet,
     peer: &structures::Peer,
     peer_manager: Arc<Mutex<PeerManager>>,
     value_store: Arc<Mutex<ValueStore>>,
-    send_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+    transport: Arc<dyn Transport>,
 ) {
-    let message = match &packet.message {
+    let request = match &packet.message {
         structures::Message::Request(request) => request,
         _ => {
             error_log("Received response when expecting request".to_string());
@@ -90,127 +144,92 @@ fn handle_request(
         }
     };
 
-    match message {
-        structures::Request::Ping => {
-            let response = structures::Packet {
-                node_id: local_node_id.to_string(),
-                transaction_id: packet.transaction_id.clone(),
-                message: structures::Message::Response(structures::Response::Pong),
-            };
+    let response = respond(
+        request,
+        &peer_manager.lock().unwrap(),
+        &mut value_store.lock().unwrap(),
+    );
 
-            send_packet(&response, &peer.address, send_tx).unwrap();
-        }
-        structures::Request::FindNode(node_id) => {
-            let nodes = peer_manager.lock().unwrap().nearby_peers(node_id);
-
-            if let Err(error) = nodes {
-                error_log(error);
-                return;
-            }
+    let response = structures::Packet {
+        node_id: *local_node_id,
+        transaction_id: packet.transaction_id,
+        message: structures::Message::Response(response),
+    };
 
-            let nodes = nodes
-                .unwrap()
-                .iter()
-                .map(|peer| structures::FoundNode {
-                    address: peer.address,
-                    node_id: peer.node_id.clone(),
-                })
-                .collect();
-
-            let response = structures::Packet {
-                node_id: local_node_id.to_string(),
-                transaction_id: packet.transaction_id.clone(),
-                message: structures::Message::Response(structures::Response::FindNode(nodes)),
-            };
+    send_response(&response, &peer.address, transport);
+}
 
-            send_packet(&response, &peer.address, send_tx).unwrap();
+/// Answers a request from the routing table and value store. This does no I/O, so anything
+/// driving nodes without a transport answers requests exactly as a running node does.
+pub fn respond(
+    request: &structures::Request,
+    peer_manager: &PeerManager,
+    value_store: &mut ValueStore,
+) -> structures::Response {
+    match request {
+        structures::Request::Ping => structures::Response::Pong,
+        structures::Request::FindNode(node_id) => {
+            structures::Response::FindNode(found_nodes(peer_manager, node_id))
         }
         structures::Request::Store(key, value) => {
-            value_store.lock().unwrap().store(key, value);
+            if let Err(error) = value_store.store(key, value) {
+                error_log(format!("Failed to store {}: {}", key, error));
+            }
 
-            let response = structures::Packet {
-                node_id: local_node_id.to_string(),
-                transaction_id: packet.transaction_id.clone(),
-                message: structures::Message::Response(structures::Response::Store),
+            structures::Response::Store
+        }
+        structures::Request::FindValue(key) => {
+            let found_value = match value_store.retrieve(key) {
+                Ok(Some(value)) => structures::FoundValue::Value(value),
+                Ok(None) => structures::FoundValue::Nodes(found_nodes(peer_manager, key)),
+                Err(error) => {
+                    error_log(format!("Failed to read {}: {}", key, error));
+                    structures::FoundValue::Nodes(found_nodes(peer_manager, key))
+                }
             };
 
-            send_packet(&response, &peer.address, send_tx).unwrap();
-        }
-        _ => {
-            error_log("Received unhandled request".to_string());
+            structures::Response::FindValue(found_value)
         }
     }
 }
 
-pub fn find_nearby_peers(
-    is_running: Arc<AtomicBool>,
-    local_node_id: &str,
-    peer_manager: Arc<Mutex<PeerManager>>,
-    response_queue: Arc<Mutex<VecDeque<structures::Packet>>>,
-    send_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
-) -> Result<(), String> {
-    let nearby_peers = peer_manager.lock().unwrap().nearby_peers(local_node_id)?;
-
-    for peer in nearby_peers {
-        let packet = structures::Packet {
-            node_id: local_node_id.to_string(),
-            message: structures::Message::Request(structures::Request::FindNode(
-                local_node_id.to_string(),
-            )),
-            transaction_id: random_sha1_to_string(),
-        };
-
-        send_packet(&packet, &peer.address, send_tx.clone())?;
-
-        let response = match wait_for_response(
-            is_running.clone(),
-            response_queue.clone(),
-            &packet.transaction_id,
-        ) {
-            Ok(response) => response,
-            Err(_) => {
-                continue;
-            }
-        };
-
-        let nodes = match response.message {
-            structures::Message::Response(structures::Response::FindNode(nodes)) => nodes,
-            _ => {
-                error_log("Received unexpected response".to_string());
-                continue;
-            }
-        };
-
-        for node in nodes {
-            let peer =
-                peer_manager
-                    .lock()
-                    .unwrap()
-                    .add_peer(&node.address, &node.node_id, false)?;
-
-            let ping_packet = structures::Packet {
-                node_id: local_node_id.to_string(),
-                message: structures::Message::Request(structures::Request::Ping),
-                transaction_id: random_sha1_to_string(),
-            };
-
-            send_packet(&ping_packet, &peer.address, send_tx.clone())?;
+fn found_nodes(peer_manager: &PeerManager, target_id: &NodeId) -> Vec<structures::FoundNode> {
+    peer_manager
+        .nearby_peers(target_id)
+        .iter()
+        .map(|peer| structures::FoundNode {
+            address: peer.address,
+            node_id: peer.node_id,
+        })
+        .collect()
+}
 
-            let _ = wait_for_response(
-                is_running.clone(),
-                response_queue.clone(),
-                &ping_packet.transaction_id,
-            );
-        }
-    }
+pub fn ping_peer(
+    local_node_id: &NodeId,
+    socket_addr: &SocketAddr,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
+) -> Result<structures::Packet, String> {
+    let packet = structures::Packet {
+        node_id: *local_node_id,
+        message: structures::Message::Request(structures::Request::Ping),
+        transaction_id: TransactionId::random(),
+    };
 
-    Ok(())
+    send_request(
+        &packet,
+        socket_addr,
+        peer_manager,
+        pending_requests,
+        transport,
+    )
 }
 
 pub fn send_packet(
     packet: &structures::Packet,
     socket_addr: &SocketAddr,
-    send_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+    transport: Arc<dyn Transport>,
 ) -> Result<(), String> {
     let data = bincode::serialize(&packet).map_err(|error| {
         format!(
@@ -224,45 +243,209 @@ pub fn send_packet(
         &packet.message, &socket_addr
     ));
 
-    send_tx
-        .send((*socket_addr, data))
-        .map_err(|error| format!("Failed to send packet to peer: {}", error))?;
+    transport
+        .send(socket_addr, &data)
+        .map_err(|error| format!("Failed to send packet to peer {}: {}", socket_addr, error))
+}
 
-    Ok(())
+fn send_response(
+    packet: &structures::Packet,
+    socket_addr: &SocketAddr,
+    transport: Arc<dyn Transport>,
+) {
+    if let Err(error) = send_packet(packet, socket_addr, transport) {
+        error_log(error);
+    }
 }
 
-pub fn wait_for_response(
-    is_running: Arc<AtomicBool>,
-    response_queue: Arc<Mutex<VecDeque<structures::Packet>>>,
-    transaction_id: &str,
+/// Sends a request and blocks until its response arrives, every attempt times out, or the node
+/// shuts down. Unanswered requests are resent with the timeout doubling each time, starting from
+/// a timeout based on the peer's measured round trip time.
+pub fn send_request(
+    packet: &structures::Packet,
+    socket_addr: &SocketAddr,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
 ) -> Result<structures::Packet, String> {
-    let end_time = std::time::SystemTime::now() + std::time::Duration::from_secs(5);
-
-    while is_running.load(std::sync::atomic::Ordering::Relaxed) {
-        let mut queue = response_queue.lock().unwrap();
+    let attempt_timeouts = peer_manager.lock().unwrap().request_timeouts(socket_addr);
+
+    // Retries reuse the transaction ID, so a late response to an earlier attempt still counts
+    let response_rx = pending_requests.register(
+        &packet.transaction_id,
+        socket_addr,
+        attempt_timeouts.iter().sum(),
+    );
+
+    let clock = pending_requests.clock();
+    let sent_at = clock.now();
+    let response = transmit(
+        packet,
+        socket_addr,
+        &attempt_timeouts,
+        &response_rx,
+        clock,
+        transport,
+    );
+
+    pending_requests.remove(&packet.transaction_id);
+
+    let (attempt, response) = response?;
+
+    // A response to a resent request could be answering any of the attempts, so only responses
+    // to the first attempt give an RTT sample (Karn's algorithm)
+    if attempt == 0 {
+        peer_manager
+            .lock()
+            .unwrap()
+            .record_rtt(&response.node_id, clock.now() - sent_at);
+    }
 
-        if let Some(val) = queue.pop_front() {
-            if val.transaction_id == *transaction_id {
-                return Ok(val);
-            }
+    Ok(response)
+}
 
-            queue.push_back(val);
+/// Sends the request until an attempt is answered, returning the index of that attempt with the
+/// response.
+fn transmit(
+    packet: &structures::Packet,
+    socket_addr: &SocketAddr,
+    attempt_timeouts: &[Duration],
+    response_rx: &mpsc::Receiver<structures::Packet>,
+    clock: &dyn Clock,
+    transport: Arc<dyn Transport>,
+) -> Result<(usize, structures::Packet), String> {
+    for (attempt, attempt_timeout) in attempt_timeouts.iter().enumerate() {
+        if attempt > 0 {
+            debug_log(format!(
+                "Resending transaction {} to {} (attempt {} of {})",
+                packet.transaction_id,
+                socket_addr,
+                attempt + 1,
+                attempt_timeouts.len()
+            ));
         }
 
-        drop(queue);
+        send_packet(packet, socket_addr, transport.clone())?;
 
-        sleep(std::time::Duration::from_millis(50));
+        let deadline = clock.now() + *attempt_timeout;
 
-        if std::time::SystemTime::now() > end_time {
-            return Err(format!(
-                "Timed out waiting for response to transaction: {}",
-                transaction_id
-            ));
+        if let Some(response) = wait_for_response(response_rx, deadline, clock)? {
+            return Ok((attempt, response));
         }
     }
 
     Err(format!(
-        "Aborting wait for response to transaction: {}",
-        transaction_id
+        "Timed out waiting for response to transaction {} after {} attempts",
+        packet.transaction_id,
+        attempt_timeouts.len()
     ))
 }
+
+/// Waits for a response until the clock passes the deadline, returning `None` if it does. The
+/// pending requests are closed when the node shuts down, which ends the wait early.
+fn wait_for_response(
+    response_rx: &mpsc::Receiver<structures::Packet>,
+    deadline: Instant,
+    clock: &dyn Clock,
+) -> Result<Option<structures::Packet>, String> {
+    loop {
+        let timeout = clock.timeout_until(deadline);
+
+        if timeout.is_zero() {
+            return Ok(None);
+        }
+
+        match response_rx.recv_timeout(timeout) {
+            Ok(response) => return Ok(Some(response)),
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err("Aborting wait for response, the node is shutting down".to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, SystemClock};
+    use crate::transport::MemoryNetwork;
+    use std::thread;
+
+    #[test]
+    fn test_wait_for_response_times_out_when_clock_passes_deadline() {
+        let clock = Arc::new(ManualClock::new(0));
+        let (_response_tx, response_rx) = mpsc::channel::<structures::Packet>();
+        let deadline = clock.now() + Duration::from_secs(60);
+
+        let clock_clone = clock.clone();
+        let wait_thread =
+            thread::spawn(move || wait_for_response(&response_rx, deadline, clock_clone.as_ref()));
+
+        // The wait ends as soon as the clock is advanced, not after a minute
+        clock.advance(Duration::from_secs(60));
+
+        assert_eq!(wait_thread.join().unwrap(), Ok(None));
+    }
+
+    #[test]
+    fn test_wait_for_response_ends_when_requests_are_closed() {
+        let pending_requests = Arc::new(PendingRequests::new(Arc::new(SystemClock)));
+        let response_rx = pending_requests.register(
+            &TransactionId::random(),
+            &SocketAddr::from(([127, 0, 0, 1], 16600)),
+            Duration::from_secs(60),
+        );
+        let deadline = Instant::now() + Duration::from_secs(60);
+
+        let wait_thread =
+            thread::spawn(move || wait_for_response(&response_rx, deadline, &SystemClock));
+
+        pending_requests.close();
+
+        assert!(wait_thread.join().unwrap().is_err());
+        assert!(Instant::now() < deadline);
+    }
+
+    #[test]
+    fn test_transmit_reports_which_attempt_was_answered() {
+        let network = MemoryNetwork::new();
+        let local = network
+            .bind(&SocketAddr::from(([10, 0, 0, 1], 16600)))
+            .unwrap();
+        let remote_address = SocketAddr::from(([10, 0, 0, 1], 16601));
+        let remote = network.bind(&remote_address).unwrap();
+
+        let packet = structures::Packet {
+            node_id: NodeId::random(),
+            transaction_id: TransactionId::random(),
+            message: structures::Message::Request(structures::Request::Ping),
+        };
+        let response = structures::Packet {
+            message: structures::Message::Response(structures::Response::Pong),
+            ..packet.clone()
+        };
+
+        // The first attempt goes unanswered, so the response belongs to the second
+        let (response_tx, response_rx) = mpsc::channel();
+        let remote_thread = thread::spawn(move || {
+            remote.receive().unwrap();
+            remote.receive().unwrap();
+            response_tx.send(response).unwrap();
+        });
+
+        let (attempt, _) = transmit(
+            &packet,
+            &remote_address,
+            &[Duration::from_millis(50), Duration::from_secs(5)],
+            &response_rx,
+            &SystemClock,
+            Arc::new(local),
+        )
+        .unwrap();
+
+        remote_thread.join().unwrap();
+
+        assert_eq!(attempt, 1);
+    }
+}
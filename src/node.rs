@@ -0,0 +1,708 @@
+use crate::clock::{Clock, SystemClock};
+use crate::lookup::{find_node, find_value, store_value, LookupParameters, ALPHA};
+use crate::maintenance::start_maintenance;
+use crate::messages::{ping_peer, process_incoming_requests};
+use crate::node_id::{Key, NodeId};
+use crate::node_state::{new_node_state, start_snapshots, LoadedState, StatePath, StateStore};
+use crate::peers::{PeerManager, PeerParameters};
+use crate::pending_requests::PendingRequests;
+use crate::rtt::Timeouts;
+use crate::shutdown::Shutdown;
+use crate::structures;
+use crate::transport::{Transport, UdpTransport};
+use crate::values::{ValueStore, MAX_TTL};
+use crate::{debug_log, error_log};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const BOOTSTRAP_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_BOOTSTRAP_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Configures and starts a `Node`.
+pub struct NodeBuilder {
+    alpha: usize,
+    bind_address: SocketAddr,
+    bootstrap_peers: Vec<SocketAddr>,
+    clock: Arc<dyn Clock>,
+    compaction_interval: Duration,
+    peer_parameters: PeerParameters,
+    snapshot_interval: Duration,
+    state_path: Option<StatePath>,
+    transport: Option<Arc<dyn Transport>>,
+}
+
+impl NodeBuilder {
+    /// Starts from a node bound to an ephemeral port on all interfaces, with no state file and
+    /// the standard Kademlia parameters.
+    pub fn new() -> Self {
+        Self {
+            alpha: ALPHA,
+            bind_address: SocketAddr::from(([0, 0, 0, 0], 0)),
+            bootstrap_peers: Vec::new(),
+            clock: Arc::new(SystemClock),
+            compaction_interval: COMPACTION_INTERVAL,
+            peer_parameters: PeerParameters::default(),
+            snapshot_interval: SNAPSHOT_INTERVAL,
+            state_path: None,
+            transport: None,
+        }
+    }
+
+    pub fn bind_address(mut self, bind_address: SocketAddr) -> Self {
+        self.bind_address = bind_address;
+        self
+    }
+
+    /// Loads the node's ID, peers and values from this file, and saves them back periodically and
+    /// on shutdown. Without a state file the node starts with a random ID and keeps nothing.
+    pub fn state_file(mut self, state_file: &str) -> Self {
+        self.state_path = Some(StatePath::File(state_file.to_string()));
+        self
+    }
+
+    /// Like `state_file`, but keeps the node's ID and peers in separate files in this directory
+    /// and writes each value to a log on disk as it is stored, so values need not fit in memory.
+    pub fn data_dir(mut self, data_dir: &str) -> Self {
+        self.state_path = Some(StatePath::DataDir(data_dir.to_string()));
+        self
+    }
+
+    /// How often the routing table is saved while the node runs. Default: 60 seconds
+    pub fn snapshot_interval(mut self, snapshot_interval: Duration) -> Self {
+        self.snapshot_interval = snapshot_interval;
+        self
+    }
+
+    /// How often the values log in a data directory is checked for space to reclaim from
+    /// replaced and expired values. Default: 5 minutes
+    pub fn compaction_interval(mut self, compaction_interval: Duration) -> Self {
+        self.compaction_interval = compaction_interval;
+        self
+    }
+
+    /// Uses an already bound transport instead of binding a UDP socket to the bind address.
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Uses this clock for timestamps, expiry and timeouts instead of the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Peers to contact when the node starts, so it can join the network. Contacting them is
+    /// retried until one responds or another node contacts us.
+    pub fn bootstrap_peers(mut self, bootstrap_peers: Vec<SocketAddr>) -> Self {
+        self.bootstrap_peers = bootstrap_peers;
+        self
+    }
+
+    /// Kademlia's k, the size of each routing table bucket and the number of nodes a value is
+    /// stored on.
+    pub fn bucket_size(mut self, bucket_size: usize) -> Self {
+        self.peer_parameters.bucket_size = bucket_size;
+        self
+    }
+
+    /// How many peers each bucket keeps in reserve for when one of its peers goes away.
+    pub fn replacement_cache_size(mut self, replacement_cache_size: usize) -> Self {
+        self.peer_parameters.replacement_cache_size = replacement_cache_size;
+        self
+    }
+
+    /// How many requests in a row a peer may fail to answer before it is evicted.
+    pub fn max_failed_requests(mut self, max_failed_requests: u32) -> Self {
+        self.peer_parameters.max_failed_requests = max_failed_requests;
+        self
+    }
+
+    /// How many times a request is sent before the peer is counted as failing it.
+    pub fn request_attempts(mut self, request_attempts: u32) -> Self {
+        self.peer_parameters.request_attempts = request_attempts;
+        self
+    }
+
+    /// The request timeout for new peers and the bounds on the timeouts measured from RTTs.
+    pub fn request_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.peer_parameters.timeouts = timeouts;
+        self
+    }
+
+    /// How many queries a lookup keeps in flight at once.
+    pub fn alpha(mut self, alpha: usize) -> Self {
+        self.alpha = alpha;
+        self
+    }
+
+    /// Checks the parameters without starting anything. `build` does this too.
+    pub fn validate(&self) -> Result<(), String> {
+        let peer_parameters = &self.peer_parameters;
+        let timeouts = &peer_parameters.timeouts;
+
+        if peer_parameters.bucket_size == 0 {
+            return Err("Bucket size must be at least 1".to_string());
+        }
+
+        if self.alpha == 0 {
+            return Err("Alpha must be at least 1".to_string());
+        }
+
+        if peer_parameters.max_failed_requests == 0 {
+            return Err("Max failed requests must be at least 1".to_string());
+        }
+
+        if peer_parameters.request_attempts == 0 {
+            return Err("Request attempts must be at least 1".to_string());
+        }
+
+        if timeouts.min.is_zero() {
+            return Err("Minimum request timeout must be above zero".to_string());
+        }
+
+        if timeouts.min > timeouts.max {
+            return Err(format!(
+                "Minimum request timeout ({:?}) is above the maximum ({:?})",
+                timeouts.min, timeouts.max
+            ));
+        }
+
+        if timeouts.initial < timeouts.min || timeouts.initial > timeouts.max {
+            return Err(format!(
+                "Initial request timeout ({:?}) must be between {:?} and {:?}",
+                timeouts.initial, timeouts.min, timeouts.max
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn build(self) -> Result<Node, String> {
+        self.validate()?;
+
+        let state_store = match &self.state_path {
+            Some(state_path) => Some(Arc::new(StateStore::open(state_path)?)),
+            None => None,
+        };
+
+        let loaded_state = match &state_store {
+            Some(state_store) => state_store.load()?,
+            None => LoadedState::from(new_node_state()),
+        };
+
+        let local_node_id = loaded_state.node_id;
+        let parameters = LookupParameters {
+            alpha: self.alpha,
+            bucket_size: self.peer_parameters.bucket_size,
+        };
+
+        let peer_manager = PeerManager::new(
+            loaded_state.buckets,
+            &local_node_id,
+            self.peer_parameters,
+            self.clock.clone(),
+        )?;
+        debug_log(format!("Loaded {} peers", peer_manager.to_vec().len()));
+
+        let value_store = ValueStore::new(loaded_state.storage, self.clock.clone())?;
+        debug_log(format!("Loaded {} values", value_store.len()));
+
+        let transport: Arc<dyn Transport> = match self.transport {
+            Some(transport) => transport,
+            None => Arc::new(UdpTransport::bind(&self.bind_address)?),
+        };
+
+        debug_log(format!(
+            "[{}] Started server on {}",
+            local_node_id,
+            transport.local_address()
+        ));
+
+        let is_running = Arc::new(AtomicBool::new(true));
+        let shutdown = Arc::new(Shutdown::new());
+
+        let peer_manager = Arc::new(Mutex::new(peer_manager));
+        let pending_requests = Arc::new(PendingRequests::new(self.clock.clone()));
+        let value_store = Arc::new(Mutex::new(value_store));
+
+        let process_messages_thread = process_incoming_requests(
+            local_node_id,
+            peer_manager.clone(),
+            value_store.clone(),
+            pending_requests.clone(),
+            transport.clone(),
+        );
+
+        let maintenance_thread = start_maintenance(
+            is_running.clone(),
+            shutdown.clone(),
+            parameters,
+            local_node_id,
+            peer_manager.clone(),
+            value_store.clone(),
+            pending_requests.clone(),
+            transport.clone(),
+        );
+
+        let bootstrap_thread = bootstrap(
+            is_running.clone(),
+            shutdown.clone(),
+            parameters,
+            local_node_id,
+            self.bootstrap_peers,
+            peer_manager.clone(),
+            pending_requests.clone(),
+            transport.clone(),
+        );
+
+        let mut threads = vec![
+            process_messages_thread,
+            maintenance_thread,
+            bootstrap_thread,
+        ];
+
+        if let Some(state_store) = &state_store {
+            threads.push(start_snapshots(
+                shutdown.clone(),
+                self.clock.clone(),
+                state_store.clone(),
+                self.snapshot_interval,
+                self.compaction_interval,
+                local_node_id,
+                peer_manager.clone(),
+                value_store.clone(),
+            ));
+        }
+
+        Ok(Node {
+            started_at: self.clock.now(),
+            clock: self.clock,
+            is_running,
+            local_node_id,
+            parameters,
+            peer_manager,
+            pending_requests,
+            shutdown,
+            state_store,
+            threads: Mutex::new(threads),
+            transport,
+            value_store,
+        })
+    }
+}
+
+impl Default for NodeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A running DHT node. The node answers requests and maintains its routing table and stored
+/// values in the background until it is shut down or dropped.
+pub struct Node {
+    clock: Arc<dyn Clock>,
+    is_running: Arc<AtomicBool>,
+    local_node_id: NodeId,
+    parameters: LookupParameters,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    pending_requests: Arc<PendingRequests>,
+    shutdown: Arc<Shutdown>,
+    started_at: Instant,
+    state_store: Option<Arc<StateStore>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    transport: Arc<dyn Transport>,
+    value_store: Arc<Mutex<ValueStore>>,
+}
+
+/// A summary of a running node, for monitoring.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct NodeStats {
+    pub active_peer_count: usize,
+    pub address: SocketAddr,
+    pub node_id: NodeId,
+    pub peer_count: usize,
+    pub uptime_seconds: u64,
+    pub value_count: usize,
+}
+
+impl Node {
+    pub fn builder() -> NodeBuilder {
+        NodeBuilder::new()
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.local_node_id
+    }
+
+    pub fn local_address(&self) -> SocketAddr {
+        self.transport.local_address()
+    }
+
+    pub fn peers(&self) -> Vec<structures::Peer> {
+        self.peer_manager.lock().unwrap().to_vec()
+    }
+
+    pub fn stats(&self) -> NodeStats {
+        let peers = self.peers();
+
+        NodeStats {
+            active_peer_count: peers.iter().filter(|peer| peer.active).count(),
+            address: self.local_address(),
+            node_id: self.local_node_id,
+            peer_count: peers.len(),
+            uptime_seconds: (self.clock.now() - self.started_at).as_secs(),
+            value_count: self.value_store.lock().unwrap().len(),
+        }
+    }
+
+    /// Pings a node, adding it to the routing table if it responds. Returns the node's ID.
+    pub fn ping(&self, socket_addr: &SocketAddr) -> Result<NodeId, String> {
+        let response = ping_peer(
+            &self.local_node_id,
+            socket_addr,
+            self.peer_manager.clone(),
+            self.pending_requests.clone(),
+            self.transport.clone(),
+        )?;
+
+        Ok(response.node_id)
+    }
+
+    /// Looks up the nodes closest to the target ID.
+    pub fn find_node(&self, target_id: &NodeId) -> Result<Vec<structures::FoundNode>, String> {
+        find_node(
+            self.is_running.clone(),
+            self.parameters,
+            &self.local_node_id,
+            target_id,
+            self.peer_manager.clone(),
+            self.pending_requests.clone(),
+            self.transport.clone(),
+        )
+    }
+
+    /// Publishes a value under the key for `ttl` seconds, at most `MAX_TTL`, storing it locally
+    /// and on the closest nodes. Returns how many remote nodes accepted it.
+    pub fn put(&self, key: &Key, data: Vec<u8>, ttl: u64) -> Result<usize, String> {
+        let value = structures::Value {
+            data,
+            published_at: self.clock.unix_timestamp(),
+            publisher: self.local_node_id,
+            ttl: ttl.min(MAX_TTL),
+        };
+
+        self.value_store.lock().unwrap().store(key, &value)?;
+
+        store_value(
+            self.is_running.clone(),
+            self.parameters,
+            &self.local_node_id,
+            key,
+            &value,
+            self.peer_manager.clone(),
+            self.pending_requests.clone(),
+            self.transport.clone(),
+        )
+    }
+
+    /// Returns the value stored under the key, checking the local store before the network.
+    pub fn get(&self, key: &Key) -> Result<structures::Value, String> {
+        let local_value = self.value_store.lock().unwrap().retrieve(key)?;
+
+        if let Some(value) = local_value {
+            return Ok(value);
+        }
+
+        find_value(
+            self.is_running.clone(),
+            self.parameters,
+            &self.local_node_id,
+            key,
+            self.peer_manager.clone(),
+            self.pending_requests.clone(),
+            self.transport.clone(),
+        )
+    }
+
+    /// Stops the node, waits for its background threads and saves its state. Calling this again
+    /// once the node has stopped does nothing.
+    pub fn shutdown(&self) -> Result<(), String> {
+        let mut threads = self.threads.lock().unwrap();
+
+        if threads.is_empty() {
+            return Ok(());
+        }
+
+        self.is_running
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        self.shutdown.signal();
+        self.pending_requests.close();
+        self.transport.close();
+
+        debug_log("Waiting for server threads to finish".to_string());
+        for thread in threads.drain(..) {
+            thread
+                .join()
+                .map_err(|_| "A node thread panicked".to_string())?;
+        }
+
+        let state_store = match &self.state_store {
+            Some(state_store) => state_store,
+            None => return Ok(()),
+        };
+
+        debug_log(format!("Saving node state to {}", state_store.path()));
+        self.value_store.lock().unwrap().sync()?;
+        state_store.save_peers(self.local_node_id, &self.peer_manager, &self.value_store)
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if let Err(error) = self.shutdown() {
+            error_log(format!("Failed to shut down node: {}", error));
+        }
+    }
+}
+
+/// Contacts the bootstrap peers, then looks up our own ID to fill the routing table with the
+/// nodes closest to us. While none of the bootstrap peers respond, and nobody else has contacted
+/// us either, contacting them is retried with the delay doubling each time.
+#[allow(clippy::too_many_arguments)]
+fn bootstrap(
+    is_running: Arc<AtomicBool>,
+    shutdown: Arc<Shutdown>,
+    parameters: LookupParameters,
+    local_node_id: NodeId,
+    bootstrap_peers: Vec<SocketAddr>,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    pending_requests: Arc<PendingRequests>,
+    transport: Arc<dyn Transport>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut retry_delay = BOOTSTRAP_RETRY_DELAY;
+
+        loop {
+            let mut reachable_count = 0;
+
+            for bootstrap_peer in &bootstrap_peers {
+                match ping_peer(
+                    &local_node_id,
+                    bootstrap_peer,
+                    peer_manager.clone(),
+                    pending_requests.clone(),
+                    transport.clone(),
+                ) {
+                    Ok(_) => reachable_count += 1,
+                    Err(error) => error_log(format!(
+                        "Failed to contact bootstrap peer {}: {}",
+                        bootstrap_peer, error
+                    )),
+                }
+            }
+
+            if bootstrap_peers.is_empty()
+                || reachable_count > 0
+                || !peer_manager.lock().unwrap().to_vec().is_empty()
+            {
+                break;
+            }
+
+            error_log(format!(
+                "None of the {} bootstrap peers responded, retrying in {} seconds",
+                bootstrap_peers.len(),
+                retry_delay.as_secs()
+            ));
+
+            let clock = pending_requests.clock();
+
+            if !clock.wait_until(clock.now() + retry_delay, &shutdown) {
+                return;
+            }
+
+            retry_delay = (retry_delay * 2).min(MAX_BOOTSTRAP_RETRY_DELAY);
+        }
+
+        match find_node(
+            is_running,
+            parameters,
+            &local_node_id,
+            &local_node_id,
+            peer_manager,
+            pending_requests,
+            transport,
+        ) {
+            Ok(nodes) => debug_log(format!("Finished finding {} nearby peers", nodes.len())),
+            Err(error) => error_log(format!("Failed to find nearby peers: {}", error)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::transport::MemoryNetwork;
+
+    fn memory_node(network: &MemoryNetwork, port: u16) -> Node {
+        let transport = network
+            .bind(&SocketAddr::from(([10, 0, 0, 1], port)))
+            .unwrap();
+
+        Node::builder()
+            .transport(Arc::new(transport))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_parameters() {
+        assert!(Node::builder().bucket_size(0).build().is_err());
+        assert!(Node::builder().alpha(0).build().is_err());
+        assert!(Node::builder().request_attempts(0).build().is_err());
+        assert!(Node::builder()
+            .request_timeouts(Timeouts {
+                initial: Duration::from_secs(1),
+                max: Duration::from_millis(500),
+                min: Duration::from_millis(100),
+            })
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_nodes_ping_put_and_get() {
+        let network = MemoryNetwork::new();
+        let first = memory_node(&network, 16600);
+        let second = memory_node(&network, 16601);
+        let key = Key::random();
+
+        assert_eq!(
+            second.ping(&first.local_address()).unwrap(),
+            first.node_id()
+        );
+        assert_eq!(second.peers()[0].node_id, first.node_id());
+
+        assert_eq!(second.put(&key, b"value".to_vec(), 60).unwrap(), 1);
+        assert_eq!(first.get(&key).unwrap().data, b"value");
+
+        first.shutdown().unwrap();
+        second.shutdown().unwrap();
+        // Shutting down twice is harmless
+        second.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_state_is_snapshotted_while_running() {
+        let directory = std::env::temp_dir().join(format!("snapshot-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let state_file = directory.join("state.bin");
+        let state_file = state_file.to_str().unwrap();
+
+        let network = MemoryNetwork::new();
+        let peer = memory_node(&network, 16600);
+        let transport = network
+            .bind(&SocketAddr::from(([10, 0, 0, 1], 16601)))
+            .unwrap();
+
+        let node = Node::builder()
+            .transport(Arc::new(transport))
+            .state_file(state_file)
+            .snapshot_interval(Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        node.ping(&peer.local_address()).unwrap();
+
+        let mut saved_peers = 0;
+        for _ in 0..100 {
+            let node_state: structures::NodeState =
+                crate::node_state::read_snapshot(state_file).unwrap();
+            saved_peers = node_state.buckets.iter().map(|bucket| bucket.len()).sum();
+
+            if saved_peers > 0 {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        assert_eq!(saved_peers, 1);
+    }
+
+    #[test]
+    fn test_maintenance_runs_as_the_clock_advances() {
+        let network = MemoryNetwork::new();
+        let clock = Arc::new(ManualClock::new(1_700_000_000));
+        let transport = network
+            .bind(&SocketAddr::from(([10, 0, 0, 1], 16601)))
+            .unwrap();
+
+        let node = Node::builder()
+            .transport(Arc::new(transport))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+
+        assert_eq!(node.put(&Key::random(), b"value".to_vec(), 60).unwrap(), 0);
+
+        assert_eq!(node.stats().value_count, 1);
+
+        // Maintenance purges the expired value once the clock reaches its next run
+        for _ in 0..100 {
+            clock.advance(Duration::from_secs(60));
+            thread::sleep(Duration::from_millis(10));
+
+            if node.stats().value_count == 0 {
+                break;
+            }
+        }
+
+        assert_eq!(node.stats().value_count, 0);
+    }
+
+    #[test]
+    fn test_bootstrap_retries_until_a_peer_is_reachable() {
+        let network = MemoryNetwork::new();
+        let clock = Arc::new(ManualClock::new(1_700_000_000));
+        let transport = network
+            .bind(&SocketAddr::from(([10, 0, 0, 1], 16601)))
+            .unwrap();
+
+        let node = Node::builder()
+            .transport(Arc::new(transport))
+            .clock(clock.clone())
+            .bootstrap_peers(vec![SocketAddr::from(([10, 0, 0, 1], 16600))])
+            .build()
+            .unwrap();
+
+        // Nothing is listening on the bootstrap address yet, so the first attempts time out
+        for _ in 0..20 {
+            clock.advance(Duration::from_secs(1));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(node.peers().is_empty());
+
+        let bootstrap_node = memory_node(&network, 16600);
+
+        for _ in 0..1000 {
+            if !node.peers().is_empty() {
+                break;
+            }
+
+            clock.advance(Duration::from_secs(1));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(node.peers()[0].node_id, bootstrap_node.node_id());
+    }
+}
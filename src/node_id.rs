@@ -0,0 +1,257 @@
+use rand::{thread_rng, Rng};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::Cell;
+use std::fmt;
+use std::ops::BitXor;
+use std::str::FromStr;
+
+use crate::distance::Distance;
+
+pub const ID_BYTES: usize = 20;
+
+/// A 160-bit identifier for a node. IDs are written as their 20 raw bytes on the wire and in the
+/// state file, and as 40 character hex strings in human readable formats such as JSON, where
+/// anything else is rejected while deserializing.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId([u8; ID_BYTES]);
+
+/// Values share the node ID space so they can be stored on the nodes closest to their key.
+pub type Key = NodeId;
+
+pub type TransactionId = NodeId;
+
+thread_local! {
+    static HEX_IDS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` with IDs written and read as hex strings in binary formats too, as they were before
+/// version 3 of the state file and version 2 of the values log.
+pub fn with_hex_ids<T>(f: impl FnOnce() -> T) -> T {
+    let previous = HEX_IDS.replace(true);
+    let result = f();
+    HEX_IDS.set(previous);
+
+    result
+}
+
+impl NodeId {
+    pub fn random() -> Self {
+        Self::random_from(&mut thread_rng())
+    }
+
+    /// Generates a random ID from the given generator, so a seeded generator gives repeatable IDs.
+    pub fn random_from<R: Rng>(rng: &mut R) -> Self {
+        let mut bytes: [u8; ID_BYTES] = [0; ID_BYTES];
+        rng.fill(&mut bytes[..]);
+
+        Self(bytes)
+    }
+
+    /// Generates a random ID whose distance from this one falls in the given routing table bucket,
+    /// by keeping the first `bucket_index` bits, flipping the next and randomising the rest.
+    pub fn random_in_bucket(&self, bucket_index: usize) -> Self {
+        let mut bytes = NodeId::random().0;
+
+        for bit in 0..=bucket_index.min(ID_BYTES * 8 - 1) {
+            let index = bit / 8;
+            let mask = 0x80 >> (bit % 8);
+            let local_bit = self.0[index] & mask;
+
+            let bit_value = if bit == bucket_index {
+                local_bit ^ mask
+            } else {
+                local_bit
+            };
+
+            bytes[index] = (bytes[index] & !mask) | bit_value;
+        }
+
+        Self(bytes)
+    }
+
+    pub fn distance(&self, other: &NodeId) -> Distance {
+        Distance::between(&self.0, &other.0)
+    }
+}
+
+impl BitXor for NodeId {
+    type Output = Distance;
+
+    fn bitxor(self, other: NodeId) -> Distance {
+        self.distance(&other)
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.len() != ID_BYTES * 2 {
+            return Err(format!(
+                "Invalid ID \"{}\", expected {} hex characters.",
+                value,
+                ID_BYTES * 2
+            ));
+        }
+
+        if !value.chars().all(|character| character.is_ascii_hexdigit()) {
+            return Err(format!(
+                "Invalid ID \"{}\", expected hex characters.",
+                value
+            ));
+        }
+
+        let mut bytes: [u8; ID_BYTES] = [0; ID_BYTES];
+
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&value[index * 2..index * 2 + 2], 16)
+                .map_err(|error| format!("Invalid ID \"{}\", {}.", value, error))?;
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+impl Serialize for NodeId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() || HEX_IDS.get() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NodeIdVisitor;
+
+        impl Visitor<'_> for NodeIdVisitor {
+            type Value = NodeId;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a 40 character hex string")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<NodeId, E> {
+                value.parse().map_err(E::custom)
+            }
+        }
+
+        if deserializer.is_human_readable() || HEX_IDS.get() {
+            deserializer.deserialize_str(NodeIdVisitor)
+        } else {
+            <[u8; ID_BYTES]>::deserialize(deserializer).map(Self)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_id_round_trips_through_string() {
+        let node_id: NodeId = "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3".parse().unwrap();
+
+        assert_eq!(
+            node_id.to_string(),
+            "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3"
+        );
+        assert_eq!(node_id.0[0], 0xa9);
+        assert_eq!(node_id.0[19], 0xd3);
+    }
+
+    #[test]
+    fn test_node_id_rejects_malformed_strings() {
+        let invalid = [
+            "",
+            "a94a8fe5",
+            "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3ff",
+            "z94a8fe5ccb19ba61c4c0873d391e987982fbbd3",
+            "+94a8fe5ccb19ba61c4c0873d391e987982fbbd3",
+            "é4a8fe5ccb19ba61c4c0873d391e987982fbbd3",
+        ];
+
+        for value in invalid {
+            assert!(value.parse::<NodeId>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_node_id_deserialize_rejects_malformed_ids() {
+        let valid = "\"a94a8fe5ccb19ba61c4c0873d391e987982fbbd3\"";
+        let short = "\"a94a8fe5\"";
+        let non_hex = "\"zz4a8fe5ccb19ba61c4c0873d391e987982fbbd3\"";
+
+        assert!(serde_json::from_str::<NodeId>(valid).is_ok());
+        assert!(serde_json::from_str::<NodeId>(short).is_err());
+        assert!(serde_json::from_str::<NodeId>(non_hex).is_err());
+    }
+
+    #[test]
+    fn test_node_id_is_raw_bytes_in_binary_formats() {
+        let node_id: NodeId = "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3".parse().unwrap();
+
+        let encoded = bincode::serialize(&node_id).unwrap();
+        assert_eq!(encoded, node_id.0);
+        assert_eq!(bincode::deserialize::<NodeId>(&encoded).unwrap(), node_id);
+
+        let hex_encoded = with_hex_ids(|| bincode::serialize(&node_id).unwrap());
+        assert_eq!(
+            hex_encoded,
+            bincode::serialize(&node_id.to_string()).unwrap()
+        );
+        assert_eq!(
+            with_hex_ids(|| bincode::deserialize::<NodeId>(&hex_encoded)).unwrap(),
+            node_id
+        );
+
+        assert_eq!(
+            serde_json::to_string(&node_id).unwrap(),
+            "\"a94a8fe5ccb19ba61c4c0873d391e987982fbbd3\""
+        );
+    }
+
+    #[test]
+    fn test_node_id_random_in_bucket() {
+        let local: NodeId = "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3".parse().unwrap();
+
+        for bucket_index in [0, 1, 7, 8, 63, 100, 158, 159] {
+            for _ in 0..10 {
+                let node_id = local.random_in_bucket(bucket_index);
+
+                assert_eq!((local ^ node_id).bucket_index(), Some(bucket_index));
+            }
+        }
+    }
+
+    #[test]
+    fn test_node_id_xor() {
+        let a: NodeId = "ff00000000000000000000000000000000000001".parse().unwrap();
+        let b: NodeId = "0f00000000000000000000000000000000000001".parse().unwrap();
+
+        assert_eq!(
+            (a ^ b).to_string(),
+            "f000000000000000000000000000000000000000"
+        );
+        assert_eq!(a ^ b, b ^ a);
+    }
+}
@@ -1,40 +1,830 @@
+use crate::clock::Clock;
 use crate::structures;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
-use std::fs::File;
-
-use crate::utilities::lock_file;
-use crate::utilities::random_sha1_to_string;
-
-pub fn load_node_state(path: &str) -> Result<(structures::NodeState, File), String> {
-    if !std::path::Path::new(path).exists() {
-        let node_state = structures::NodeState {
-            buckets: vec![
-                VecDeque::with_capacity(crate::peers::BUCKET_SIZE);
-                crate::peers::ID_BITS
-            ],
-            node_id: random_sha1_to_string(),
-            values: HashMap::new(),
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::node_id::{with_hex_ids, Key, NodeId};
+use crate::peers::{PeerManager, ID_BITS};
+use crate::shutdown::Shutdown;
+use crate::storage::{LogStorage, MemoryStorage, Storage};
+use crate::utilities::{lock_file, sync_parent_directory, unix_timestamp};
+use crate::values::{ValueStore, DEFAULT_TTL};
+use crate::{debug_log, error_log};
+
+/// Marks a file as a node state file.
+const STATE_MAGIC: &[u8; 4] = b"KDHT";
+/// The format version written by `save_snapshot`. Older versions are upgraded on load by
+/// `migrate`, one version at a time:
+///
+/// 1. No header, with bare values as in `NodeStateV1`
+/// 2. A header, with values that carry their publisher and TTL
+/// 3. IDs as raw bytes instead of hex strings
+const STATE_VERSION: u32 = 3;
+
+const IDENTITY_FILE: &str = "identity";
+const LOCK_FILE: &str = "lock";
+const PEERS_FILE: &str = "peers.bin";
+/// Values snapshot written by data directories before they kept a values log.
+const VALUES_FILE: &str = "values.bin";
+const VALUES_LOG_FILE: &str = "values.log";
+/// How often stored values are synced to disk. Values are not synced as each one is stored, so
+/// a crash loses at most the values stored within this long.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where a node keeps its state between runs.
+#[derive(Clone, PartialEq, Debug)]
+pub enum StatePath {
+    /// The node ID, routing table and values together in one file.
+    File(String),
+    /// A directory with the node ID and routing table in separate files, and the values in a log
+    /// that each value is written to as it is stored.
+    DataDir(String),
+}
+
+impl StatePath {
+    /// Whether a node has saved its state here before.
+    pub fn exists(&self) -> bool {
+        match self {
+            StatePath::File(path) => {
+                Path::new(path).exists() || Path::new(&backup_path(path)).exists()
+            }
+            StatePath::DataDir(directory) => Path::new(directory).join(IDENTITY_FILE).exists(),
+        }
+    }
+}
+
+impl std::fmt::Display for StatePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatePath::File(path) | StatePath::DataDir(path) => write!(f, "{}", path),
+        }
+    }
+}
+
+/// A node's state as loaded, with its values left in their storage.
+pub struct LoadedState {
+    pub buckets: Vec<VecDeque<structures::Peer>>,
+    pub node_id: NodeId,
+    pub storage: Box<dyn Storage>,
+}
+
+impl LoadedState {
+    /// Reads every value into memory, for exporting the state.
+    pub fn into_node_state(self) -> Result<structures::NodeState, String> {
+        let mut values = HashMap::new();
+
+        for (key, _) in self.storage.entries() {
+            if let Some(stored_value) = self.storage.get(&key)? {
+                values.insert(key, stored_value);
+            }
+        }
+
+        Ok(structures::NodeState {
+            buckets: self.buckets,
+            node_id: self.node_id,
+            values,
+        })
+    }
+}
+
+impl From<structures::NodeState> for LoadedState {
+    fn from(node_state: structures::NodeState) -> Self {
+        Self {
+            buckets: node_state.buckets,
+            node_id: node_state.node_id,
+            storage: Box::new(MemoryStorage::new(node_state.values)),
+        }
+    }
+}
+
+/// Reads and writes a node's state. The state stays locked while the store is open, so two nodes
+/// never share it.
+pub struct StateStore {
+    _lock: File,
+    path: StatePath,
+}
+
+impl StateStore {
+    pub fn open(path: &StatePath) -> Result<Self, String> {
+        let lock_path = match path {
+            // Snapshots replace the state file, so the lock is held on a file of its own
+            StatePath::File(file) => format!("{}.lock", file),
+            StatePath::DataDir(directory) => {
+                fs::create_dir_all(directory).map_err(|error| {
+                    format!(
+                        "Failed to create data directory \"{}\": {}",
+                        directory, error
+                    )
+                })?;
+
+                data_file(directory, LOCK_FILE)
+            }
         };
 
-        save_node_state(path, &node_state)?;
+        Ok(Self {
+            _lock: lock_file(&lock_path)?,
+            path: path.clone(),
+        })
+    }
+
+    pub fn path(&self) -> &StatePath {
+        &self.path
+    }
+
+    /// Loads the state, or starts a fresh one if there is none yet. Corrupt snapshots fall back
+    /// to the previous ones, and snapshots that cannot be read at all are set aside.
+    pub fn load(&self) -> Result<LoadedState, String> {
+        match &self.path {
+            StatePath::File(path) => match recover_snapshot::<structures::NodeState>(path)? {
+                Some(node_state) => Ok(LoadedState::from(node_state)),
+                None => {
+                    let node_state = new_node_state();
+                    save_snapshot(path, &node_state)?;
+
+                    Ok(LoadedState::from(node_state))
+                }
+            },
+            StatePath::DataDir(directory) => {
+                let identity_path = data_file(directory, IDENTITY_FILE);
+
+                let node_id = if Path::new(&identity_path).exists() {
+                    read_identity(&identity_path)?
+                } else {
+                    let node_id = NodeId::random();
+                    write_atomically(&identity_path, format!("{}\n", node_id).as_bytes())?;
+
+                    node_id
+                };
+
+                let buckets = recover_snapshot(&data_file(directory, PEERS_FILE))?
+                    .unwrap_or_else(|| vec![VecDeque::new(); ID_BITS]);
+
+                Ok(LoadedState {
+                    buckets,
+                    node_id,
+                    storage: Box::new(open_values_log(directory)?),
+                })
+            }
+        }
+    }
+
+    pub fn save(&self, node_state: &structures::NodeState) -> Result<(), String> {
+        match &self.path {
+            StatePath::File(path) => save_snapshot(path, node_state),
+            StatePath::DataDir(directory) => {
+                let identity_path = data_file(directory, IDENTITY_FILE);
+
+                // The identity only changes when a state with another node ID is imported
+                if read_identity(&identity_path).ok() != Some(node_state.node_id) {
+                    write_atomically(
+                        &identity_path,
+                        format!("{}\n", node_state.node_id).as_bytes(),
+                    )?;
+                }
+
+                save_snapshot(&data_file(directory, PEERS_FILE), &node_state.buckets)?;
+
+                let mut values_log = open_values_log(directory)?;
+
+                for (key, _) in values_log.entries() {
+                    values_log.remove(&key)?;
+                }
+
+                for (key, stored_value) in &node_state.values {
+                    values_log.put(key, stored_value)?;
+                }
+
+                values_log.sync()?;
+                values_log.compact().map(|_| ())
+            }
+        }
+    }
+
+    /// Saves the routing table. A single state file also holds the values, so they are saved
+    /// along with it, while a data directory's values are already in its log.
+    pub fn save_peers(
+        &self,
+        local_node_id: NodeId,
+        peer_manager: &Mutex<PeerManager>,
+        value_store: &Mutex<ValueStore>,
+    ) -> Result<(), String> {
+        match &self.path {
+            StatePath::File(path) => {
+                save_snapshot(path, &snapshot(local_node_id, peer_manager, value_store)?)
+            }
+            StatePath::DataDir(directory) => save_snapshot(
+                &data_file(directory, PEERS_FILE),
+                &peer_manager.lock().unwrap().buckets(),
+            ),
+        }
+    }
+}
+
+/// A fresh state with a random node ID and no peers or values.
+pub fn new_node_state() -> structures::NodeState {
+    structures::NodeState {
+        buckets: vec![VecDeque::new(); ID_BITS],
+        node_id: NodeId::random(),
+        values: HashMap::new(),
+    }
+}
+
+/// Takes a copy of the node's current state.
+pub fn snapshot(
+    local_node_id: NodeId,
+    peer_manager: &Mutex<PeerManager>,
+    value_store: &Mutex<ValueStore>,
+) -> Result<structures::NodeState, String> {
+    Ok(structures::NodeState {
+        buckets: peer_manager.lock().unwrap().buckets(),
+        node_id: local_node_id,
+        values: value_store.lock().unwrap().values()?,
+    })
+}
+
+/// Saves the routing table every `peers_interval` while the node runs, so a crash loses at most
+/// the peers learned since the last snapshot, syncs the values every `SYNC_INTERVAL`, and checks
+/// whether the values need compacting every `compaction_interval`.
+#[allow(clippy::too_many_arguments)]
+pub fn start_snapshots(
+    shutdown: Arc<Shutdown>,
+    clock: Arc<dyn Clock>,
+    state_store: Arc<StateStore>,
+    peers_interval: Duration,
+    compaction_interval: Duration,
+    local_node_id: NodeId,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    value_store: Arc<Mutex<ValueStore>>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut next_peers_run = clock.now() + peers_interval;
+        let mut next_sync_run = clock.now() + SYNC_INTERVAL;
+        let mut next_compaction_run = clock.now() + compaction_interval;
+
+        while clock.wait_until(
+            next_peers_run.min(next_sync_run).min(next_compaction_run),
+            &shutdown,
+        ) {
+            let now = clock.now();
+
+            if now >= next_sync_run {
+                if let Err(error) = value_store.lock().unwrap().sync() {
+                    error_log(format!("Failed to sync the values: {}", error));
+                }
+
+                next_sync_run = clock.now() + SYNC_INTERVAL;
+            }
+
+            if now >= next_peers_run {
+                match state_store.save_peers(local_node_id, &peer_manager, &value_store) {
+                    Ok(()) => debug_log(format!(
+                        "Saved a snapshot of the node state to {}",
+                        state_store.path
+                    )),
+                    Err(error) => {
+                        error_log(format!("Failed to save a snapshot of the peers: {}", error))
+                    }
+                }
+
+                next_peers_run = clock.now() + peers_interval;
+            }
+
+            if now >= next_compaction_run {
+                if let Err(error) = compact_values(&value_store) {
+                    error_log(format!("Failed to compact the values: {}", error));
+                }
+
+                next_compaction_run = clock.now() + compaction_interval;
+            }
+        }
+    })
+}
+
+/// Compacts the values, holding the lock only while the compaction is planned and finished, so
+/// values can be stored and looked up while the live ones are copied.
+fn compact_values(value_store: &Mutex<ValueStore>) -> Result<(), String> {
+    let compaction = match value_store.lock().unwrap().start_compaction()? {
+        Some(compaction) => compaction,
+        None => return Ok(()),
+    };
+
+    let compacted_log = compaction.copy()?;
+
+    value_store.lock().unwrap().finish_compaction(compacted_log)
+}
+
+/// Reads a snapshot, falling back to the previous one when it is missing or corrupt. If neither
+/// can be read they are set aside, and `None` is returned as if there were no snapshot.
+fn recover_snapshot<T: Serialize + DeserializeOwned>(path: &str) -> Result<Option<T>, String> {
+    let backup_path = backup_path(path);
+
+    if !Path::new(path).exists() && !Path::new(&backup_path).exists() {
+        return Ok(None);
+    }
+
+    let error = match read_snapshot(path) {
+        Ok(value) => return Ok(Some(value)),
+        Err(error) => error,
+    };
+
+    match read_snapshot(&backup_path) {
+        Ok(value) => {
+            error_log(format!(
+                "{}, recovered the previous snapshot from {}",
+                error, backup_path
+            ));
+
+            Ok(Some(value))
+        }
+        Err(backup_error) => {
+            error_log(format!(
+                "{}, and the previous snapshot could not be used either: {}",
+                error, backup_error
+            ));
+
+            set_aside_unreadable(&[path, &backup_path])?;
+
+            Ok(None)
+        }
+    }
+}
+
+/// Reads a snapshot, upgrading it if it was written in an older format. A copy of an older file
+/// is kept next to it, since the upgraded snapshot is saved in the current format.
+pub fn read_snapshot<T: Serialize + DeserializeOwned>(path: &str) -> Result<T, String> {
+    let contents = fs::read(path)
+        .map_err(|error| format!("Failed to read state file \"{}\": {}", path, error))?;
+
+    let (version, payload) = match contents.strip_prefix(STATE_MAGIC) {
+        Some(rest) if rest.len() >= 4 => {
+            let version = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+            (version, &rest[4..])
+        }
+        Some(_) => return Err(format!("State file \"{}\" is truncated", path)),
+        // Files written before the header was added
+        None => (1, &contents[..]),
+    };
+
+    if version == 0 || version > STATE_VERSION {
+        return Err(format!(
+            "State file \"{}\" has unsupported format version {}, expected at most {}",
+            path, version, STATE_VERSION
+        ));
+    }
+
+    let payload = migrate::<T>(version, payload.to_vec())
+        .map_err(|error| format!("Failed to upgrade state file \"{}\": {}", path, error))?;
+
+    let value = bincode::deserialize(&payload)
+        .map_err(|error| format!("Failed to deserialize state file \"{}\": {}", path, error))?;
+
+    if version < STATE_VERSION {
+        let copy_path = format!("{}.v{}", path, version);
+
+        fs::copy(path, &copy_path)
+            .map_err(|error| format!("Failed to keep a copy of \"{}\": {}", path, error))?;
+
+        debug_log(format!(
+            "Upgraded state file {} from format version {} to {}, the original is kept as {}",
+            path, version, STATE_VERSION, copy_path
+        ));
+    }
+
+    Ok(value)
+}
+
+/// The state as written before values carried their publisher and TTL.
+#[derive(Serialize, Deserialize)]
+struct NodeStateV1 {
+    buckets: Vec<VecDeque<structures::Peer>>,
+    node_id: NodeId,
+    values: HashMap<String, Vec<u8>>,
+}
+
+/// Upgrades a payload of the given version to the current version.
+fn migrate<T: Serialize + DeserializeOwned>(
+    mut version: u32,
+    mut payload: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    while version < STATE_VERSION {
+        payload = match version {
+            1 => with_hex_ids(|| migrate_v1(payload))?,
+            2 => migrate_v2::<T>(&payload)?,
+            _ => return Err(format!("No migration from format version {}", version)),
+        };
+
+        version += 1;
+    }
+
+    Ok(payload)
+}
+
+/// Gives version 1 values a publisher and TTL, as if the node had just published them itself.
+fn migrate_v1(payload: Vec<u8>) -> Result<Vec<u8>, String> {
+    // Files saved without a header after values gained publishers already have the version 2
+    // layout, and never read as version 1 unless they hold no values
+    let node_state: NodeStateV1 = match bincode::deserialize(&payload) {
+        Ok(node_state) => node_state,
+        Err(_) => return Ok(payload),
+    };
+
+    let now = unix_timestamp();
+    let mut values = HashMap::new();
 
-        return Ok((node_state, lock_file(path)?));
+    for (key, data) in node_state.values {
+        let key: Key = match key.parse() {
+            Ok(key) => key,
+            Err(error) => {
+                error_log(format!("Dropped a value with an invalid key: {}", error));
+                continue;
+            }
+        };
+
+        values.insert(
+            key,
+            structures::StoredValue {
+                stored_at: now,
+                value: structures::Value {
+                    data,
+                    published_at: now,
+                    publisher: node_state.node_id,
+                    ttl: DEFAULT_TTL,
+                },
+            },
+        );
     }
 
-    let contents =
-        std::fs::read(path).map_err(|error| format!("Failed to read state file: {}", error))?;
+    bincode::serialize(&structures::NodeState {
+        buckets: node_state.buckets,
+        node_id: node_state.node_id,
+        values,
+    })
+    .map_err(|error| format!("Failed to serialize state: {}", error))
+}
 
-    let node_state: structures::NodeState = bincode::deserialize(&contents)
+/// Rewrites the IDs in a version 2 payload, which are hex strings, as raw bytes.
+fn migrate_v2<T: Serialize + DeserializeOwned>(payload: &[u8]) -> Result<Vec<u8>, String> {
+    let value: T = with_hex_ids(|| bincode::deserialize(payload))
         .map_err(|error| format!("Failed to deserialize state: {}", error))?;
 
-    Ok((node_state, lock_file(path)?))
+    bincode::serialize(&value).map_err(|error| format!("Failed to serialize state: {}", error))
 }
 
-pub fn save_node_state(path: &str, state: &structures::NodeState) -> Result<(), String> {
-    let contents = bincode::serialize(state)
-        .map_err(|error| format!("Failed to serialize state: {}", error))?;
+/// Renames unreadable state files so they are kept for inspection, but not loaded again.
+fn set_aside_unreadable(paths: &[&str]) -> Result<(), String> {
+    let timestamp = unix_timestamp();
+
+    for path in paths {
+        if !Path::new(path).exists() {
+            continue;
+        }
+
+        let unreadable_path = format!("{}.unreadable-{}", path, timestamp);
 
-    std::fs::write(path, contents).map_err(|error| format!("Failed to write state: {}", error))?;
+        fs::rename(path, &unreadable_path).map_err(|error| {
+            format!(
+                "Failed to move unreadable state file \"{}\": {}",
+                path, error
+            )
+        })?;
+
+        error_log(format!(
+            "Kept unreadable state file {} as {}, starting with a fresh state",
+            path, unreadable_path
+        ));
+    }
 
     Ok(())
 }
+
+/// Writes a snapshot in the current format, with a header marking its version.
+pub fn save_snapshot<T: Serialize>(path: &str, value: &T) -> Result<(), String> {
+    let mut contents = STATE_MAGIC.to_vec();
+    contents.extend_from_slice(&STATE_VERSION.to_le_bytes());
+
+    bincode::serialize_into(&mut contents, value)
+        .map_err(|error| format!("Failed to serialize state: {}", error))?;
+
+    write_atomically(path, &contents)
+}
+
+/// Writes to a temporary file and renames it over the file, so a crash never leaves a partly
+/// written file behind. The contents it replaces are kept as a backup.
+fn write_atomically(path: &str, contents: &[u8]) -> Result<(), String> {
+    let temporary_path = format!("{}.tmp", path);
+
+    File::create(&temporary_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .map_err(|error| format!("Failed to write state to \"{}\": {}", temporary_path, error))?;
+
+    // The file is linked rather than moved to the backup, so it is never missing
+    if Path::new(path).exists() {
+        let backup_path = backup_path(path);
+
+        if Path::new(&backup_path).exists() {
+            fs::remove_file(&backup_path).map_err(|error| {
+                format!("Failed to remove old backup \"{}\": {}", backup_path, error)
+            })?;
+        }
+
+        fs::hard_link(path, &backup_path)
+            .map_err(|error| format!("Failed to back up state file \"{}\": {}", path, error))?;
+    }
+
+    fs::rename(&temporary_path, path)
+        .map_err(|error| format!("Failed to replace state file \"{}\": {}", path, error))?;
+
+    // The rename is only durable once the directory itself is synced
+    sync_parent_directory(path)
+}
+
+/// Opens a data directory's values log, moving in the values from a snapshot left by an older
+/// version.
+fn open_values_log(directory: &str) -> Result<LogStorage, String> {
+    let mut values_log = LogStorage::open(&data_file(directory, VALUES_LOG_FILE))?;
+
+    let values_path = data_file(directory, VALUES_FILE);
+    let values: Option<HashMap<Key, structures::StoredValue>> = recover_snapshot(&values_path)?;
+
+    if let Some(values) = values {
+        for (key, stored_value) in &values {
+            values_log.put(key, stored_value)?;
+        }
+
+        // The snapshot is removed next, so the values have to be in the log for good first
+        values_log.sync()?;
+
+        debug_log(format!(
+            "Moved {} values from {} into the values log",
+            values.len(),
+            values_path
+        ));
+    }
+
+    for path in [values_path.clone(), backup_path(&values_path)] {
+        if Path::new(&path).exists() {
+            fs::remove_file(&path)
+                .map_err(|error| format!("Failed to remove \"{}\": {}", path, error))?;
+        }
+    }
+
+    Ok(values_log)
+}
+
+fn read_identity(path: &str) -> Result<NodeId, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|error| format!("Failed to read identity file \"{}\": {}", path, error))?;
+
+    contents
+        .trim()
+        .parse()
+        .map_err(|error| format!("Invalid identity file \"{}\": {}", path, error))
+}
+
+fn backup_path(path: &str) -> String {
+    format!("{}.bak", path)
+}
+
+fn data_file(directory: &str, name: &str) -> String {
+    Path::new(directory)
+        .join(name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn test_directory(name: &str) -> String {
+        let directory = env::temp_dir().join(format!("node-state-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        directory.to_str().unwrap().to_string()
+    }
+
+    fn state_path(name: &str) -> String {
+        data_file(&test_directory(name), "state.bin")
+    }
+
+    fn load(path: &str) -> Result<structures::NodeState, String> {
+        StateStore::open(&StatePath::File(path.to_string()))?
+            .load()?
+            .into_node_state()
+    }
+
+    #[test]
+    fn test_load_falls_back_to_previous_snapshot_when_corrupt() {
+        let path = state_path("corrupt");
+        let first = new_node_state();
+        let second = new_node_state();
+
+        save_snapshot(&path, &first).unwrap();
+        save_snapshot(&path, &second).unwrap();
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        assert_eq!(
+            read_snapshot::<structures::NodeState>(&path).unwrap(),
+            second
+        );
+
+        // A state file cut short, as a crash during a plain write would leave it
+        let contents = fs::read(&path).unwrap();
+        fs::write(&path, &contents[..contents.len() / 2]).unwrap();
+
+        assert_eq!(load(&path).unwrap(), first);
+    }
+
+    #[test]
+    fn test_load_upgrades_state_without_header_and_keeps_original() {
+        let path = state_path("legacy");
+        let node_state = new_node_state();
+        let legacy_contents = with_hex_ids(|| bincode::serialize(&node_state)).unwrap();
+
+        fs::write(&path, &legacy_contents).unwrap();
+
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded, node_state);
+        assert_eq!(fs::read(format!("{}.v1", path)).unwrap(), legacy_contents);
+
+        save_snapshot(&path, &loaded).unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(STATE_MAGIC));
+        assert_eq!(
+            read_snapshot::<structures::NodeState>(&path).unwrap(),
+            node_state
+        );
+    }
+
+    #[test]
+    fn test_load_gives_version_1_values_a_publisher_and_ttl() {
+        let path = state_path("version-1-values");
+        let key = Key::random();
+        let legacy_state = NodeStateV1 {
+            buckets: vec![VecDeque::new(); ID_BITS],
+            node_id: NodeId::random(),
+            values: HashMap::from([(key.to_string(), b"value".to_vec())]),
+        };
+
+        fs::write(
+            &path,
+            with_hex_ids(|| bincode::serialize(&legacy_state)).unwrap(),
+        )
+        .unwrap();
+
+        let loaded = load(&path).unwrap();
+        let stored_value = &loaded.values[&key];
+        assert_eq!(loaded.node_id, legacy_state.node_id);
+        assert_eq!(stored_value.value.data, b"value");
+        assert_eq!(stored_value.value.publisher, legacy_state.node_id);
+        assert_eq!(stored_value.value.published_at, stored_value.stored_at);
+        assert_eq!(stored_value.value.ttl, DEFAULT_TTL);
+
+        // Headerless files with the version 2 layout load as they are
+        let mut node_state = new_node_state();
+        node_state.values.insert(key, stored_value.clone());
+        fs::write(
+            &path,
+            with_hex_ids(|| bincode::serialize(&node_state)).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(load(&path).unwrap(), node_state);
+    }
+
+    #[test]
+    fn test_load_rewrites_version_2_ids_as_raw_bytes() {
+        let path = state_path("version-2");
+        let mut node_state = new_node_state();
+        node_state.values.insert(
+            Key::random(),
+            structures::StoredValue {
+                stored_at: 1_700_000_000,
+                value: structures::Value {
+                    data: b"value".to_vec(),
+                    published_at: 1_700_000_000,
+                    publisher: NodeId::random(),
+                    ttl: 60,
+                },
+            },
+        );
+
+        let mut version_2_contents = STATE_MAGIC.to_vec();
+        version_2_contents.extend_from_slice(&2u32.to_le_bytes());
+        version_2_contents.extend(with_hex_ids(|| bincode::serialize(&node_state)).unwrap());
+        fs::write(&path, &version_2_contents).unwrap();
+
+        assert_eq!(
+            read_snapshot::<structures::NodeState>(&path).unwrap(),
+            node_state
+        );
+        assert_eq!(
+            fs::read(format!("{}.v2", path)).unwrap(),
+            version_2_contents
+        );
+
+        save_snapshot(&path, &node_state).unwrap();
+        assert!(fs::read(&path).unwrap().len() < version_2_contents.len());
+    }
+
+    #[test]
+    fn test_load_sets_aside_unreadable_state_and_starts_fresh() {
+        let path = state_path("unreadable");
+        let mut newer_version = STATE_MAGIC.to_vec();
+        newer_version.extend_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+
+        fs::write(&path, &newer_version).unwrap();
+
+        let node_state = load(&path).unwrap();
+        assert_eq!(
+            read_snapshot::<structures::NodeState>(&path).unwrap(),
+            node_state
+        );
+
+        let directory = Path::new(&path).parent().unwrap();
+        let kept: Vec<Vec<u8>> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_str().unwrap().contains(".unreadable-"))
+            .map(|path| fs::read(path).unwrap())
+            .collect();
+        assert_eq!(kept, vec![newer_version]);
+    }
+
+    #[test]
+    fn test_data_dir_keeps_identity_peers_and_values_apart() {
+        let directory = data_file(&test_directory("data-dir"), "node");
+        let state_path = StatePath::DataDir(directory.clone());
+        assert!(!state_path.exists());
+
+        let state_store = StateStore::open(&state_path).unwrap();
+        let node_state = state_store.load().unwrap().into_node_state().unwrap();
+        assert!(state_path.exists());
+
+        // A second node cannot use the directory while it is open
+        assert!(StateStore::open(&state_path).is_err());
+
+        state_store.save(&node_state).unwrap();
+
+        for file in [IDENTITY_FILE, PEERS_FILE, VALUES_LOG_FILE] {
+            assert!(Path::new(&data_file(&directory, file)).exists());
+        }
+
+        drop(state_store);
+        assert_eq!(
+            StateStore::open(&state_path)
+                .unwrap()
+                .load()
+                .unwrap()
+                .into_node_state()
+                .unwrap(),
+            node_state
+        );
+    }
+
+    #[test]
+    fn test_data_dir_moves_old_values_snapshot_into_log() {
+        let directory = test_directory("values-snapshot");
+        let key = Key::random();
+        let mut values = HashMap::new();
+        values.insert(
+            key,
+            structures::StoredValue {
+                stored_at: 1_700_000_000,
+                value: structures::Value {
+                    data: b"value".to_vec(),
+                    published_at: 1_700_000_000,
+                    publisher: NodeId::random(),
+                    ttl: 60,
+                },
+            },
+        );
+        save_snapshot(&data_file(&directory, VALUES_FILE), &values).unwrap();
+
+        let state_path = StatePath::DataDir(directory.clone());
+        let loaded_state = StateStore::open(&state_path).unwrap().load().unwrap();
+
+        assert!(!Path::new(&data_file(&directory, VALUES_FILE)).exists());
+        assert_eq!(loaded_state.into_node_state().unwrap().values, values);
+    }
+
+    #[test]
+    fn test_load_fails_when_locked_by_another_node() {
+        let path = StatePath::File(state_path("locked"));
+
+        let state_store = StateStore::open(&path).unwrap();
+        state_store.load().unwrap();
+
+        assert!(StateStore::open(&path).is_err());
+    }
+}
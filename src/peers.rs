@@ -1,119 +1,326 @@
+use crate::clock::Clock;
+use crate::messages::REQUEST_ATTEMPTS;
+use crate::node_id::NodeId;
+use crate::rtt::{backoff_timeouts, RttEstimator, Timeouts};
 use crate::structures;
-use std::cmp::{max, min};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashSet, VecDeque};
 use std::net::SocketAddr;
-
-use crate::utilities::calculate_xor_distance;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub const BUCKET_SIZE: usize = 20;
 pub const ID_BITS: usize = 160;
-const FIND_PEER_COUNT: usize = 20;
+pub const REPLACEMENT_CACHE_SIZE: usize = 20;
+pub const MAX_FAILED_REQUESTS: u32 = 3;
+
+/// How the routing table is kept. Each bucket holds `bucket_size` peers plus up to
+/// `replacement_cache_size` replacements, peers are evicted after `max_failed_requests` failures
+/// in a row, and requests are tried `request_attempts` times within `timeouts`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PeerParameters {
+    pub bucket_size: usize,
+    pub max_failed_requests: u32,
+    pub replacement_cache_size: usize,
+    pub request_attempts: u32,
+    pub timeouts: Timeouts,
+}
+
+impl Default for PeerParameters {
+    fn default() -> Self {
+        Self {
+            bucket_size: BUCKET_SIZE,
+            max_failed_requests: MAX_FAILED_REQUESTS,
+            replacement_cache_size: REPLACEMENT_CACHE_SIZE,
+            request_attempts: REQUEST_ATTEMPTS,
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+pub enum PeerStatus {
+    /// The peer is stored in the routing table.
+    Stored(structures::Peer),
+    /// The peer's bucket is full, so it is waiting in the bucket's replacement cache. When
+    /// `stale_peer` is set the caller should ping it and report the outcome through
+    /// `PeerManager::resolve_eviction`.
+    Cached {
+        peer: structures::Peer,
+        stale_peer: Option<structures::Peer>,
+    },
+}
+
+impl PeerStatus {
+    pub fn peer(&self) -> &structures::Peer {
+        match self {
+            PeerStatus::Stored(peer) => peer,
+            PeerStatus::Cached { peer, .. } => peer,
+        }
+    }
+}
 
 pub struct PeerManager {
+    bucket_lookups: Vec<u64>,
     buckets: Vec<VecDeque<structures::Peer>>,
-    local_node_id: String,
+    clock: Arc<dyn Clock>,
+    eviction_checks: HashSet<NodeId>,
+    local_node_id: NodeId,
+    parameters: PeerParameters,
+    replacements: Vec<VecDeque<structures::Peer>>,
 }
 
 impl PeerManager {
     pub fn new(
         buckets: Vec<VecDeque<structures::Peer>>,
-        local_node_id: &str,
+        local_node_id: &NodeId,
+        parameters: PeerParameters,
+        clock: Arc<dyn Clock>,
     ) -> Result<Self, String> {
-        Ok(Self {
-            buckets,
-            local_node_id: local_node_id.to_string(),
-        })
+        let mut peer_manager = Self {
+            bucket_lookups: vec![clock.unix_timestamp(); ID_BITS],
+            buckets: vec![VecDeque::with_capacity(parameters.bucket_size); ID_BITS],
+            clock,
+            eviction_checks: HashSet::new(),
+            local_node_id: *local_node_id,
+            parameters,
+            replacements: vec![VecDeque::new(); ID_BITS],
+        };
+
+        // Re-bucket loaded peers so that tables saved with an older distance metric are corrected
+        for peer in buckets.into_iter().flatten() {
+            let bucket_index = match peer_manager.bucket_index(&peer.node_id) {
+                Ok(bucket_index) => bucket_index,
+                Err(_) => continue,
+            };
+
+            if peer_manager.buckets[bucket_index].len() < parameters.bucket_size {
+                peer_manager.buckets[bucket_index].push_back(peer);
+            } else {
+                peer_manager.cache_replacement(bucket_index, peer);
+            }
+        }
+
+        Ok(peer_manager)
     }
 
+    /// Records contact with a peer. Buckets are ordered from least to most recently seen, so a
+    /// peer that is seen again moves to the back of its bucket.
     pub fn add_peer(
         &mut self,
         socket_addr: &SocketAddr,
-        peer_node_id: &str,
-        active: bool,
-    ) -> Result<structures::Peer, String> {
-        let distance = calculate_xor_distance(&self.local_node_id, peer_node_id)
-            .map_err(|error| format!("Failed to calculate distance: {}", error))?;
-        let bucket_index = distance.leading_zeros() as usize;
+        peer_node_id: &NodeId,
+    ) -> Result<PeerStatus, String> {
+        let bucket_index = self.bucket_index(peer_node_id)?;
+
+        let now = self.clock.unix_timestamp();
+
+        let peer_index = self.buckets[bucket_index]
+            .iter()
+            .position(|peer| peer.node_id == *peer_node_id);
+
+        if let Some(index) = peer_index {
+            let mut peer = self.buckets[bucket_index]
+                .remove(index)
+                .expect("Peer index should be in bounds.");
+
+            peer.active = true;
+            peer.address = *socket_addr;
+            peer.failed_requests = 0;
+            peer.last_seen = Some(now);
 
-        if self.buckets[bucket_index].len() >= BUCKET_SIZE {
-            return Err("Bucket is full".to_string());
+            self.buckets[bucket_index].push_back(peer.clone());
+
+            return Ok(PeerStatus::Stored(peer));
+        }
+
+        let peer = structures::Peer {
+            active: true,
+            address: *socket_addr,
+            failed_requests: 0,
+            first_seen: now,
+            last_seen: Some(now),
+            node_id: *peer_node_id,
+            rtt: RttEstimator::default(),
+        };
+
+        if self.buckets[bucket_index].len() < self.parameters.bucket_size {
+            self.buckets[bucket_index].push_back(peer.clone());
+
+            return Ok(PeerStatus::Stored(peer));
+        }
+
+        self.cache_replacement(bucket_index, peer.clone());
+
+        let least_recently_seen = self.buckets[bucket_index]
+            .front()
+            .cloned()
+            .expect("Full bucket should have a least recently seen peer.");
+
+        let stale_peer = if self.eviction_checks.insert(least_recently_seen.node_id) {
+            Some(least_recently_seen)
+        } else {
+            None
+        };
+
+        Ok(PeerStatus::Cached { peer, stale_peer })
+    }
+
+    /// Completes an eviction check started by `add_peer`. A stale peer that failed to respond is
+    /// removed and its slot is filled from the replacement cache.
+    pub fn resolve_eviction(&mut self, stale_node_id: &NodeId, responded: bool) {
+        self.eviction_checks.remove(stale_node_id);
+
+        if !responded {
+            self.remove_peer(stale_node_id);
         }
+    }
 
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .expect("Unable to generate timestamp due to current time.")
-            .as_secs();
+    /// Removes a peer from the routing table, replacing it with the most recently seen entry
+    /// from the bucket's replacement cache.
+    pub fn remove_peer(&mut self, peer_node_id: &NodeId) -> Option<structures::Peer> {
+        let bucket_index = self.bucket_index(peer_node_id).ok()?;
 
         let peer_index = self.buckets[bucket_index]
             .iter()
-            .position(|peer| peer.node_id == peer_node_id);
+            .position(|peer| peer.node_id == *peer_node_id)?;
 
-        let peer = match peer_index {
-            Some(index) => {
-                let peer = &mut self.buckets[bucket_index][index];
+        let mut peer = self.buckets[bucket_index].remove(peer_index)?;
+        peer.active = false;
 
-                peer.active = active;
-                if active {
-                    peer.last_seen = Some(now);
-                }
+        if let Some(replacement) = self.replacements[bucket_index].pop_back() {
+            self.buckets[bucket_index].push_back(replacement);
+        }
 
-                peer.clone()
-            }
-            None => {
-                let last_seen;
-
-                if active {
-                    last_seen = Some(now);
-                } else {
-                    last_seen = None;
-                }
-
-                let peer = structures::Peer {
-                    active,
-                    address: socket_addr.clone(),
-                    first_seen: now,
-                    last_seen,
-                    node_id: peer_node_id.to_string(),
-                };
-
-                self.buckets[bucket_index].push_back(peer.clone());
-
-                peer
-            }
+        Some(peer)
+    }
+
+    /// Counts a request the peer failed to answer. Peers that fail `max_failed_requests` times in
+    /// a row are evicted, and the evicted peer is returned.
+    pub fn record_failure(&mut self, peer_node_id: &NodeId) -> Option<structures::Peer> {
+        let bucket_index = self.bucket_index(peer_node_id).ok()?;
+
+        let peer = self.buckets[bucket_index]
+            .iter_mut()
+            .find(|peer| peer.node_id == *peer_node_id)?;
+
+        peer.active = false;
+        peer.failed_requests += 1;
+
+        if peer.failed_requests < self.parameters.max_failed_requests {
+            return None;
+        }
+
+        self.remove_peer(peer_node_id)
+    }
+
+    /// Adds a measured round trip time to the peer's estimator.
+    pub fn record_rtt(&mut self, peer_node_id: &NodeId, rtt: Duration) {
+        let bucket_index = match self.bucket_index(peer_node_id) {
+            Ok(bucket_index) => bucket_index,
+            Err(_) => return,
         };
 
-        Ok(peer)
+        if let Some(peer) = self.buckets[bucket_index]
+            .iter_mut()
+            .find(|peer| peer.node_id == *peer_node_id)
+        {
+            peer.rtt.add_sample(rtt);
+        }
+    }
+
+    /// The timeout for a request to the given address, derived from the peer's measured round
+    /// trip time. Unknown addresses get the initial timeout.
+    pub fn request_timeout(&self, socket_addr: &SocketAddr) -> Duration {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.iter())
+            .find(|peer| peer.address == *socket_addr)
+            .map(|peer| peer.rtt.timeout(&self.parameters.timeouts))
+            .unwrap_or(self.parameters.timeouts.initial)
+    }
+
+    /// The timeout for each attempt at a request to this address, doubling after every
+    /// unanswered attempt.
+    pub fn request_timeouts(&self, socket_addr: &SocketAddr) -> Vec<Duration> {
+        backoff_timeouts(
+            self.request_timeout(socket_addr),
+            self.parameters.request_attempts,
+            self.parameters.timeouts.max,
+        )
+    }
+
+    /// Marks the bucket covering the target as refreshed, since a lookup for it is under way.
+    pub fn touch_bucket(&mut self, target_id: &NodeId) {
+        if let Ok(bucket_index) = self.bucket_index(target_id) {
+            self.bucket_lookups[bucket_index] = self.clock.unix_timestamp();
+        }
+    }
+
+    /// Returns random IDs to look up for every bucket that has not seen a lookup in `max_age`
+    /// seconds. Buckets closer than the closest known peer are skipped since they are empty and a
+    /// lookup cannot fill them.
+    pub fn stale_bucket_targets(&self, max_age: u64) -> Vec<NodeId> {
+        let now = self.clock.unix_timestamp();
+
+        let deepest_bucket = match self.buckets.iter().rposition(|bucket| !bucket.is_empty()) {
+            Some(deepest_bucket) => deepest_bucket,
+            None => return Vec::new(),
+        };
+
+        (0..=deepest_bucket)
+            .filter(|index| now.saturating_sub(self.bucket_lookups[*index]) >= max_age)
+            .map(|index| self.local_node_id.random_in_bucket(index))
+            .collect()
+    }
+
+    /// Returns peers that have not been heard from in `max_age` seconds, or ever.
+    pub fn stale_peers(&self, max_age: u64) -> Vec<structures::Peer> {
+        let now = self.clock.unix_timestamp();
+
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.iter())
+            .filter(|peer| match peer.last_seen {
+                Some(last_seen) => now.saturating_sub(last_seen) >= max_age,
+                None => true,
+            })
+            .cloned()
+            .collect()
     }
 
     pub fn buckets(&self) -> Vec<VecDeque<structures::Peer>> {
         self.buckets.clone()
     }
 
-    pub fn nearby_peers(&self, target_node_id: &str) -> Result<Vec<structures::Peer>, String> {
-        let mut peers: HashMap<String, structures::Peer> = self
-            .find_nearby_peers(target_node_id, false)?
-            .into_iter()
-            .map(|peer| (peer.node_id.clone(), peer))
+    pub fn nearby_peers(&self, target_node_id: &NodeId) -> Vec<structures::Peer> {
+        let mut peers: Vec<structures::Peer> = self
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.iter())
+            .filter(|peer| peer.node_id != *target_node_id)
+            .cloned()
             .collect();
 
-        // If we don't have enough seen peers, include unseen peers
-        if peers.len() < FIND_PEER_COUNT {
-            let unseen_peers = self.find_nearby_peers(target_node_id, true)?;
+        peers.sort_by_key(|peer| peer.node_id ^ *target_node_id);
+        peers.truncate(self.parameters.bucket_size);
 
-            for peer in unseen_peers {
-                if peers.contains_key(&peer.node_id) {
-                    continue;
-                }
+        peers
+    }
 
-                peers.insert(peer.node_id.clone(), peer);
+    fn bucket_index(&self, peer_node_id: &NodeId) -> Result<usize, String> {
+        (self.local_node_id ^ *peer_node_id)
+            .bucket_index()
+            .ok_or_else(|| "Unable to add the local node as a peer".to_string())
+    }
 
-                if peers.len() >= FIND_PEER_COUNT {
-                    break;
-                }
-            }
-        }
+    fn cache_replacement(&mut self, bucket_index: usize, peer: structures::Peer) {
+        let replacements = &mut self.replacements[bucket_index];
 
-        Ok(peers.into_iter().map(|(_, peer)| peer).collect())
+        replacements.retain(|replacement| replacement.node_id != peer.node_id);
+        replacements.push_back(peer);
+
+        if replacements.len() > self.parameters.replacement_cache_size {
+            replacements.pop_front();
+        }
     }
 
     pub fn to_vec(&self) -> Vec<structures::Peer> {
@@ -123,100 +330,397 @@ impl PeerManager {
             .cloned()
             .collect()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::rtt::INITIAL_TIMEOUT;
+
+    const LOCAL_ID: &str = "0000000000000000000000000000000000000000";
+
+    fn id(value: &str) -> NodeId {
+        value.parse().unwrap()
+    }
+
+    fn peer_manager_with(node_ids: &[&str]) -> PeerManager {
+        peer_manager_with_clock(Arc::new(ManualClock::new(1_700_000_000)), node_ids)
+    }
+
+    fn peer_manager_with_clock(clock: Arc<ManualClock>, node_ids: &[&str]) -> PeerManager {
+        let mut peer_manager = PeerManager::new(
+            vec![VecDeque::new(); ID_BITS],
+            &id(LOCAL_ID),
+            PeerParameters::default(),
+            clock,
+        )
+        .unwrap();
+
+        for (index, node_id) in node_ids.iter().enumerate() {
+            let address = SocketAddr::from(([127, 0, 0, 1], 16600 + index as u16));
+
+            peer_manager.add_peer(&address, &id(node_id)).unwrap();
+        }
+
+        peer_manager
+    }
+
+    #[test]
+    fn test_add_peer_uses_distance_bucket() {
+        let peer_manager = peer_manager_with(&[
+            "8000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000001",
+            "00ff000000000000000000000000000000000000",
+        ]);
+
+        let buckets = peer_manager.buckets();
+
+        assert_eq!(
+            buckets[0][0].node_id,
+            id("8000000000000000000000000000000000000000")
+        );
+        assert_eq!(
+            buckets[8][0].node_id,
+            id("00ff000000000000000000000000000000000000")
+        );
+        assert_eq!(
+            buckets[159][0].node_id,
+            id("0000000000000000000000000000000000000001")
+        );
+    }
+
+    #[test]
+    fn test_add_peer_rejects_local_node() {
+        let mut peer_manager = peer_manager_with(&[]);
+        let address = SocketAddr::from(([127, 0, 0, 1], 16600));
+
+        assert!(peer_manager.add_peer(&address, &id(LOCAL_ID)).is_err());
+    }
+
+    #[test]
+    fn test_nearby_peers_sorted_by_distance() {
+        let peer_manager = peer_manager_with(&[
+            "f000000000000000000000000000000000000000",
+            "0f00000000000000000000000000000000000000",
+            "00f0000000000000000000000000000000000000",
+            "ff00000000000000000000000000000000000000",
+            "000f000000000000000000000000000000000000",
+        ]);
+
+        let peers: Vec<String> = peer_manager
+            .nearby_peers(&id("ff00000000000000000000000000000000000001"))
+            .into_iter()
+            .map(|peer| peer.node_id.to_string())
+            .collect();
+
+        assert_eq!(
+            peers,
+            vec![
+                "ff00000000000000000000000000000000000000",
+                "f000000000000000000000000000000000000000",
+                "0f00000000000000000000000000000000000000",
+                "000f000000000000000000000000000000000000",
+                "00f0000000000000000000000000000000000000",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nearby_peers_limited_to_closest() {
+        let node_ids: Vec<String> = (1..=(BUCKET_SIZE + 5))
+            .map(|index| format!("{:040x}", index))
+            .collect();
+        let peer_manager =
+            peer_manager_with(&node_ids.iter().map(|id| id.as_str()).collect::<Vec<&str>>());
+
+        let peers = peer_manager.nearby_peers(&id("0000000000000000000000000000000000000003"));
 
-    fn find_nearby_peers(
-        &self,
-        target_node_id: &str,
-        include_unseen: bool,
-    ) -> Result<Vec<structures::Peer>, String> {
-        let mut peers: HashMap<String, structures::Peer> = HashMap::new();
-
-        let target_distance = calculate_xor_distance(&self.local_node_id, target_node_id)
-            .map_err(|error| format!("Failed to calculate distance: {}", error))?;
-        let target_bucket_index = target_distance.leading_zeros() as usize;
-
-        for offset in 0..ID_BITS {
-            // Check bucket at distance + offset
-            if let Ok(nodes) = self.find_peers_at_offset(
-                target_node_id,
-                target_bucket_index + offset,
-                include_unseen,
-            ) {
-                for node in nodes {
-                    if node.node_id == target_node_id {
-                        continue;
-                    }
-
-                    if peers.contains_key(&node.node_id) {
-                        continue;
-                    }
-
-                    peers.insert(node.node_id.clone(), node);
-
-                    if peers.len() >= FIND_PEER_COUNT {
-                        return Ok(peers.into_iter().map(|(_, peer)| peer).collect());
-                    }
-                }
+        assert_eq!(peers.len(), BUCKET_SIZE);
+        assert_eq!(peers[0].node_id, id(&format!("{:040x}", 2)));
+        assert_eq!(peers[1].node_id, id(&format!("{:040x}", 1)));
+        assert!(!peers
+            .iter()
+            .any(|peer| peer.node_id == id("0000000000000000000000000000000000000003")));
+        assert!(!peers
+            .iter()
+            .any(|peer| peer.node_id == id(&format!("{:040x}", BUCKET_SIZE + 5))));
+    }
+
+    fn far_id(index: usize) -> NodeId {
+        id(&format!("80{:038x}", index))
+    }
+
+    fn fill_far_bucket(peer_manager: &mut PeerManager) {
+        for index in 0..BUCKET_SIZE {
+            let address = SocketAddr::from(([127, 0, 0, 1], 17000 + index as u16));
+
+            match peer_manager.add_peer(&address, &far_id(index)).unwrap() {
+                PeerStatus::Stored(_) => {}
+                PeerStatus::Cached { .. } => panic!("Bucket should not be full yet"),
             }
+        }
+    }
+
+    #[test]
+    fn test_add_peer_moves_seen_peer_to_back() {
+        let mut peer_manager = peer_manager_with(&[]);
+        fill_far_bucket(&mut peer_manager);
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 17000));
+        peer_manager.add_peer(&address, &far_id(0)).unwrap();
 
-            // Check bucket at distance - offset
-            if offset > 0 && offset <= target_bucket_index {
-                if let Ok(nodes) = self.find_peers_at_offset(
-                    target_node_id,
-                    target_bucket_index - offset,
-                    include_unseen,
-                ) {
-                    for node in nodes {
-                        if node.node_id == target_node_id {
-                            continue;
-                        }
-
-                        if peers.contains_key(&node.node_id) {
-                            continue;
-                        }
-
-                        peers.insert(node.node_id.clone(), node);
-
-                        if peers.len() >= FIND_PEER_COUNT {
-                            return Ok(peers.into_iter().map(|(_, peer)| peer).collect());
-                        }
-                    }
-                }
+        let buckets = peer_manager.buckets();
+
+        assert_eq!(buckets[0].len(), BUCKET_SIZE);
+        assert_eq!(buckets[0].front().unwrap().node_id, far_id(1));
+        assert_eq!(buckets[0].back().unwrap().node_id, far_id(0));
+    }
+
+    #[test]
+    fn test_full_bucket_caches_peer_and_requests_one_eviction_check() {
+        let mut peer_manager = peer_manager_with(&[]);
+        fill_far_bucket(&mut peer_manager);
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 18000));
+
+        match peer_manager
+            .add_peer(&address, &far_id(BUCKET_SIZE))
+            .unwrap()
+        {
+            PeerStatus::Cached { peer, stale_peer } => {
+                assert_eq!(peer.node_id, far_id(BUCKET_SIZE));
+                assert_eq!(stale_peer.unwrap().node_id, far_id(0));
             }
+            PeerStatus::Stored(_) => panic!("Peer should have been cached"),
         }
 
-        Ok(peers.into_iter().map(|(_, peer)| peer).collect())
+        match peer_manager
+            .add_peer(&address, &far_id(BUCKET_SIZE))
+            .unwrap()
+        {
+            PeerStatus::Cached { stale_peer, .. } => assert!(stale_peer.is_none()),
+            PeerStatus::Stored(_) => panic!("Peer should have been cached"),
+        }
+
+        assert!(!peer_manager
+            .to_vec()
+            .iter()
+            .any(|peer| peer.node_id == far_id(BUCKET_SIZE)));
+    }
+
+    #[test]
+    fn test_resolve_eviction_replaces_unresponsive_peer() {
+        let mut peer_manager = peer_manager_with(&[]);
+        fill_far_bucket(&mut peer_manager);
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 18000));
+        peer_manager
+            .add_peer(&address, &far_id(BUCKET_SIZE))
+            .unwrap();
+
+        peer_manager.resolve_eviction(&far_id(0), false);
+
+        let buckets = peer_manager.buckets();
+
+        assert_eq!(buckets[0].len(), BUCKET_SIZE);
+        assert!(!buckets[0].iter().any(|peer| peer.node_id == far_id(0)));
+        assert_eq!(buckets[0].back().unwrap().node_id, far_id(BUCKET_SIZE));
     }
 
-    fn find_peers_at_offset(
-        &self,
-        target_node_id: &str,
-        distance_offset: usize,
-        include_unseen: bool,
-    ) -> Result<Vec<structures::Peer>, String> {
-        let distance = calculate_xor_distance(&self.local_node_id, target_node_id)
-            .map_err(|error| format!("Failed to calculate distance: {}", error))?;
+    #[test]
+    fn test_resolve_eviction_keeps_responsive_peer() {
+        let mut peer_manager = peer_manager_with(&[]);
+        fill_far_bucket(&mut peer_manager);
 
-        let distance = distance + distance_offset as u32;
-        let distance = max(distance, 0);
-        let distance = min(distance, ID_BITS as u32);
+        let address = SocketAddr::from(([127, 0, 0, 1], 18000));
+        peer_manager
+            .add_peer(&address, &far_id(BUCKET_SIZE))
+            .unwrap();
 
-        let bucket_index = distance.leading_zeros() as usize;
+        peer_manager.resolve_eviction(&far_id(0), true);
 
-        let mut peers: Vec<structures::Peer> = self.buckets[bucket_index]
+        let buckets = peer_manager.buckets();
+
+        assert!(buckets[0].iter().any(|peer| peer.node_id == far_id(0)));
+        assert!(!buckets[0]
             .iter()
-            .cloned()
-            .collect::<Vec<structures::Peer>>();
+            .any(|peer| peer.node_id == far_id(BUCKET_SIZE)));
+
+        // The cached peer is still available to replace the next peer that goes away
+        peer_manager.remove_peer(&far_id(5));
 
-        if !include_unseen {
-            peers = peers
-                .into_iter()
-                .filter(|peer| peer.last_seen != None)
-                .collect();
+        assert_eq!(
+            peer_manager.buckets()[0].back().unwrap().node_id,
+            far_id(BUCKET_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_record_failure_evicts_after_repeated_failures() {
+        let mut peer_manager = peer_manager_with(&[]);
+        fill_far_bucket(&mut peer_manager);
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 18000));
+        peer_manager
+            .add_peer(&address, &far_id(BUCKET_SIZE))
+            .unwrap();
+
+        for _ in 1..MAX_FAILED_REQUESTS {
+            assert!(peer_manager.record_failure(&far_id(3)).is_none());
         }
 
-        peers.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        let evicted_peer = peer_manager.record_failure(&far_id(3)).unwrap();
+
+        assert_eq!(evicted_peer.node_id, far_id(3));
+        assert!(!evicted_peer.active);
+        assert!(!peer_manager
+            .to_vec()
+            .iter()
+            .any(|peer| peer.node_id == far_id(3)));
+        assert!(peer_manager
+            .to_vec()
+            .iter()
+            .any(|peer| peer.node_id == far_id(BUCKET_SIZE)));
+    }
+
+    #[test]
+    fn test_add_peer_resets_failures() {
+        let mut peer_manager = peer_manager_with(&["8000000000000000000000000000000000000000"]);
+        let node_id = id("8000000000000000000000000000000000000000");
+        let address = SocketAddr::from(([127, 0, 0, 1], 16600));
+
+        for _ in 1..MAX_FAILED_REQUESTS {
+            peer_manager.record_failure(&node_id);
+        }
+
+        peer_manager.add_peer(&address, &node_id).unwrap();
+
+        assert_eq!(peer_manager.to_vec()[0].failed_requests, 0);
+        assert!(peer_manager.record_failure(&node_id).is_none());
+    }
+
+    #[test]
+    fn test_request_timeout_follows_recorded_rtt() {
+        let mut peer_manager = peer_manager_with(&["8000000000000000000000000000000000000000"]);
+        let node_id = id("8000000000000000000000000000000000000000");
+        let address = SocketAddr::from(([127, 0, 0, 1], 16600));
+
+        assert_eq!(peer_manager.request_timeout(&address), INITIAL_TIMEOUT);
+
+        for _ in 0..10 {
+            peer_manager.record_rtt(&node_id, Duration::from_secs(2));
+        }
+
+        // The estimate survives the peer being seen again
+        peer_manager.add_peer(&address, &node_id).unwrap();
+
+        assert!(peer_manager.request_timeout(&address) > Duration::from_secs(2));
+        assert_eq!(
+            peer_manager.request_timeout(&SocketAddr::from(([127, 0, 0, 1], 16700))),
+            INITIAL_TIMEOUT
+        );
+    }
+
+    #[test]
+    fn test_peer_parameters_change_eviction_and_timeouts() {
+        let parameters = PeerParameters {
+            max_failed_requests: 1,
+            request_attempts: 2,
+            timeouts: Timeouts {
+                initial: Duration::from_millis(300),
+                max: Duration::from_millis(500),
+                min: Duration::from_millis(100),
+            },
+            ..PeerParameters::default()
+        };
+        let clock = Arc::new(ManualClock::new(1_700_000_000));
+        let mut peer_manager = PeerManager::new(
+            vec![VecDeque::new(); ID_BITS],
+            &id(LOCAL_ID),
+            parameters,
+            clock,
+        )
+        .unwrap();
+        let node_id = id("8000000000000000000000000000000000000000");
+        let address = SocketAddr::from(([127, 0, 0, 1], 16600));
+
+        peer_manager.add_peer(&address, &node_id).unwrap();
+
+        assert_eq!(
+            peer_manager.request_timeouts(&address),
+            vec![Duration::from_millis(300), Duration::from_millis(500)]
+        );
+        assert!(peer_manager.record_failure(&node_id).is_some());
+    }
+
+    #[test]
+    fn test_stale_peers_include_unseen_peers() {
+        let mut peer_manager = peer_manager_with(&["8000000000000000000000000000000000000000"]);
+        let address = SocketAddr::from(([127, 0, 0, 1], 16601));
+
+        let peer = peer_manager
+            .add_peer(&address, &id("4000000000000000000000000000000000000000"))
+            .unwrap()
+            .peer()
+            .clone();
+
+        // As saved by versions that added peers before hearing from them
+        let bucket_index = peer_manager.bucket_index(&peer.node_id).unwrap();
+        peer_manager.buckets[bucket_index][0].last_seen = None;
+
+        let stale_peers = peer_manager.stale_peers(60);
+
+        assert_eq!(stale_peers.len(), 1);
+        assert_eq!(
+            stale_peers[0].node_id,
+            id("4000000000000000000000000000000000000000")
+        );
+        assert_eq!(peer_manager.stale_peers(0).len(), 2);
+    }
+
+    #[test]
+    fn test_stale_bucket_targets_cover_populated_range() {
+        let mut peer_manager = peer_manager_with(&["0100000000000000000000000000000000000000"]);
+
+        assert!(peer_manager.stale_bucket_targets(60).is_empty());
+
+        let targets = peer_manager.stale_bucket_targets(0);
+
+        assert_eq!(targets.len(), 8);
+        for (index, target) in targets.iter().enumerate() {
+            assert_eq!((id(LOCAL_ID) ^ *target).bucket_index(), Some(index));
+        }
+
+        peer_manager.bucket_lookups[3] = 0;
+
+        assert_eq!(peer_manager.stale_bucket_targets(60).len(), 1);
+    }
+
+    #[test]
+    fn test_peers_and_buckets_go_stale_as_clock_advances() {
+        let clock = Arc::new(ManualClock::new(1_700_000_000));
+        let mut peer_manager =
+            peer_manager_with_clock(clock.clone(), &["0100000000000000000000000000000000000000"]);
+
+        assert!(peer_manager.stale_peers(60).is_empty());
+        assert!(peer_manager.stale_bucket_targets(60).is_empty());
+
+        clock.advance(Duration::from_secs(60));
+
+        assert_eq!(peer_manager.stale_peers(60).len(), 1);
+        assert_eq!(peer_manager.stale_bucket_targets(60).len(), 8);
+
+        // Hearing from the peer and looking up a bucket make them fresh again
+        peer_manager
+            .add_peer(
+                &SocketAddr::from(([127, 0, 0, 1], 16600)),
+                &id("0100000000000000000000000000000000000000"),
+            )
+            .unwrap();
+        peer_manager.touch_bucket(&id("0100000000000000000000000000000000000000"));
 
-        Ok(peers)
+        assert!(peer_manager.stale_peers(60).is_empty());
+        assert_eq!(peer_manager.stale_bucket_targets(60).len(), 7);
     }
 }
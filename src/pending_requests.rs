@@ -0,0 +1,242 @@
+use crate::clock::Clock;
+use crate::node_id::TransactionId;
+use crate::structures;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct PendingRequest {
+    address: SocketAddr,
+    deadline: Instant,
+    response_tx: mpsc::SyncSender<structures::Packet>,
+}
+
+/// Requests awaiting a response, keyed by transaction ID. Each request owns a single use channel
+/// that the response is delivered on, so waiting callers never have to poll. Closing drops the
+/// channels, which wakes every waiting caller.
+pub struct PendingRequests {
+    clock: Arc<dyn Clock>,
+    is_closed: AtomicBool,
+    requests: Mutex<HashMap<TransactionId, PendingRequest>>,
+}
+
+impl PendingRequests {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            is_closed: AtomicBool::new(false),
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The clock request deadlines are measured against.
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Registers a request sent to `address`. The returned receiver yields the response if one
+    /// arrives from that address before `timeout` elapses, and is disconnected once the requests
+    /// are closed.
+    pub fn register(
+        &self,
+        transaction_id: &TransactionId,
+        address: &SocketAddr,
+        timeout: Duration,
+    ) -> mpsc::Receiver<structures::Packet> {
+        let (response_tx, response_rx) = mpsc::sync_channel(1);
+        let mut requests = self.requests.lock().unwrap();
+
+        if self.is_closed.load(Ordering::Relaxed) {
+            return response_rx;
+        }
+
+        requests.insert(
+            *transaction_id,
+            PendingRequest {
+                address: *address,
+                deadline: self.clock.now() + timeout,
+                response_tx,
+            },
+        );
+
+        response_rx
+    }
+
+    /// Checks that a response is for a request still waiting on one, and comes from the address
+    /// the request was sent to, without delivering it.
+    pub fn expects(&self, src: &SocketAddr, packet: &structures::Packet) -> Result<(), String> {
+        let requests = self.requests.lock().unwrap();
+
+        check_response(&requests, src, packet)
+    }
+
+    /// Delivers a response to the request waiting on it. Responses for unknown or timed out
+    /// transactions, or from an address other than the one the request was sent to, are rejected.
+    pub fn complete(&self, src: &SocketAddr, packet: structures::Packet) -> Result<(), String> {
+        let mut requests = self.requests.lock().unwrap();
+
+        check_response(&requests, src, &packet)?;
+
+        let request = requests
+            .remove(&packet.transaction_id)
+            .expect("Pending request should exist.");
+
+        if self.clock.now() > request.deadline {
+            return Err(format!(
+                "Dropping late response from {} for transaction {}",
+                src, packet.transaction_id
+            ));
+        }
+
+        // The waiter may have given up already, in which case the response is dropped
+        let _ = request.response_tx.try_send(packet);
+
+        Ok(())
+    }
+
+    pub fn remove(&self, transaction_id: &TransactionId) {
+        self.requests.lock().unwrap().remove(transaction_id);
+    }
+
+    /// Abandons every pending request and refuses new ones, for when the node shuts down.
+    pub fn close(&self) {
+        let mut requests = self.requests.lock().unwrap();
+
+        self.is_closed.store(true, Ordering::Relaxed);
+        requests.clear();
+    }
+}
+
+fn check_response(
+    requests: &HashMap<TransactionId, PendingRequest>,
+    src: &SocketAddr,
+    packet: &structures::Packet,
+) -> Result<(), String> {
+    let request = requests.get(&packet.transaction_id).ok_or_else(|| {
+        format!(
+            "Dropping response from {} for unknown transaction {}",
+            src, packet.transaction_id
+        )
+    })?;
+
+    if request.address != *src {
+        return Err(format!(
+            "Dropping response for transaction {} from {}, expected {}",
+            packet.transaction_id, src, request.address
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, SystemClock};
+    use crate::node_id::NodeId;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn pong(transaction_id: &TransactionId) -> structures::Packet {
+        structures::Packet {
+            message: structures::Message::Response(structures::Response::Pong),
+            node_id: NodeId::random(),
+            transaction_id: *transaction_id,
+        }
+    }
+
+    #[test]
+    fn test_complete_delivers_response() {
+        let pending_requests = PendingRequests::new(Arc::new(SystemClock));
+        let transaction_id = TransactionId::random();
+
+        let response_rx =
+            pending_requests.register(&transaction_id, &address(1), Duration::from_secs(5));
+
+        pending_requests
+            .complete(&address(1), pong(&transaction_id))
+            .unwrap();
+
+        assert_eq!(
+            response_rx.try_recv().unwrap().transaction_id,
+            transaction_id
+        );
+        assert!(!pending_requests
+            .requests
+            .lock()
+            .unwrap()
+            .contains_key(&transaction_id));
+    }
+
+    #[test]
+    fn test_complete_rejects_unexpected_address() {
+        let pending_requests = PendingRequests::new(Arc::new(SystemClock));
+        let transaction_id = TransactionId::random();
+
+        let response_rx =
+            pending_requests.register(&transaction_id, &address(1), Duration::from_secs(5));
+
+        assert!(pending_requests
+            .complete(&address(2), pong(&transaction_id))
+            .is_err());
+        assert!(response_rx.try_recv().is_err());
+
+        // The request is still waiting on the real peer
+        assert!(pending_requests
+            .complete(&address(1), pong(&transaction_id))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_close_disconnects_waiting_and_new_requests() {
+        let pending_requests = PendingRequests::new(Arc::new(SystemClock));
+
+        let response_rx = pending_requests.register(
+            &TransactionId::random(),
+            &address(1),
+            Duration::from_secs(5),
+        );
+
+        pending_requests.close();
+
+        assert_eq!(
+            response_rx.recv_timeout(Duration::from_secs(5)),
+            Err(mpsc::RecvTimeoutError::Disconnected)
+        );
+
+        let late_rx = pending_requests.register(
+            &TransactionId::random(),
+            &address(1),
+            Duration::from_secs(5),
+        );
+
+        assert_eq!(late_rx.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_complete_rejects_unknown_and_late_responses() {
+        let clock = Arc::new(ManualClock::new(0));
+        let pending_requests = PendingRequests::new(clock.clone());
+        let transaction_id = TransactionId::random();
+
+        assert!(pending_requests
+            .complete(&address(1), pong(&transaction_id))
+            .is_err());
+
+        pending_requests.register(&transaction_id, &address(1), Duration::from_secs(5));
+        clock.advance(Duration::from_secs(6));
+
+        assert!(pending_requests
+            .complete(&address(1), pong(&transaction_id))
+            .is_err());
+        assert!(!pending_requests
+            .requests
+            .lock()
+            .unwrap()
+            .contains_key(&transaction_id));
+    }
+}
@@ -0,0 +1,49 @@
+use client_server_test::error_log;
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+
+/// Records the process ID for supervisors. The file stays locked while the node runs, so a second
+/// node cannot start with the same pid file, and it is removed when dropped.
+pub struct PidFile {
+    _file: File,
+    path: String,
+}
+
+impl PidFile {
+    pub fn create(path: &str) -> Result<Self, String> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|error| format!("Failed to open pid file \"{}\": {}", path, error))?;
+
+        file.try_lock_exclusive().map_err(|_| {
+            format!(
+                "Pid file \"{}\" is locked, is another node already running?",
+                path
+            )
+        })?;
+
+        file.set_len(0)
+            .and_then(|_| writeln!(file, "{}", std::process::id()))
+            .map_err(|error| format!("Failed to write pid file \"{}\": {}", path, error))?;
+
+        Ok(Self {
+            _file: file,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.path) {
+            error_log(format!(
+                "Failed to remove pid file \"{}\": {}",
+                self.path, error
+            ));
+        }
+    }
+}
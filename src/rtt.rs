@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+/// The timeout used for peers we have no round trip samples for yet.
+pub const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+pub const MIN_TIMEOUT: Duration = Duration::from_millis(200);
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bounds on request timeouts. `initial` is used for peers without round trip samples, and
+/// measured timeouts and backoff are kept between `min` and `max`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Timeouts {
+    pub initial: Duration,
+    pub max: Duration,
+    pub min: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            initial: INITIAL_TIMEOUT,
+            max: MAX_TIMEOUT,
+            min: MIN_TIMEOUT,
+        }
+    }
+}
+
+/// Smoothed round trip time and variance for a peer, following the estimator TCP uses
+/// (RFC 6298). The request timeout adapts to the measured RTT so slow links get more time while
+/// fast peers that stop responding are noticed quickly.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct RttEstimator {
+    smoothed_rtt: Option<Duration>,
+    rtt_variance: Duration,
+}
+
+impl RttEstimator {
+    pub fn add_sample(&mut self, rtt: Duration) {
+        match self.smoothed_rtt {
+            None => {
+                self.smoothed_rtt = Some(rtt);
+                self.rtt_variance = rtt / 2;
+            }
+            Some(smoothed_rtt) => {
+                let deviation = smoothed_rtt.abs_diff(rtt);
+
+                self.rtt_variance = (self.rtt_variance * 3 + deviation) / 4;
+                self.smoothed_rtt = Some((smoothed_rtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    pub fn timeout(&self, timeouts: &Timeouts) -> Duration {
+        match self.smoothed_rtt {
+            Some(smoothed_rtt) => {
+                (smoothed_rtt + self.rtt_variance * 4).clamp(timeouts.min, timeouts.max)
+            }
+            None => timeouts.initial,
+        }
+    }
+}
+
+/// The timeout for each attempt at a request, doubling after every unanswered attempt.
+pub fn backoff_timeouts(timeout: Duration, attempts: u32, max_timeout: Duration) -> Vec<Duration> {
+    (0..attempts)
+        .map(|attempt| (timeout * 2u32.pow(attempt)).min(max_timeout))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeout_without_samples() {
+        assert_eq!(
+            RttEstimator::default().timeout(&Timeouts::default()),
+            INITIAL_TIMEOUT
+        );
+    }
+
+    #[test]
+    fn test_timeout_tracks_measured_rtt() {
+        let timeouts = Timeouts::default();
+        let mut fast = RttEstimator::default();
+        let mut slow = RttEstimator::default();
+
+        for _ in 0..20 {
+            fast.add_sample(Duration::from_millis(10));
+            slow.add_sample(Duration::from_millis(1500));
+        }
+
+        // A fast peer is clamped to the minimum, so a dead peer is detected quickly
+        assert_eq!(fast.timeout(&timeouts), MIN_TIMEOUT);
+        // A slow but steady peer gets a timeout above its RTT
+        assert!(slow.timeout(&timeouts) > Duration::from_millis(1500));
+        assert!(slow.timeout(&timeouts) < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_timeout_grows_with_variance() {
+        let timeouts = Timeouts::default();
+        let mut steady = RttEstimator::default();
+        let mut jittery = RttEstimator::default();
+
+        for sample in 0..20 {
+            let rtt = if sample % 2 == 0 { 100 } else { 500 };
+
+            steady.add_sample(Duration::from_millis(300));
+            jittery.add_sample(Duration::from_millis(rtt));
+        }
+
+        assert!(jittery.timeout(&timeouts) > steady.timeout(&timeouts));
+    }
+
+    #[test]
+    fn test_backoff_timeouts_double_up_to_maximum() {
+        assert_eq!(
+            backoff_timeouts(Duration::from_millis(500), 3, MAX_TIMEOUT),
+            vec![
+                Duration::from_millis(500),
+                Duration::from_secs(1),
+                Duration::from_secs(2)
+            ]
+        );
+        assert_eq!(
+            backoff_timeouts(Duration::from_secs(8), 2, MAX_TIMEOUT),
+            vec![Duration::from_secs(8), MAX_TIMEOUT]
+        );
+        assert_eq!(
+            backoff_timeouts(Duration::from_secs(2), 2, Duration::from_secs(3)),
+            vec![Duration::from_secs(2), Duration::from_secs(3)]
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Signalled once when the node stops. Background threads wait on it between runs instead of
+/// sleeping, so they stop as soon as the node does.
+#[derive(Default)]
+pub struct Shutdown {
+    condvar: Condvar,
+    is_shut_down: Mutex<bool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wakes every thread waiting on the signal.
+    pub fn signal(&self) {
+        *self.is_shut_down.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+
+    /// Waits until the node shuts down or the timeout passes, returning whether it shut down.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let is_shut_down = self.is_shut_down.lock().unwrap();
+
+        let (is_shut_down, _) = self
+            .condvar
+            .wait_timeout_while(is_shut_down, timeout, |is_shut_down| !*is_shut_down)
+            .unwrap();
+
+        *is_shut_down
+    }
+
+    /// Waits while `condition` holds and the node is running, returning whether it shut down.
+    /// The condition is only checked again when the node shuts down or `wake` is called.
+    pub fn wait_while(&self, condition: impl Fn() -> bool) -> bool {
+        let is_shut_down = self.is_shut_down.lock().unwrap();
+
+        let is_shut_down = self
+            .condvar
+            .wait_while(is_shut_down, |is_shut_down| !*is_shut_down && condition())
+            .unwrap();
+
+        *is_shut_down
+    }
+
+    /// Wakes the threads waiting on the signal so they check their conditions again.
+    pub fn wake(&self) {
+        // Taking the lock ensures a waiter is either still checking its condition or already
+        // waiting, so the notification cannot be missed
+        let _is_shut_down = self.is_shut_down.lock().unwrap();
+        self.condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_signal_wakes_waiting_threads() {
+        let shutdown = Arc::new(Shutdown::new());
+
+        assert!(!shutdown.wait_timeout(Duration::from_millis(1)));
+
+        let shutdown_clone = shutdown.clone();
+        let started_at = Instant::now();
+        let wait_thread =
+            thread::spawn(move || shutdown_clone.wait_timeout(Duration::from_secs(60)));
+
+        shutdown.signal();
+
+        assert!(wait_thread.join().unwrap());
+        assert!(started_at.elapsed() < Duration::from_secs(60));
+        assert!(shutdown.wait_timeout(Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,792 @@
+//! Runs many nodes in one process over a virtual network. Simulated nodes use the same routing
+//! table, value store, lookup and request handling as a real node, but messages are delivered by
+//! an event queue, every node reads the time from one manually advanced clock, and latency and
+//! loss are drawn from a seeded random number generator. A simulation with the same
+//! configuration plays out identically every time.
+
+use crate::clock::{Clock, ManualClock};
+use crate::lookup::{Lookup, LookupKind, LookupParameters, LookupResult};
+use crate::maintenance::take_due_values;
+use crate::messages::respond;
+use crate::node_id::{Key, NodeId, TransactionId};
+use crate::peers::{PeerManager, PeerParameters, PeerStatus};
+use crate::storage::MemoryStorage;
+use crate::structures;
+use crate::values::{ValueStore, DEFAULT_TTL};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Simulations start at a fixed time so that timestamps repeat from run to run
+const START_TIMESTAMP: u64 = 1_700_000_000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SimulationConfig {
+    pub seed: u64,
+    pub node_count: usize,
+    pub parameters: LookupParameters,
+    /// Each message is delayed by a latency drawn uniformly from this range.
+    pub min_latency: Duration,
+    pub max_latency: Duration,
+    /// The probability that any one message is lost.
+    pub loss_rate: f64,
+    /// How long a node waits for a response before counting the request as failed.
+    pub request_timeout: Duration,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            node_count: 100,
+            parameters: LookupParameters::default(),
+            min_latency: Duration::from_millis(10),
+            max_latency: Duration::from_millis(100),
+            loss_rate: 0.0,
+            request_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct SimulationStats {
+    pub messages_sent: usize,
+    pub messages_lost: usize,
+    pub requests_timed_out: usize,
+}
+
+struct SimulatedNode {
+    address: SocketAddr,
+    node_id: NodeId,
+    online: bool,
+    peer_manager: PeerManager,
+    value_store: ValueStore,
+}
+
+enum Purpose {
+    /// A query made by the operation currently being run.
+    Query,
+    /// A ping deciding whether a stale peer should be evicted.
+    EvictionCheck(NodeId),
+}
+
+struct PendingRequest {
+    address: SocketAddr,
+    purpose: Purpose,
+    requester: usize,
+}
+
+enum Event {
+    Deliver {
+        from: SocketAddr,
+        to: SocketAddr,
+        packet: structures::Packet,
+    },
+    Timeout(TransactionId),
+}
+
+pub struct Simulation {
+    addresses: HashMap<SocketAddr, usize>,
+    clock: Arc<ManualClock>,
+    completed: VecDeque<(TransactionId, Option<structures::Packet>)>,
+    config: SimulationConfig,
+    events: BTreeMap<(Duration, u64), Event>,
+    next_event: u64,
+    nodes: Vec<SimulatedNode>,
+    now: Duration,
+    pending: HashMap<TransactionId, PendingRequest>,
+    rng: StdRng,
+    stats: SimulationStats,
+}
+
+impl Simulation {
+    /// Creates the nodes and joins them to the network one at a time, each through a random node
+    /// that has already joined.
+    pub fn new(config: SimulationConfig) -> Result<Self, String> {
+        let mut simulation = Self {
+            addresses: HashMap::new(),
+            clock: Arc::new(ManualClock::new(START_TIMESTAMP)),
+            completed: VecDeque::new(),
+            config,
+            events: BTreeMap::new(),
+            next_event: 0,
+            nodes: Vec::with_capacity(config.node_count),
+            now: Duration::ZERO,
+            pending: HashMap::new(),
+            rng: StdRng::seed_from_u64(config.seed),
+            stats: SimulationStats::default(),
+        };
+
+        for index in 0..config.node_count {
+            let node_id = NodeId::random_from(&mut simulation.rng);
+            let address = SocketAddr::from((
+                [10, (index >> 16) as u8, (index >> 8) as u8, index as u8],
+                16600,
+            ));
+
+            simulation.addresses.insert(address, index);
+            simulation.nodes.push(SimulatedNode {
+                address,
+                node_id,
+                online: true,
+                peer_manager: PeerManager::new(
+                    Vec::new(),
+                    &node_id,
+                    PeerParameters {
+                        bucket_size: config.parameters.bucket_size,
+                        ..PeerParameters::default()
+                    },
+                    simulation.clock.clone(),
+                )?,
+                value_store: ValueStore::new(
+                    Box::new(MemoryStorage::new(HashMap::new())),
+                    simulation.clock.clone(),
+                )?,
+            });
+
+            if index > 0 {
+                let bootstrap = simulation.rng.gen_range(0..index);
+                let bootstrap_address = simulation.nodes[bootstrap].address;
+                let bootstrap_id = simulation.nodes[bootstrap].node_id;
+
+                simulation.nodes[index]
+                    .peer_manager
+                    .add_peer(&bootstrap_address, &bootstrap_id)?;
+                simulation.find_node(index, &node_id);
+            }
+        }
+
+        Ok(simulation)
+    }
+
+    pub fn node_id(&self, index: usize) -> NodeId {
+        self.nodes[index].node_id
+    }
+
+    pub fn is_online(&self, index: usize) -> bool {
+        self.nodes[index].online
+    }
+
+    pub fn online_nodes(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|index| self.nodes[*index].online)
+            .collect()
+    }
+
+    pub fn peers(&self, index: usize) -> Vec<structures::Peer> {
+        self.nodes[index].peer_manager.to_vec()
+    }
+
+    pub fn has_value(&self, index: usize, key: &Key) -> bool {
+        matches!(self.nodes[index].value_store.retrieve(key), Ok(Some(_)))
+    }
+
+    /// The virtual time that has passed since the simulation started.
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn stats(&self) -> SimulationStats {
+        self.stats
+    }
+
+    /// Takes a node off the network or brings it back. Offline nodes keep their state but
+    /// neither send nor receive, and a returning node looks up its own ID as a restarted node
+    /// does.
+    pub fn set_online(&mut self, index: usize, online: bool) {
+        let rejoining = online && !self.nodes[index].online;
+
+        self.nodes[index].online = online;
+
+        if rejoining {
+            let node_id = self.nodes[index].node_id;
+            self.find_node(index, &node_id);
+        }
+    }
+
+    /// Takes each online node offline with probability `leave_probability`, and brings each
+    /// offline node back with probability `rejoin_probability`.
+    pub fn churn(&mut self, leave_probability: f64, rejoin_probability: f64) {
+        for index in 0..self.nodes.len() {
+            let online = if self.nodes[index].online {
+                !self.rng.gen_bool(leave_probability)
+            } else {
+                self.rng.gen_bool(rejoin_probability)
+            };
+
+            self.set_online(index, online);
+        }
+    }
+
+    /// Lets virtual time pass, delivering messages and timing out requests along the way.
+    pub fn advance(&mut self, duration: Duration) {
+        let until = self.now + duration;
+
+        while self
+            .events
+            .first_key_value()
+            .is_some_and(|((time, _), _)| *time <= until)
+        {
+            self.step();
+        }
+
+        self.set_time(until);
+    }
+
+    /// Looks up the nodes closest to the target from the given node.
+    pub fn find_node(&mut self, index: usize, target_id: &NodeId) -> Vec<structures::FoundNode> {
+        match self.run_lookup(index, LookupKind::Node, target_id) {
+            LookupResult::Nodes(nodes) => nodes,
+            LookupResult::Value(_) => Vec::new(),
+        }
+    }
+
+    /// Publishes a value from the given node, storing it locally and on the closest nodes.
+    /// Returns how many remote nodes accepted it.
+    pub fn put(&mut self, index: usize, key: &Key, data: Vec<u8>) -> usize {
+        let value = structures::Value {
+            data,
+            published_at: self.clock.unix_timestamp(),
+            publisher: self.nodes[index].node_id,
+            ttl: DEFAULT_TTL,
+        };
+
+        self.nodes[index]
+            .value_store
+            .store(key, &value)
+            .expect("Memory storage does not fail");
+
+        self.store_value(index, key, &value)
+    }
+
+    /// Returns the value stored under the key, checking the node's own store before the network.
+    pub fn get(&mut self, index: usize, key: &Key) -> Option<structures::Value> {
+        if let Ok(Some(value)) = self.nodes[index].value_store.retrieve(key) {
+            return Some(value);
+        }
+
+        match self.run_lookup(index, LookupKind::Value, key) {
+            LookupResult::Value(value) => Some(value),
+            LookupResult::Nodes(_) => None,
+        }
+    }
+
+    /// Runs a running node's periodic value maintenance on every online node: expired values
+    /// are purged, and values that are due to be republished or replicated are stored on the
+    /// nodes now closest to their key.
+    pub fn maintain_values(&mut self) {
+        for index in self.online_nodes() {
+            let node = &mut self.nodes[index];
+            let (_, mut due_values) = take_due_values(&mut node.value_store, &node.node_id)
+                .expect("Memory storage does not fail");
+
+            // The store is a hash map, so sort to keep the order of requests repeatable
+            due_values.sort_by_key(|(key, _)| *key);
+
+            for (key, value) in due_values {
+                self.store_value(index, &key, &value);
+            }
+        }
+    }
+
+    fn store_value(&mut self, index: usize, key: &Key, value: &structures::Value) -> usize {
+        let mut in_flight = HashMap::new();
+
+        for node in self.find_node(index, key) {
+            let transaction_id = self.send_request(
+                index,
+                &node.address,
+                structures::Request::Store(*key, value.clone()),
+                Purpose::Query,
+            );
+
+            in_flight.insert(transaction_id, node);
+        }
+
+        let mut stored_count = 0;
+
+        while !in_flight.is_empty() {
+            let (transaction_id, response) = self.next_completed();
+
+            let node = match in_flight.remove(&transaction_id) {
+                Some(node) => node,
+                None => continue,
+            };
+
+            if response.is_some() {
+                stored_count += 1;
+            } else {
+                self.nodes[index].peer_manager.record_failure(&node.node_id);
+            }
+        }
+
+        stored_count
+    }
+
+    /// Drives a `Lookup` the same way `lookup::run_lookup` does, but over the virtual network.
+    fn run_lookup(&mut self, index: usize, kind: LookupKind, target_id: &NodeId) -> LookupResult {
+        let node = &mut self.nodes[index];
+        node.peer_manager.touch_bucket(target_id);
+
+        let initial_nodes = node
+            .peer_manager
+            .nearby_peers(target_id)
+            .iter()
+            .map(|peer| structures::FoundNode {
+                address: peer.address,
+                node_id: peer.node_id,
+            })
+            .collect();
+
+        let mut lookup = Lookup::new(
+            &node.node_id,
+            target_id,
+            initial_nodes,
+            self.config.parameters,
+        );
+        let mut in_flight = HashMap::new();
+
+        loop {
+            for node in lookup.next_queries() {
+                let request = match kind {
+                    LookupKind::Node => structures::Request::FindNode(*target_id),
+                    LookupKind::Value => structures::Request::FindValue(*target_id),
+                };
+
+                let transaction_id =
+                    self.send_request(index, &node.address, request, Purpose::Query);
+
+                in_flight.insert(transaction_id, node);
+            }
+
+            if in_flight.is_empty() || lookup.is_finished() {
+                break;
+            }
+
+            let (transaction_id, response) = self.next_completed();
+
+            // Queries left over from an earlier operation are ignored
+            let node = match in_flight.remove(&transaction_id) {
+                Some(node) => node,
+                None => continue,
+            };
+
+            let response = match response {
+                Some(response) => response,
+                None => {
+                    self.nodes[index].peer_manager.record_failure(&node.node_id);
+                    lookup.on_failure(&node.node_id);
+                    continue;
+                }
+            };
+
+            match (kind, response.message) {
+                (
+                    LookupKind::Node,
+                    structures::Message::Response(structures::Response::FindNode(nodes)),
+                )
+                | (
+                    LookupKind::Value,
+                    structures::Message::Response(structures::Response::FindValue(
+                        structures::FoundValue::Nodes(nodes),
+                    )),
+                ) => lookup.on_response(&node.node_id, nodes),
+                (
+                    LookupKind::Value,
+                    structures::Message::Response(structures::Response::FindValue(
+                        structures::FoundValue::Value(value),
+                    )),
+                ) => return LookupResult::Value(value),
+                _ => lookup.on_failure(&node.node_id),
+            }
+        }
+
+        LookupResult::Nodes(lookup.closest())
+    }
+
+    /// Runs the network until a query completes or times out. Callers only wait while they have
+    /// queries in flight, and every query has a timeout queued, so there is always an event.
+    fn next_completed(&mut self) -> (TransactionId, Option<structures::Packet>) {
+        loop {
+            if let Some(completed) = self.completed.pop_front() {
+                return completed;
+            }
+
+            assert!(
+                self.step(),
+                "Simulation ran out of events while waiting for a query"
+            );
+        }
+    }
+
+    fn step(&mut self) -> bool {
+        let ((time, _), event) = match self.events.pop_first() {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        self.set_time(time);
+
+        match event {
+            Event::Deliver { from, to, packet } => self.deliver(from, to, packet),
+            Event::Timeout(transaction_id) => self.time_out(&transaction_id),
+        }
+
+        true
+    }
+
+    fn set_time(&mut self, time: Duration) {
+        self.clock.advance(time - self.now);
+        self.now = time;
+    }
+
+    fn schedule(&mut self, delay: Duration, event: Event) {
+        // Events due at the same time run in the order they were scheduled
+        self.events
+            .insert((self.now + delay, self.next_event), event);
+        self.next_event += 1;
+    }
+
+    fn send_request(
+        &mut self,
+        index: usize,
+        address: &SocketAddr,
+        request: structures::Request,
+        purpose: Purpose,
+    ) -> TransactionId {
+        let transaction_id = TransactionId::random_from(&mut self.rng);
+
+        self.pending.insert(
+            transaction_id,
+            PendingRequest {
+                address: *address,
+                purpose,
+                requester: index,
+            },
+        );
+        self.schedule(self.config.request_timeout, Event::Timeout(transaction_id));
+
+        let packet = structures::Packet {
+            node_id: self.nodes[index].node_id,
+            message: structures::Message::Request(request),
+            transaction_id,
+        };
+
+        self.send(index, address, packet);
+
+        transaction_id
+    }
+
+    fn send(&mut self, index: usize, address: &SocketAddr, packet: structures::Packet) {
+        if !self.nodes[index].online {
+            return;
+        }
+
+        self.stats.messages_sent += 1;
+
+        if self.rng.gen_bool(self.config.loss_rate) {
+            self.stats.messages_lost += 1;
+            return;
+        }
+
+        let latency = self
+            .rng
+            .gen_range(self.config.min_latency..=self.config.max_latency);
+
+        self.schedule(
+            latency,
+            Event::Deliver {
+                from: self.nodes[index].address,
+                to: *address,
+                packet,
+            },
+        );
+    }
+
+    /// Handles a message arriving at a node the way `messages::process_incoming_requests` does.
+    fn deliver(&mut self, from: SocketAddr, to: SocketAddr, packet: structures::Packet) {
+        let index = match self.addresses.get(&to) {
+            Some(index) if self.nodes[*index].online => *index,
+            _ => return,
+        };
+
+        let request = match packet.message {
+            structures::Message::Request(request) => request,
+            structures::Message::Response(_) => {
+                // Only responses to requests we are waiting on are accepted
+                let is_expected = self
+                    .pending
+                    .get(&packet.transaction_id)
+                    .is_some_and(|pending| pending.requester == index && pending.address == from);
+
+                if !is_expected {
+                    return;
+                }
+
+                let transaction_id = packet.transaction_id;
+                let pending = self.pending.remove(&transaction_id);
+
+                self.add_peer(index, &from, &packet.node_id);
+
+                match pending.map(|pending| pending.purpose) {
+                    Some(Purpose::Query) => {
+                        self.completed.push_back((transaction_id, Some(packet)))
+                    }
+                    Some(Purpose::EvictionCheck(stale_node_id)) => self.nodes[index]
+                        .peer_manager
+                        .resolve_eviction(&stale_node_id, true),
+                    None => {}
+                }
+
+                return;
+            }
+        };
+
+        self.add_peer(index, &from, &packet.node_id);
+
+        let node = &mut self.nodes[index];
+        let response = structures::Packet {
+            node_id: node.node_id,
+            transaction_id: packet.transaction_id,
+            message: structures::Message::Response(respond(
+                &request,
+                &node.peer_manager,
+                &mut node.value_store,
+            )),
+        };
+
+        self.send(index, &from, response);
+    }
+
+    fn time_out(&mut self, transaction_id: &TransactionId) {
+        let pending = match self.pending.remove(transaction_id) {
+            Some(pending) => pending,
+            None => return,
+        };
+
+        self.stats.requests_timed_out += 1;
+
+        match pending.purpose {
+            Purpose::Query => self.completed.push_back((*transaction_id, None)),
+            Purpose::EvictionCheck(stale_node_id) => self.nodes[pending.requester]
+                .peer_manager
+                .resolve_eviction(&stale_node_id, false),
+        }
+    }
+
+    /// Records contact with a peer, pinging the least recently seen peer of a full bucket.
+    fn add_peer(&mut self, index: usize, address: &SocketAddr, node_id: &NodeId) {
+        if let Ok(PeerStatus::Cached {
+            stale_peer: Some(stale_peer),
+            ..
+        }) = self.nodes[index].peer_manager.add_peer(address, node_id)
+        {
+            self.send_request(
+                index,
+                &stale_peer.address,
+                structures::Request::Ping,
+                Purpose::EvictionCheck(stale_peer.node_id),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::values::{REPLICATE_INTERVAL, REPUBLISH_INTERVAL};
+
+    /// The online nodes closest to the target, other than the node looking it up.
+    fn closest_online_nodes(
+        simulation: &Simulation,
+        index: usize,
+        target_id: &NodeId,
+    ) -> Vec<NodeId> {
+        let mut node_ids: Vec<NodeId> = simulation
+            .online_nodes()
+            .into_iter()
+            .filter(|online_index| *online_index != index)
+            .map(|online_index| simulation.node_id(online_index))
+            .collect();
+
+        node_ids.sort_by_key(|node_id| *node_id ^ *target_id);
+        node_ids.truncate(simulation.config.parameters.bucket_size);
+
+        node_ids
+    }
+
+    fn count_offline_peers(simulation: &Simulation) -> usize {
+        let offline_ids: Vec<NodeId> = (0..simulation.nodes.len())
+            .filter(|index| !simulation.is_online(*index))
+            .map(|index| simulation.node_id(index))
+            .collect();
+
+        simulation
+            .online_nodes()
+            .into_iter()
+            .flat_map(|index| simulation.peers(index))
+            .filter(|peer| offline_ids.contains(&peer.node_id))
+            .count()
+    }
+
+    #[test]
+    fn test_lookups_converge_on_closest_nodes() {
+        let mut simulation = Simulation::new(SimulationConfig {
+            node_count: 200,
+            ..SimulationConfig::default()
+        })
+        .unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for _ in 0..20 {
+            let index = rng.gen_range(0..200);
+            let target_id = NodeId::random_from(&mut rng);
+
+            let found: Vec<NodeId> = simulation
+                .find_node(index, &target_id)
+                .iter()
+                .map(|node| node.node_id)
+                .collect();
+
+            assert_eq!(found, closest_online_nodes(&simulation, index, &target_id));
+        }
+    }
+
+    #[test]
+    fn test_offline_peers_are_evicted() {
+        // Small buckets fill up quickly, so new contacts trigger eviction checks
+        let mut simulation = Simulation::new(SimulationConfig {
+            parameters: LookupParameters {
+                alpha: 3,
+                bucket_size: 4,
+            },
+            ..SimulationConfig::default()
+        })
+        .unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for index in 0..20 {
+            simulation.set_online(index, false);
+        }
+
+        let offline_before = count_offline_peers(&simulation);
+        assert!(offline_before > 0);
+
+        for _ in 0..20 {
+            for index in simulation.online_nodes() {
+                simulation.find_node(index, &NodeId::random_from(&mut rng));
+            }
+
+            simulation.advance(simulation.config.request_timeout);
+        }
+
+        // Peers nobody runs into stay put, but most are found out and replaced
+        assert!(count_offline_peers(&simulation) * 2 < offline_before);
+    }
+
+    #[test]
+    fn test_replicated_values_survive_churn() {
+        let mut simulation = Simulation::new(SimulationConfig {
+            loss_rate: 0.05,
+            ..SimulationConfig::default()
+        })
+        .unwrap();
+        let key = Key::random_from(&mut StdRng::seed_from_u64(1));
+
+        assert!(simulation.put(0, &key, b"value".to_vec()) > 0);
+
+        // Values that were just stored are not due to go out again yet
+        let messages_sent = simulation.stats().messages_sent;
+        simulation.maintain_values();
+        assert_eq!(simulation.stats().messages_sent, messages_sent);
+
+        // Nodes leave for good, so the value only survives by moving to the new closest nodes
+        for _ in 0..5 {
+            simulation.churn(0.2, 0.0);
+            simulation.advance(Duration::from_secs(REPLICATE_INTERVAL));
+            simulation.maintain_values();
+        }
+
+        for index in simulation.online_nodes() {
+            assert_eq!(simulation.get(index, &key).unwrap().data, b"value");
+        }
+
+        let holders = closest_online_nodes(&simulation, usize::MAX, &key)
+            .iter()
+            .filter(|node_id| {
+                let index = (0..simulation.nodes.len())
+                    .find(|index| simulation.node_id(*index) == **node_id)
+                    .unwrap();
+
+                simulation.has_value(index, &key)
+            })
+            .count();
+
+        assert!(holders * 2 > simulation.config.parameters.bucket_size);
+    }
+
+    #[test]
+    fn test_values_expire_after_their_ttl() {
+        let mut simulation = Simulation::new(SimulationConfig::default()).unwrap();
+        let key = Key::random_from(&mut StdRng::seed_from_u64(1));
+
+        simulation.put(0, &key, b"value".to_vec());
+        assert!(simulation.get(50, &key).is_some());
+
+        simulation.advance(Duration::from_secs(DEFAULT_TTL));
+
+        assert!(simulation.get(50, &key).is_none());
+    }
+
+    #[test]
+    fn test_publisher_republishes_values_before_they_expire() {
+        let mut simulation = Simulation::new(SimulationConfig::default()).unwrap();
+        let key = Key::random_from(&mut StdRng::seed_from_u64(1));
+
+        simulation.put(0, &key, b"value".to_vec());
+        let published_at = simulation.get(50, &key).unwrap().published_at;
+
+        // Maintenance runs for longer than the value lives unless it is republished
+        for _ in 0..=DEFAULT_TTL / REPLICATE_INTERVAL {
+            simulation.advance(Duration::from_secs(REPLICATE_INTERVAL));
+            simulation.maintain_values();
+        }
+
+        let value = simulation.get(50, &key).unwrap();
+        assert_eq!(value.data, b"value");
+        assert!(value.published_at >= published_at + REPUBLISH_INTERVAL);
+    }
+
+    #[test]
+    fn test_same_seed_replays_identically() {
+        let run = |seed| {
+            let mut simulation = Simulation::new(SimulationConfig {
+                seed,
+                node_count: 50,
+                loss_rate: 0.1,
+                ..SimulationConfig::default()
+            })
+            .unwrap();
+
+            simulation.churn(0.2, 0.2);
+
+            let index = simulation.online_nodes()[0];
+            let key = Key::random_from(&mut StdRng::seed_from_u64(seed));
+            simulation.put(index, &key, b"value".to_vec());
+
+            let peer_ids: Vec<NodeId> = simulation
+                .peers(index)
+                .iter()
+                .map(|peer| peer.node_id)
+                .collect();
+
+            (simulation.stats(), simulation.now(), peer_ids)
+        };
+
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+}
@@ -0,0 +1,355 @@
+//! Converts a node's state file to and from JSON or TOML, so operators can inspect, diff and
+//! hand-edit routing tables and values, or seed them on other machines.
+
+use crate::node_id::{Key, NodeId};
+use crate::node_state::{new_node_state, StatePath, StateStore};
+use crate::peers::ID_BITS;
+use crate::structures::{NodeState, Peer, StoredValue, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StateFormat {
+    Json,
+    Toml,
+}
+
+impl StateFormat {
+    /// Picks the format from a `.json` or `.toml` file extension.
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        match Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some("json") => Ok(StateFormat::Json),
+            Some("toml") => Ok(StateFormat::Toml),
+            _ => Err(format!(
+                "Unknown state format for \"{}\", expected a .json or .toml file",
+                path
+            )),
+        }
+    }
+}
+
+/// The state as operators see it. Peers are listed without their buckets, which are worked out
+/// again from the node ID on import, and the node ID may be left out to keep the existing one.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+struct ExportedState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    node_id: Option<NodeId>,
+    #[serde(default)]
+    peers: Vec<ExportedPeer>,
+    #[serde(default)]
+    values: Vec<ExportedValue>,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+struct ExportedPeer {
+    address: SocketAddr,
+    #[serde(default)]
+    first_seen: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_seen: Option<u64>,
+    node_id: NodeId,
+}
+
+/// A stored value. Text values are written as `value` and anything else as `value_hex`.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+struct ExportedValue {
+    key: Key,
+    published_at: u64,
+    publisher: NodeId,
+    #[serde(default)]
+    stored_at: u64,
+    ttl: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_hex: Option<String>,
+}
+
+/// Writes a node's state out as JSON or TOML, picked by the export file's extension. The state
+/// is locked while it is read, so this fails if a node is running on it.
+pub fn export_state(state_path: &StatePath, export_file: &str) -> Result<(), String> {
+    let format = StateFormat::from_path(export_file)?;
+
+    if !state_path.exists() {
+        return Err(format!("No node state found at \"{}\"", state_path));
+    }
+
+    let node_state = StateStore::open(state_path)?.load()?.into_node_state()?;
+
+    let contents = serialize_state(&export(&node_state), format)?;
+
+    fs::write(export_file, contents)
+        .map_err(|error| format!("Failed to write \"{}\": {}", export_file, error))
+}
+
+/// Replaces a node's state with the peers and values in a JSON or TOML file. Without a node ID in
+/// the file, the node's current ID is kept.
+pub fn import_state(import_file: &str, state_path: &StatePath) -> Result<(), String> {
+    let format = StateFormat::from_path(import_file)?;
+
+    let contents = fs::read_to_string(import_file)
+        .map_err(|error| format!("Failed to read \"{}\": {}", import_file, error))?;
+
+    let exported_state = deserialize_state(&contents, format)
+        .map_err(|error| format!("Failed to parse \"{}\": {}", import_file, error))?;
+
+    let had_state = state_path.exists();
+    let state_store = StateStore::open(state_path)?;
+
+    let node_id = match exported_state.node_id {
+        Some(node_id) => node_id,
+        None if had_state => state_store.load()?.node_id,
+        None => new_node_state().node_id,
+    };
+
+    state_store.save(&import(node_id, exported_state)?)
+}
+
+fn serialize_state(exported_state: &ExportedState, format: StateFormat) -> Result<String, String> {
+    match format {
+        StateFormat::Json => serde_json::to_string_pretty(exported_state)
+            .map(|json| json + "\n")
+            .map_err(|error| format!("Failed to serialize state as JSON: {}", error)),
+        StateFormat::Toml => toml::to_string(exported_state)
+            .map_err(|error| format!("Failed to serialize state as TOML: {}", error)),
+    }
+}
+
+fn deserialize_state(contents: &str, format: StateFormat) -> Result<ExportedState, String> {
+    match format {
+        StateFormat::Json => serde_json::from_str(contents).map_err(|error| error.to_string()),
+        StateFormat::Toml => toml::from_str(contents).map_err(|error| error.to_string()),
+    }
+}
+
+fn export(node_state: &NodeState) -> ExportedState {
+    let peers = node_state
+        .buckets
+        .iter()
+        .flatten()
+        .map(|peer| ExportedPeer {
+            address: peer.address,
+            first_seen: peer.first_seen,
+            last_seen: peer.last_seen,
+            node_id: peer.node_id,
+        })
+        .collect();
+
+    let mut values: Vec<ExportedValue> = node_state
+        .values
+        .iter()
+        .map(|(key, stored_value)| {
+            let value = &stored_value.value;
+            let text = String::from_utf8(value.data.clone()).ok();
+
+            ExportedValue {
+                key: *key,
+                published_at: value.published_at,
+                publisher: value.publisher,
+                stored_at: stored_value.stored_at,
+                ttl: value.ttl,
+                value_hex: text.is_none().then(|| to_hex(&value.data)),
+                value: text,
+            }
+        })
+        .collect();
+
+    // Sorted so exports of the same state can be diffed
+    values.sort_by_key(|value| value.key.to_string());
+
+    ExportedState {
+        node_id: Some(node_state.node_id),
+        peers,
+        values,
+    }
+}
+
+fn import(node_id: NodeId, exported_state: ExportedState) -> Result<NodeState, String> {
+    let mut buckets = vec![VecDeque::new(); ID_BITS];
+
+    for peer in exported_state.peers {
+        let bucket_index = (node_id ^ peer.node_id)
+            .bucket_index()
+            .ok_or_else(|| format!("Peer {} has the node's own ID", peer.node_id))?;
+
+        buckets[bucket_index].push_back(Peer {
+            active: false,
+            address: peer.address,
+            failed_requests: 0,
+            first_seen: peer.first_seen,
+            last_seen: peer.last_seen,
+            node_id: peer.node_id,
+            rtt: Default::default(),
+        });
+    }
+
+    let mut values = HashMap::new();
+
+    for value in exported_state.values {
+        let data = match (value.value, value.value_hex) {
+            (Some(text), None) => text.into_bytes(),
+            (None, Some(hex)) => from_hex(&hex)
+                .map_err(|error| format!("Invalid value_hex for key {}: {}", value.key, error))?,
+            _ => {
+                return Err(format!(
+                    "Value for key {} needs exactly one of value or value_hex",
+                    value.key
+                ));
+            }
+        };
+
+        values.insert(
+            value.key,
+            StoredValue {
+                stored_at: value.stored_at,
+                value: Value {
+                    data,
+                    published_at: value.published_at,
+                    publisher: value.publisher,
+                    ttl: value.ttl,
+                },
+            },
+        );
+    }
+
+    Ok(NodeState {
+        buckets,
+        node_id,
+        values,
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || !hex.chars().all(|character| character.is_ascii_hexdigit()) {
+        return Err("expected an even number of hex characters".to_string());
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&hex[index..index + 2], 16).map_err(|error| error.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn node_state() -> NodeState {
+        let mut node_state = new_node_state();
+        let peer_id = NodeId::random();
+        let bucket_index = (node_state.node_id ^ peer_id).bucket_index().unwrap();
+
+        node_state.buckets[bucket_index].push_back(Peer {
+            active: false,
+            address: "127.0.0.1:16600".parse().unwrap(),
+            failed_requests: 0,
+            first_seen: 1_700_000_000,
+            last_seen: None,
+            node_id: peer_id,
+            rtt: Default::default(),
+        });
+
+        for (data, ttl) in [(b"text".to_vec(), 60), (vec![0xff, 0x00, 0x10], 120)] {
+            node_state.values.insert(
+                Key::random(),
+                StoredValue {
+                    stored_at: 1_700_000_010,
+                    value: Value {
+                        data,
+                        published_at: 1_700_000_000,
+                        publisher: node_state.node_id,
+                        ttl,
+                    },
+                },
+            );
+        }
+
+        node_state
+    }
+
+    #[test]
+    fn test_state_round_trips_through_json_and_toml() {
+        let node_state = node_state();
+
+        for format in [StateFormat::Json, StateFormat::Toml] {
+            let contents = serialize_state(&export(&node_state), format).unwrap();
+            let exported_state = deserialize_state(&contents, format).unwrap();
+
+            assert_eq!(
+                import(node_state.node_id, exported_state).unwrap(),
+                node_state
+            );
+        }
+    }
+
+    #[test]
+    fn test_import_keeps_node_id_when_left_out() {
+        let directory = env::temp_dir().join(format!("state-import-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let state_path = StatePath::DataDir(directory.join("data").to_str().unwrap().to_string());
+        let import_file = directory.join("seed.toml");
+        let import_file = import_file.to_str().unwrap();
+
+        let node_id = StateStore::open(&state_path)
+            .unwrap()
+            .load()
+            .unwrap()
+            .node_id;
+
+        fs::write(
+            import_file,
+            r#"
+[[peers]]
+address = "10.0.0.1:16600"
+node_id = "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3"
+
+[[values]]
+key = "62cdb7020ff920e5aa642c3d4066950dd1f01f4d"
+publisher = "a94a8fe5ccb19ba61c4c0873d391e987982fbbd3"
+published_at = 1700000000
+ttl = 3600
+value = "hello"
+"#,
+        )
+        .unwrap();
+
+        import_state(import_file, &state_path).unwrap();
+
+        let imported = StateStore::open(&state_path)
+            .unwrap()
+            .load()
+            .unwrap()
+            .into_node_state()
+            .unwrap();
+        assert_eq!(imported.node_id, node_id);
+        assert_eq!(imported.buckets.iter().flatten().count(), 1);
+        assert_eq!(imported.values.len(), 1);
+
+        let export_file = directory.join("export.json");
+        let export_file = export_file.to_str().unwrap();
+        export_state(&state_path, export_file).unwrap();
+        assert!(fs::read_to_string(export_file)
+            .unwrap()
+            .contains("\"hello\""));
+
+        assert!(StateFormat::from_path("state.yaml").is_err());
+    }
+}
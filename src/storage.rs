@@ -0,0 +1,823 @@
+//! Where a node's values are kept. `MemoryStorage` holds them in a map, while `LogStorage` appends
+//! them to a log on disk and keeps only an index in memory, so a node can hold more values than
+//! fit in memory and keeps them through a crash.
+
+use crate::node_id::{with_hex_ids, Key, NodeId};
+use crate::structures::StoredValue;
+use crate::utilities::sync_parent_directory;
+use crate::{debug_log, error_log};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::os::unix::fs::FileExt;
+
+/// Marks a file as a values log.
+const LOG_MAGIC: &[u8; 4] = b"KDHL";
+/// Version 1 logs wrote IDs as hex strings, and are upgraded when opened.
+const LOG_VERSION: u32 = 2;
+const LOG_HEADER_LENGTH: u64 = 8;
+/// Each record starts with its payload length and a CRC-32 of the payload.
+const RECORD_HEADER_LENGTH: u64 = 8;
+
+/// What is known about a stored value without reading its data.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ValueMetadata {
+    pub published_at: u64,
+    pub publisher: NodeId,
+    pub stored_at: u64,
+    pub ttl: u64,
+}
+
+impl ValueMetadata {
+    pub fn expires_at(&self) -> u64 {
+        self.published_at.saturating_add(self.ttl)
+    }
+}
+
+impl From<&StoredValue> for ValueMetadata {
+    fn from(stored_value: &StoredValue) -> Self {
+        Self {
+            published_at: stored_value.value.published_at,
+            publisher: stored_value.value.publisher,
+            stored_at: stored_value.stored_at,
+            ttl: stored_value.value.ttl,
+        }
+    }
+}
+
+/// A key-value store for a node's values. Anything that does not need a value's data works from
+/// its metadata, so a backend only has to read data when a value is asked for.
+pub trait Storage: Send {
+    fn get(&self, key: &Key) -> Result<Option<StoredValue>, String>;
+
+    fn metadata(&self, key: &Key) -> Option<ValueMetadata>;
+
+    /// The metadata of every stored value.
+    fn entries(&self) -> Vec<(Key, ValueMetadata)>;
+
+    fn put(&mut self, key: &Key, stored_value: &StoredValue) -> Result<(), String>;
+
+    fn remove(&mut self, key: &Key) -> Result<(), String>;
+
+    fn len(&self) -> usize;
+
+    /// Makes the changes so far durable. Changes are not synced as they are made, so that many
+    /// can share one sync.
+    fn sync(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Plans reclaiming the space held by replaced and removed values, or returns `None` if it is
+    /// not worth it. The plan is carried out by `Compaction::copy` without access to the storage,
+    /// so it can run while the storage is in use.
+    fn start_compaction(&self) -> Result<Option<Compaction>, String> {
+        Ok(None)
+    }
+
+    /// Switches over to a log copied by `Compaction::copy`, bringing over any changes made since
+    /// the compaction started.
+    fn finish_compaction(&mut self, _compacted_log: CompactedLog) -> Result<(), String> {
+        Err("This storage does not compact".to_string())
+    }
+
+    /// Compacts the storage in one go. Returns whether anything was done.
+    fn compact(&mut self) -> Result<bool, String> {
+        match self.start_compaction()? {
+            Some(compaction) => {
+                let compacted_log = compaction.copy()?;
+                self.finish_compaction(compacted_log)?;
+
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+pub struct MemoryStorage {
+    values: HashMap<Key, StoredValue>,
+}
+
+impl MemoryStorage {
+    pub fn new(values: HashMap<Key, StoredValue>) -> Self {
+        Self { values }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &Key) -> Result<Option<StoredValue>, String> {
+        Ok(self.values.get(key).cloned())
+    }
+
+    fn metadata(&self, key: &Key) -> Option<ValueMetadata> {
+        self.values.get(key).map(ValueMetadata::from)
+    }
+
+    fn entries(&self) -> Vec<(Key, ValueMetadata)> {
+        self.values
+            .iter()
+            .map(|(key, stored_value)| (*key, ValueMetadata::from(stored_value)))
+            .collect()
+    }
+
+    fn put(&mut self, key: &Key, stored_value: &StoredValue) -> Result<(), String> {
+        self.values.insert(*key, stored_value.clone());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &Key) -> Result<(), String> {
+        self.values.remove(key);
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.values.len()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Put(Key, StoredValue),
+    Remove(Key),
+}
+
+/// Where the latest record for a key is in the log.
+struct IndexEntry {
+    length: u64,
+    metadata: ValueMetadata,
+    offset: u64,
+}
+
+/// Values in an append-only log. Every change is appended as a checksummed record and the
+/// latest record for each key is indexed in memory. Once replaced and removed records take up
+/// as much space as the live ones, `compact` rewrites the log with only the live records.
+pub struct LogStorage {
+    file: File,
+    /// Bytes taken by records that have been replaced or removed.
+    garbage_length: u64,
+    index: HashMap<Key, IndexEntry>,
+    length: u64,
+    path: String,
+}
+
+impl LogStorage {
+    /// Opens the log, creating it if needed. A record cut short or corrupted by a crash ends the
+    /// log, so it is cut off along with anything after it.
+    pub fn open(path: &str) -> Result<Self, String> {
+        // Left behind by a compaction that did not finish, the log itself is still complete
+        let _ = fs::remove_file(compaction_path(path));
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|error| format!("Failed to open values log \"{}\": {}", path, error))?;
+
+        let mut log_storage = Self {
+            file,
+            garbage_length: 0,
+            index: HashMap::new(),
+            length: LOG_HEADER_LENGTH,
+            path: path.to_string(),
+        };
+
+        let file_length = log_storage
+            .file
+            .metadata()
+            .map_err(|error| format!("Failed to read values log \"{}\": {}", path, error))?
+            .len();
+
+        if file_length == 0 {
+            log_storage.write_at(&log_header(LOG_VERSION), 0)?;
+            log_storage.sync()?;
+            sync_parent_directory(path)?;
+
+            return Ok(log_storage);
+        }
+
+        let mut header = [0; LOG_HEADER_LENGTH as usize];
+
+        if log_storage.file.read_exact_at(&mut header, 0).is_ok() && header[..] == log_header(1)[..]
+        {
+            drop(log_storage);
+            upgrade_log(path)?;
+
+            return Self::open(path);
+        }
+
+        log_storage.recover(file_length)?;
+
+        Ok(log_storage)
+    }
+
+    /// Rebuilds the index from the records in the log.
+    fn recover(&mut self, file_length: u64) -> Result<(), String> {
+        let mut reader = BufReader::new(&self.file);
+        let mut header = [0; LOG_HEADER_LENGTH as usize];
+
+        if reader.read_exact(&mut header).is_err() || header[..] != log_header(LOG_VERSION)[..] {
+            return Err(format!(
+                "\"{}\" is not a values log this version can read",
+                self.path
+            ));
+        }
+
+        let mut offset = LOG_HEADER_LENGTH;
+
+        while let Some((record, length)) = read_record(&mut reader)? {
+            match record {
+                Record::Put(key, stored_value) => {
+                    let entry = IndexEntry {
+                        length,
+                        metadata: ValueMetadata::from(&stored_value),
+                        offset,
+                    };
+
+                    if let Some(replaced) = self.index.insert(key, entry) {
+                        self.garbage_length += replaced.length;
+                    }
+                }
+                Record::Remove(key) => {
+                    self.garbage_length += length;
+
+                    if let Some(removed) = self.index.remove(&key) {
+                        self.garbage_length += removed.length;
+                    }
+                }
+            }
+
+            offset += length;
+        }
+
+        self.length = offset;
+
+        if offset < file_length {
+            error_log(format!(
+                "Discarding {} bytes of unreadable records at the end of \"{}\"",
+                file_length - offset,
+                self.path
+            ));
+
+            self.file
+                .set_len(offset)
+                .and_then(|_| self.file.sync_all())
+                .map_err(|error| {
+                    format!("Failed to truncate values log \"{}\": {}", self.path, error)
+                })?;
+        }
+
+        Ok(())
+    }
+
+    fn append(&mut self, record: &Record) -> Result<u64, String> {
+        let payload = bincode::serialize(record)
+            .map_err(|error| format!("Failed to serialize value: {}", error))?;
+
+        let mut bytes = Vec::with_capacity(RECORD_HEADER_LENGTH as usize + payload.len());
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+
+        // A failed write leaves the end of the log where it was, so the next one overwrites it
+        self.write_at(&bytes, self.length)?;
+        self.length += bytes.len() as u64;
+
+        Ok(bytes.len() as u64)
+    }
+
+    fn write_at(&self, bytes: &[u8], offset: u64) -> Result<(), String> {
+        self.file
+            .write_all_at(bytes, offset)
+            .map_err(|error| format!("Failed to write values log \"{}\": {}", self.path, error))
+    }
+
+    fn live_length(&self) -> u64 {
+        self.length - LOG_HEADER_LENGTH - self.garbage_length
+    }
+}
+
+impl Storage for LogStorage {
+    fn get(&self, key: &Key) -> Result<Option<StoredValue>, String> {
+        let entry = match self.index.get(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        let mut payload = vec![0; (entry.length - RECORD_HEADER_LENGTH) as usize];
+        self.file
+            .read_exact_at(&mut payload, entry.offset + RECORD_HEADER_LENGTH)
+            .map_err(|error| format!("Failed to read values log \"{}\": {}", self.path, error))?;
+
+        match bincode::deserialize(&payload) {
+            Ok(Record::Put(_, stored_value)) => Ok(Some(stored_value)),
+            _ => Err(format!(
+                "Values log \"{}\" has an invalid record for {}",
+                self.path, key
+            )),
+        }
+    }
+
+    fn metadata(&self, key: &Key) -> Option<ValueMetadata> {
+        self.index.get(key).map(|entry| entry.metadata)
+    }
+
+    fn entries(&self) -> Vec<(Key, ValueMetadata)> {
+        self.index
+            .iter()
+            .map(|(key, entry)| (*key, entry.metadata))
+            .collect()
+    }
+
+    fn put(&mut self, key: &Key, stored_value: &StoredValue) -> Result<(), String> {
+        let offset = self.length;
+        let length = self.append(&Record::Put(*key, stored_value.clone()))?;
+
+        let entry = IndexEntry {
+            length,
+            metadata: ValueMetadata::from(stored_value),
+            offset,
+        };
+
+        if let Some(replaced) = self.index.insert(*key, entry) {
+            self.garbage_length += replaced.length;
+        }
+
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &Key) -> Result<(), String> {
+        if !self.index.contains_key(key) {
+            return Ok(());
+        }
+
+        let length = self.append(&Record::Remove(*key))?;
+
+        if let Some(removed) = self.index.remove(key) {
+            self.garbage_length += removed.length + length;
+        }
+
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn sync(&self) -> Result<(), String> {
+        self.file
+            .sync_data()
+            .map_err(|error| format!("Failed to sync values log \"{}\": {}", self.path, error))
+    }
+
+    fn start_compaction(&self) -> Result<Option<Compaction>, String> {
+        if self.garbage_length == 0 || self.garbage_length < self.live_length() {
+            return Ok(None);
+        }
+
+        let mut records: Vec<(u64, u64)> = self
+            .index
+            .values()
+            .map(|entry| (entry.offset, entry.length))
+            .collect();
+        // Copied in log order, so the old log is read sequentially
+        records.sort_unstable();
+
+        let file = self
+            .file
+            .try_clone()
+            .map_err(|error| format!("Failed to open values log \"{}\": {}", self.path, error))?;
+
+        Ok(Some(Compaction {
+            file,
+            length: self.length,
+            path: self.path.clone(),
+            records,
+        }))
+    }
+
+    /// Records appended while the live ones were copied are copied after them, then the
+    /// compacted log is renamed over the old one, so a crash at any point leaves a complete log.
+    fn finish_compaction(&mut self, compacted_log: CompactedLog) -> Result<(), String> {
+        let compaction_path = compaction_path(&self.path);
+        let previous_length = self.length;
+        let appended_length = self.length - compacted_log.copied_from_length;
+
+        let copied: Result<(), std::io::Error> = (|| {
+            let mut appended = vec![0; appended_length as usize];
+            self.file
+                .read_exact_at(&mut appended, compacted_log.copied_from_length)?;
+            compacted_log
+                .file
+                .write_all_at(&appended, compacted_log.length)?;
+            compacted_log.file.sync_data()
+        })();
+
+        if let Err(error) = copied {
+            let _ = fs::remove_file(&compaction_path);
+
+            return Err(format!(
+                "Failed to write compacted log \"{}\": {}",
+                compaction_path, error
+            ));
+        }
+
+        fs::rename(&compaction_path, &self.path).map_err(|error| {
+            format!("Failed to replace values log \"{}\": {}", self.path, error)
+        })?;
+
+        for entry in self.index.values_mut() {
+            entry.offset = if entry.offset >= compacted_log.copied_from_length {
+                entry.offset - compacted_log.copied_from_length + compacted_log.length
+            } else {
+                compacted_log.offsets[&entry.offset]
+            };
+        }
+
+        self.file = compacted_log.file;
+        self.length = compacted_log.length + appended_length;
+
+        // Records copied over but replaced or removed since are garbage again
+        let live_length: u64 = self.index.values().map(|entry| entry.length).sum();
+        self.garbage_length = self.length - LOG_HEADER_LENGTH - live_length;
+
+        debug_log(format!(
+            "Compacted values log \"{}\" from {} to {} bytes",
+            self.path, previous_length, self.length
+        ));
+
+        // The log has been replaced either way, so this is only checked once it is in use
+        sync_parent_directory(&self.path)
+    }
+}
+
+/// The live records of a values log as they were when a compaction started.
+pub struct Compaction {
+    /// The log being compacted, which stays readable after the compacted log replaces it.
+    file: File,
+    length: u64,
+    path: String,
+    /// The offset and length of each live record.
+    records: Vec<(u64, u64)>,
+}
+
+impl Compaction {
+    /// Copies the live records to a new log next to the old one.
+    pub fn copy(self) -> Result<CompactedLog, String> {
+        let compaction_path = compaction_path(&self.path);
+
+        let compacted_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&compaction_path)
+            .map_err(|error| {
+                format!(
+                    "Failed to create compacted log \"{}\": {}",
+                    compaction_path, error
+                )
+            })?;
+
+        let mut writer = BufWriter::new(&compacted_file);
+        let mut offsets = HashMap::with_capacity(self.records.len());
+        let mut length = LOG_HEADER_LENGTH;
+
+        let copied: Result<(), std::io::Error> = (|| {
+            writer.write_all(&log_header(LOG_VERSION))?;
+
+            for (offset, record_length) in &self.records {
+                let mut record = vec![0; *record_length as usize];
+                self.file.read_exact_at(&mut record, *offset)?;
+                writer.write_all(&record)?;
+
+                offsets.insert(*offset, length);
+                length += record_length;
+            }
+
+            writer.flush()?;
+            compacted_file.sync_all()
+        })();
+
+        drop(writer);
+
+        if let Err(error) = copied {
+            let _ = fs::remove_file(&compaction_path);
+
+            return Err(format!(
+                "Failed to write compacted log \"{}\": {}",
+                compaction_path, error
+            ));
+        }
+
+        Ok(CompactedLog {
+            copied_from_length: self.length,
+            file: compacted_file,
+            length,
+            offsets,
+        })
+    }
+}
+
+/// A compacted copy of a values log, waiting to replace it.
+pub struct CompactedLog {
+    /// Where the old log ended when the copy started.
+    copied_from_length: u64,
+    file: File,
+    length: u64,
+    /// The new offset of each copied record, by its old offset.
+    offsets: HashMap<u64, u64>,
+}
+
+fn log_header(version: u32) -> Vec<u8> {
+    let mut header = LOG_MAGIC.to_vec();
+    header.extend_from_slice(&version.to_le_bytes());
+    header
+}
+
+/// Rewrites a version 1 log in the current format, keeping a copy of the original next to it.
+/// The original stays in place until the rewritten log is complete.
+fn upgrade_log(path: &str) -> Result<(), String> {
+    let file = File::open(path)
+        .map_err(|error| format!("Failed to open values log \"{}\": {}", path, error))?;
+    let mut reader = BufReader::new(file);
+    reader
+        .seek_relative(LOG_HEADER_LENGTH as i64)
+        .map_err(|error| format!("Failed to read values log \"{}\": {}", path, error))?;
+
+    let mut values = HashMap::new();
+
+    while let Some((record, _)) = with_hex_ids(|| read_record(&mut reader))? {
+        match record {
+            Record::Put(key, stored_value) => {
+                values.insert(key, stored_value);
+            }
+            Record::Remove(key) => {
+                values.remove(&key);
+            }
+        }
+    }
+
+    let upgrade_path = format!("{}.upgrade", path);
+    let _ = fs::remove_file(&upgrade_path);
+
+    let mut upgraded_log = LogStorage::open(&upgrade_path)?;
+
+    for (key, stored_value) in &values {
+        upgraded_log.put(key, stored_value)?;
+    }
+
+    upgraded_log.sync()?;
+    drop(upgraded_log);
+
+    let copy_path = format!("{}.v1", path);
+
+    fs::copy(path, &copy_path)
+        .map_err(|error| format!("Failed to keep a copy of \"{}\": {}", path, error))?;
+    fs::rename(&upgrade_path, path)
+        .map_err(|error| format!("Failed to replace values log \"{}\": {}", path, error))?;
+    sync_parent_directory(path)?;
+
+    debug_log(format!(
+        "Upgraded values log {} from format version 1 to {}, the original is kept as {}",
+        path, LOG_VERSION, copy_path
+    ));
+
+    Ok(())
+}
+
+fn compaction_path(path: &str) -> String {
+    format!("{}.compact", path)
+}
+
+/// Reads the next record and its length on disk. Returns `None` at the end of the log, or at a
+/// record that was cut short or does not match its checksum.
+fn read_record(reader: &mut impl Read) -> Result<Option<(Record, u64)>, String> {
+    let mut header = [0; RECORD_HEADER_LENGTH as usize];
+
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(format!("Failed to read values log: {}", error)),
+    }
+
+    let payload_length = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+    let mut payload = Vec::new();
+    reader
+        .take(payload_length as u64)
+        .read_to_end(&mut payload)
+        .map_err(|error| format!("Failed to read values log: {}", error))?;
+
+    if payload.len() != payload_length as usize || crc32fast::hash(&payload) != checksum {
+        return Ok(None);
+    }
+
+    Ok(bincode::deserialize(&payload)
+        .ok()
+        .map(|record| (record, RECORD_HEADER_LENGTH + payload_length as u64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::Value;
+    use std::env;
+    use std::path::Path;
+
+    fn log_path(name: &str) -> String {
+        let directory = env::temp_dir().join(format!("storage-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        directory.join("values.log").to_str().unwrap().to_string()
+    }
+
+    fn stored_value(data: &[u8], published_at: u64) -> StoredValue {
+        StoredValue {
+            stored_at: published_at,
+            value: Value {
+                data: data.to_vec(),
+                published_at,
+                publisher: NodeId::random(),
+                ttl: 60,
+            },
+        }
+    }
+
+    #[test]
+    fn test_log_storage_reopens_with_the_latest_values() {
+        let path = log_path("reopen");
+        let kept = Key::random();
+        let removed = Key::random();
+
+        let mut storage = LogStorage::open(&path).unwrap();
+        storage.put(&kept, &stored_value(b"first", 1)).unwrap();
+        storage.put(&kept, &stored_value(b"second", 2)).unwrap();
+        storage.put(&removed, &stored_value(b"gone", 1)).unwrap();
+        storage.remove(&removed).unwrap();
+        drop(storage);
+
+        let storage = LogStorage::open(&path).unwrap();
+        assert_eq!(storage.len(), 1);
+        assert_eq!(storage.get(&kept).unwrap().unwrap().value.data, b"second");
+        assert_eq!(storage.metadata(&kept).unwrap().published_at, 2);
+        assert!(storage.get(&removed).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_log_storage_upgrades_a_version_1_log() {
+        let path = log_path("version-1");
+        let kept = Key::random();
+        let removed = Key::random();
+
+        let mut contents = log_header(1);
+
+        for record in [
+            Record::Put(kept, stored_value(b"first", 1)),
+            Record::Put(removed, stored_value(b"gone", 1)),
+            Record::Put(kept, stored_value(b"second", 2)),
+            Record::Remove(removed),
+        ] {
+            let payload = with_hex_ids(|| bincode::serialize(&record)).unwrap();
+            contents.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            contents.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+            contents.extend_from_slice(&payload);
+        }
+
+        fs::write(&path, &contents).unwrap();
+
+        let storage = LogStorage::open(&path).unwrap();
+        assert_eq!(storage.len(), 1);
+        assert_eq!(storage.get(&kept).unwrap().unwrap().value.data, b"second");
+        assert_eq!(fs::read(format!("{}.v1", path)).unwrap(), contents);
+        drop(storage);
+
+        assert!(fs::read(&path)
+            .unwrap()
+            .starts_with(&log_header(LOG_VERSION)));
+        assert_eq!(LogStorage::open(&path).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_log_storage_discards_a_torn_record() {
+        let path = log_path("torn");
+        let key = Key::random();
+
+        let mut storage = LogStorage::open(&path).unwrap();
+        storage.put(&key, &stored_value(b"value", 1)).unwrap();
+        let good_length = storage.length;
+        storage
+            .put(&Key::random(), &stored_value(b"torn", 1))
+            .unwrap();
+        drop(storage);
+
+        // A crash part way through appending the second record
+        let contents = fs::read(&path).unwrap();
+        fs::write(&path, &contents[..contents.len() - 3]).unwrap();
+
+        let mut storage = LogStorage::open(&path).unwrap();
+        assert_eq!(storage.len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), good_length);
+
+        // New records go where the torn one was
+        storage.put(&key, &stored_value(b"after", 2)).unwrap();
+        drop(storage);
+        let storage = LogStorage::open(&path).unwrap();
+        assert_eq!(storage.get(&key).unwrap().unwrap().value.data, b"after");
+    }
+
+    #[test]
+    fn test_log_storage_compacts_replaced_values() {
+        let path = log_path("compact");
+        let keys: Vec<Key> = (0..5).map(|_| Key::random()).collect();
+
+        let mut storage = LogStorage::open(&path).unwrap();
+
+        for key in &keys {
+            storage.put(key, &stored_value(b"value", 1)).unwrap();
+        }
+
+        // Not enough garbage to be worth rewriting the log yet
+        storage.remove(&keys[4]).unwrap();
+        assert!(!storage.compact().unwrap());
+
+        for key in &keys[..4] {
+            storage.put(key, &stored_value(b"newer value", 2)).unwrap();
+        }
+
+        let length_before = fs::metadata(&path).unwrap().len();
+        assert!(storage.compact().unwrap());
+        assert!(fs::metadata(&path).unwrap().len() < length_before);
+        assert!(!Path::new(&compaction_path(&path)).exists());
+
+        storage.put(&keys[4], &stored_value(b"back", 3)).unwrap();
+        drop(storage);
+
+        let storage = LogStorage::open(&path).unwrap();
+        assert_eq!(storage.len(), 5);
+        assert_eq!(storage.garbage_length, 0);
+
+        for key in &keys[..4] {
+            assert_eq!(
+                storage.get(key).unwrap().unwrap().value.data,
+                b"newer value"
+            );
+        }
+    }
+
+    #[test]
+    fn test_log_storage_keeps_changes_made_during_compaction() {
+        let path = log_path("compact-concurrently");
+        let keys: Vec<Key> = (0..4).map(|_| Key::random()).collect();
+
+        let mut storage = LogStorage::open(&path).unwrap();
+
+        for key in &keys {
+            storage.put(key, &stored_value(b"older", 1)).unwrap();
+            storage.put(key, &stored_value(b"newer", 2)).unwrap();
+        }
+
+        let compaction = storage.start_compaction().unwrap().unwrap();
+
+        // Changes made while the live records are copied
+        storage.put(&keys[0], &stored_value(b"third", 3)).unwrap();
+        storage.remove(&keys[1]).unwrap();
+        let added = Key::random();
+        storage.put(&added, &stored_value(b"added", 3)).unwrap();
+
+        let compacted_log = compaction.copy().unwrap();
+        storage.finish_compaction(compacted_log).unwrap();
+        assert!(!Path::new(&compaction_path(&path)).exists());
+
+        let expected = [
+            (keys[0], Some(b"third".to_vec())),
+            (keys[1], None),
+            (keys[2], Some(b"newer".to_vec())),
+            (keys[3], Some(b"newer".to_vec())),
+            (added, Some(b"added".to_vec())),
+        ];
+
+        for (key, data) in &expected {
+            let stored = storage.get(key).unwrap();
+            assert_eq!(stored.map(|stored_value| stored_value.value.data), *data);
+        }
+
+        let garbage_length = storage.garbage_length;
+        assert!(garbage_length > 0);
+        storage.sync().unwrap();
+        drop(storage);
+
+        let storage = LogStorage::open(&path).unwrap();
+        assert_eq!(storage.len(), 4);
+        assert_eq!(storage.garbage_length, garbage_length);
+
+        for (key, data) in &expected {
+            let stored = storage.get(key).unwrap();
+            assert_eq!(stored.map(|stored_value| stored_value.value.data), *data);
+        }
+    }
+}
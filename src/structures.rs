@@ -1,3 +1,5 @@
+use crate::node_id::{Key, NodeId, TransactionId};
+use crate::rtt::RttEstimator;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
@@ -5,8 +7,8 @@ use std::net::SocketAddr;
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct NodeState {
     pub buckets: Vec<VecDeque<Peer>>,
-    pub node_id: String,
-    pub values: HashMap<String, Vec<u8>>,
+    pub node_id: NodeId,
+    pub values: HashMap<Key, StoredValue>,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
@@ -14,16 +16,20 @@ pub struct Peer {
     #[serde(skip_serializing, skip_deserializing)]
     pub active: bool,
     pub address: SocketAddr,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub failed_requests: u32,
     pub first_seen: u64,
     pub last_seen: Option<u64>,
-    pub node_id: String,
+    pub node_id: NodeId,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub rtt: RttEstimator,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub struct Packet {
     pub message: Message,
-    pub node_id: String,
-    pub transaction_id: String,
+    pub node_id: NodeId,
+    pub transaction_id: TransactionId,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
@@ -35,9 +41,9 @@ pub enum Message {
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub enum Request {
     Ping,
-    Store(String, Vec<u8>),
-    FindNode(String),
-    FindValue(String),
+    Store(Key, Value),
+    FindNode(NodeId),
+    FindValue(Key),
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
@@ -51,11 +57,31 @@ pub enum Response {
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub struct FoundNode {
     pub address: SocketAddr,
-    pub node_id: String,
+    pub node_id: NodeId,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub enum FoundValue {
-    Value(Vec<u8>),
+    Value(Value),
     Nodes(Vec<FoundNode>),
 }
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct Value {
+    pub data: Vec<u8>,
+    pub published_at: u64,
+    pub publisher: NodeId,
+    pub ttl: u64,
+}
+
+impl Value {
+    pub fn expires_at(&self) -> u64 {
+        self.published_at.saturating_add(self.ttl)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct StoredValue {
+    pub stored_at: u64,
+    pub value: Value,
+}
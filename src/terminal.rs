@@ -1,70 +1,54 @@
-use std::collections::HashMap;
+use client_server_test::{commands, Node};
 use std::io;
-use std::sync::{atomic::AtomicBool, Arc, Mutex};
+use std::sync::{atomic::AtomicBool, Arc};
 use std::thread::{self, JoinHandle};
 
 pub struct Terminal {
-    command_handlers:
-        Arc<Mutex<HashMap<String, Box<dyn FnMut(Vec<String>) -> Result<(), String> + Send>>>>,
-    logger: Arc<Mutex<fn(String)>>,
+    logger: fn(String),
+    node: Arc<Node>,
+    on_shutdown: Arc<dyn Fn() + Send + Sync>,
 }
 
 impl Terminal {
-    pub fn new(logger: fn(String)) -> Self {
-        let command_handlers = Arc::new(Mutex::new(HashMap::new()));
-        let logger = Arc::new(Mutex::new(logger));
-
+    /// `on_shutdown` is called when the operator asks the node to shut down.
+    pub fn new<F>(node: Arc<Node>, on_shutdown: F, logger: fn(String)) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
         Self {
-            command_handlers,
             logger,
+            node,
+            on_shutdown: Arc::new(on_shutdown),
         }
     }
 
-    pub fn on_command<F>(&mut self, command: &str, callback: F)
-    where
-        F: FnMut(Vec<String>) -> Result<(), String> + 'static + Send,
-    {
-        self.command_handlers
-            .lock()
-            .unwrap()
-            .insert(command.to_string(), Box::new(callback));
-    }
-
+    /// Reads commands from stdin until the node stops or stdin is closed. The node keeps running
+    /// without a terminal once stdin is closed.
     pub fn start(&self, is_running: Arc<AtomicBool>) -> JoinHandle<()> {
-        let command_handlers = self.command_handlers.clone();
-        let logger = self.logger.clone();
+        let logger = self.logger;
+        let node = self.node.clone();
+        let on_shutdown = self.on_shutdown.clone();
 
         thread::spawn(move || {
             while is_running.load(std::sync::atomic::Ordering::Relaxed) {
                 let mut input = String::new();
 
-                io::stdin().read_line(&mut input).unwrap();
-
-                let parts: Vec<&str> = input.split_whitespace().collect();
-
-                if parts.is_empty() {
-                    logger.lock().unwrap()("".to_string());
-                    continue;
+                match io::stdin().read_line(&mut input) {
+                    Ok(0) => {
+                        logger("Input closed, no longer reading commands".to_string());
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(error) => {
+                        logger(format!("Failed to read input: {}", error));
+                        break;
+                    }
                 }
 
-                let command = parts[0];
-                let mut handler = command_handlers.lock().unwrap();
-
-                let callback = handler.get_mut(command);
-
-                if callback.is_none() {
-                    logger.lock().unwrap()(format!("Invalid command: {}", command));
-                    continue;
-                }
-
-                let parts = parts.iter().map(|part| part.to_string()).collect();
-
-                match callback.unwrap()(parts) {
-                    Ok(()) => {}
-                    Err(error) => logger.lock().unwrap()(format!(
-                        "Failed to run command {}, {}",
-                        command, error
-                    )),
+                match commands::run(&node, &input, on_shutdown.as_ref()) {
+                    Ok(output) if output.is_empty() => {}
+                    Ok(output) => logger(output),
+                    Err(error) => logger(error),
                 }
             }
         })
@@ -0,0 +1,113 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+mod memory;
+mod tcp;
+mod udp;
+
+pub use memory::{MemoryNetwork, MemoryTransport};
+pub use tcp::TcpTransport;
+pub use udp::UdpTransport;
+
+/// Moves whole messages between nodes. The message layer only ever talks to a `Transport`, so
+/// framing, fragmentation and connection handling stay inside each backend.
+pub trait Transport: Send + Sync {
+    /// The address other nodes reach this transport on.
+    fn local_address(&self) -> SocketAddr;
+
+    /// Sends a message of at most `fragments::MAX_MESSAGE_SIZE` bytes. Delivery is not
+    /// guaranteed, so callers that need a reply must be prepared to retry.
+    fn send(&self, address: &SocketAddr, message: &[u8]) -> Result<(), String>;
+
+    /// Blocks until a message arrives, returning the sender's address and the message. Returns
+    /// `None` once the transport is closed.
+    fn receive(&self) -> Option<(SocketAddr, Vec<u8>)>;
+
+    /// Closes the transport, waking any thread blocked in `receive`.
+    fn close(&self);
+}
+
+/// The address our own socket can be reached on, substituting loopback for a wildcard bind.
+fn wakeup_address(local_address: &SocketAddr) -> SocketAddr {
+    let ip = match local_address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+
+    SocketAddr::new(ip, local_address.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_wakeup_address_uses_loopback_for_wildcard() {
+        let vectors = [
+            ("0.0.0.0:16600", "127.0.0.1:16600"),
+            ("[::]:16600", "[::1]:16600"),
+            ("192.168.1.10:16600", "192.168.1.10:16600"),
+        ];
+
+        for (local_address, expected) in vectors {
+            assert_eq!(
+                wakeup_address(&local_address.parse().unwrap()),
+                expected.parse().unwrap()
+            );
+        }
+    }
+
+    /// Sends a large message each way between two transports, then checks closing wakes a
+    /// blocked receiver.
+    fn exchange_messages(first: Arc<dyn Transport>, second: Arc<dyn Transport>) {
+        let message: Vec<u8> = (0..5000).map(|byte| byte as u8).collect();
+
+        first.send(&second.local_address(), &message).unwrap();
+        assert_eq!(
+            second.receive(),
+            Some((first.local_address(), message.clone()))
+        );
+
+        second.send(&first.local_address(), b"reply").unwrap();
+        assert_eq!(
+            first.receive(),
+            Some((second.local_address(), b"reply".to_vec()))
+        );
+
+        let second_clone = second.clone();
+        let receive_thread = thread::spawn(move || second_clone.receive());
+
+        second.close();
+
+        assert_eq!(receive_thread.join().unwrap(), None);
+
+        first.close();
+    }
+
+    #[test]
+    fn test_udp_transport_exchanges_messages() {
+        let first = UdpTransport::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let second = UdpTransport::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+        exchange_messages(Arc::new(first), Arc::new(second));
+    }
+
+    #[test]
+    fn test_tcp_transport_exchanges_messages() {
+        let first = TcpTransport::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let second = TcpTransport::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+        exchange_messages(Arc::new(first), Arc::new(second));
+    }
+
+    #[test]
+    fn test_memory_transport_exchanges_messages() {
+        let network = MemoryNetwork::new();
+        let first = network.bind(&"10.0.0.1:16600".parse().unwrap()).unwrap();
+        let second = network.bind(&"10.0.0.2:16600".parse().unwrap()).unwrap();
+
+        exchange_messages(Arc::new(first), Arc::new(second));
+    }
+}
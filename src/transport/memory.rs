@@ -0,0 +1,90 @@
+use super::Transport;
+use crate::fragments::MAX_MESSAGE_SIZE;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+
+type Mailboxes = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<(SocketAddr, Vec<u8>)>>>>;
+
+/// An in-process network for tests. Transports bound to the same network deliver messages to
+/// each other directly, and messages to addresses nobody is bound to are dropped.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    mailboxes: Mailboxes,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bind(&self, address: &SocketAddr) -> Result<MemoryTransport, String> {
+        let (receive_tx, receive_rx) = mpsc::channel::<(SocketAddr, Vec<u8>)>();
+
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+
+        if mailboxes.contains_key(address) {
+            return Err(format!("Address {} is already in use", address));
+        }
+
+        mailboxes.insert(*address, receive_tx.clone());
+
+        Ok(MemoryTransport {
+            closed: AtomicBool::new(false),
+            local_address: *address,
+            mailboxes: self.mailboxes.clone(),
+            receive_rx: Mutex::new(receive_rx),
+            receive_tx,
+        })
+    }
+}
+
+pub struct MemoryTransport {
+    closed: AtomicBool,
+    local_address: SocketAddr,
+    mailboxes: Mailboxes,
+    receive_rx: Mutex<mpsc::Receiver<(SocketAddr, Vec<u8>)>>,
+    receive_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+}
+
+impl Transport for MemoryTransport {
+    fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    fn send(&self, address: &SocketAddr, message: &[u8]) -> Result<(), String> {
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(format!(
+                "Message of {} bytes exceeds the maximum message size of {} bytes",
+                message.len(),
+                MAX_MESSAGE_SIZE
+            ));
+        }
+
+        let mailbox = self.mailboxes.lock().unwrap().get(address).cloned();
+
+        if let Some(mailbox) = mailbox {
+            let _ = mailbox.send((self.local_address, message.to_vec()));
+        }
+
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<(SocketAddr, Vec<u8>)> {
+        let message = self.receive_rx.lock().unwrap().recv().ok();
+
+        if self.closed.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        message
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.mailboxes.lock().unwrap().remove(&self.local_address);
+
+        let _ = self.receive_tx.send((self.local_address, vec![]));
+    }
+}
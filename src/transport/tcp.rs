@@ -0,0 +1,513 @@
+use super::{wakeup_address, Transport};
+use crate::fragments::MAX_MESSAGE_SIZE;
+use crate::{debug_log, error_log};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// How long the other side has to complete a handshake or take a message before it is dropped
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+// Connections that carry nothing for this long are closed, and opened again when next needed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// The first byte of a handshake, saying whether the connection carries messages or only asks
+// whether we opened a connection
+const CONNECT: u8 = 0;
+const CONFIRM: u8 = 1;
+
+type Nonce = [u8; 16];
+
+struct Connection {
+    /// Set for connections we opened, so the other side can ask us to confirm we opened them.
+    nonce: Option<Nonce>,
+    stream: Mutex<TcpStream>,
+}
+
+type Connections = Arc<Mutex<HashMap<SocketAddr, Arc<Connection>>>>;
+
+/// Sends messages as length-prefixed frames over TCP, so large messages need no fragmentation and
+/// delivery is reliable while a connection stays up. Connections are opened on first use, kept
+/// for later messages and shared by both directions.
+///
+/// Whoever opens a connection first sends the port it listens on, so replies and routing table
+/// entries use the listening address rather than the connection's ephemeral port. The claim is
+/// only trusted once the node listening at that address confirms it opened the connection; until
+/// then the connection is known by its real address, so another process on the same host cannot
+/// take over a node's traffic.
+pub struct TcpTransport {
+    closed: Arc<AtomicBool>,
+    connections: Connections,
+    local_address: SocketAddr,
+    receive_rx: Mutex<mpsc::Receiver<(SocketAddr, Vec<u8>)>>,
+    receive_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+}
+
+impl TcpTransport {
+    pub fn bind(bind_address: &SocketAddr) -> Result<Self, String> {
+        let listener = TcpListener::bind(bind_address).map_err(|error| {
+            format!(
+                "Failed to bind to address {}: {}. Is the port already in use?",
+                bind_address, error
+            )
+        })?;
+
+        let local_address = listener
+            .local_addr()
+            .map_err(|error| format!("Failed to get local address: {}", error))?;
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let connections: Connections = Arc::new(Mutex::new(HashMap::new()));
+        let (receive_tx, receive_rx) = mpsc::channel::<(SocketAddr, Vec<u8>)>();
+
+        let closed_clone = closed.clone();
+        let connections_clone = connections.clone();
+        let receive_tx_clone = receive_tx.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if closed_clone.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        error_log(format!("Failed to accept connection: {}", error));
+                        continue;
+                    }
+                };
+
+                let connections = connections_clone.clone();
+                let receive_tx = receive_tx_clone.clone();
+
+                thread::spawn(move || {
+                    if let Err(error) =
+                        accept_connection(stream, &local_address, connections, receive_tx)
+                    {
+                        error_log(error);
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            closed,
+            connections,
+            local_address,
+            receive_rx: Mutex::new(receive_rx),
+            receive_tx,
+        })
+    }
+
+    fn connection(&self, address: &SocketAddr) -> Result<Arc<Connection>, String> {
+        if let Some(connection) = self.connections.lock().unwrap().get(address) {
+            return Ok(connection.clone());
+        }
+
+        let mut stream = TcpStream::connect_timeout(address, CONNECT_TIMEOUT)
+            .map_err(|error| format!("Failed to connect to {}: {}", address, error))?;
+
+        set_timeouts(&stream, IDLE_TIMEOUT)
+            .map_err(|error| format!("Failed to configure connection to {}: {}", address, error))?;
+
+        let nonce: Nonce = rand::random();
+
+        // Registered before the handshake is sent, so the connection can be confirmed as soon as
+        // the other side asks
+        let connection =
+            match register_connection(&stream, address, Some(nonce), &self.connections)? {
+                Some(connection) => connection,
+                None => {
+                    // Another thread connected first, so its connection is used instead
+                    let _ = stream.shutdown(Shutdown::Both);
+
+                    return self.connection(address);
+                }
+            };
+
+        if let Err(error) = stream.write_all(&handshake(CONNECT, self.local_address.port(), &nonce))
+        {
+            close_connection(address, &connection, &self.connections);
+
+            return Err(format!(
+                "Failed to send handshake to {}: {}",
+                address, error
+            ));
+        }
+
+        let connections = self.connections.clone();
+        let receive_tx = self.receive_tx.clone();
+        let address = *address;
+        let reader = connection.clone();
+
+        thread::spawn(move || {
+            read_messages(stream, address, address, reader, connections, receive_tx)
+        });
+
+        Ok(connection)
+    }
+}
+
+impl Transport for TcpTransport {
+    fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    fn send(&self, address: &SocketAddr, message: &[u8]) -> Result<(), String> {
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(format!(
+                "Message of {} bytes exceeds the maximum message size of {} bytes",
+                message.len(),
+                MAX_MESSAGE_SIZE
+            ));
+        }
+
+        let connection = self.connection(address)?;
+
+        let mut frame = (message.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(message);
+
+        let result = write_frame(&mut connection.stream.lock().unwrap(), &frame);
+
+        if let Err(error) = result {
+            close_connection(address, &connection, &self.connections);
+
+            return Err(format!("Failed to send message to {}: {}", address, error));
+        }
+
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<(SocketAddr, Vec<u8>)> {
+        let message = self.receive_rx.lock().unwrap().recv().ok();
+
+        if self.closed.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        message
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+
+        // Wake the accepting thread, then hang up every connection so their readers finish
+        let _ = TcpStream::connect_timeout(&wakeup_address(&self.local_address), CONNECT_TIMEOUT);
+
+        for (_, connection) in self.connections.lock().unwrap().drain() {
+            let _ = connection.stream.lock().unwrap().shutdown(Shutdown::Both);
+        }
+
+        let _ = self.receive_tx.send((self.local_address, vec![]));
+    }
+}
+
+fn handshake(kind: u8, port: u16, nonce: &Nonce) -> Vec<u8> {
+    let mut handshake = vec![kind];
+    handshake.extend_from_slice(&port.to_be_bytes());
+    handshake.extend_from_slice(nonce);
+
+    handshake
+}
+
+/// Writes a whole frame within `IO_TIMEOUT`, so a peer that stops reading, or reads slowly, is
+/// dropped rather than stalling us.
+fn write_frame(stream: &mut TcpStream, frame: &[u8]) -> io::Result<()> {
+    let deadline = Instant::now() + IO_TIMEOUT;
+    let mut written = 0;
+
+    while written < frame.len() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the peer is not reading",
+            ));
+        }
+
+        stream.set_write_timeout(Some(remaining))?;
+
+        match stream.write(&frame[written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(count) => written += count,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets how long reads may wait for the other side, and how long writes may wait for it to make
+/// room.
+fn set_timeouts(stream: &TcpStream, read_timeout: Duration) -> io::Result<()> {
+    stream.set_read_timeout(Some(read_timeout))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))
+}
+
+fn accept_connection(
+    mut stream: TcpStream,
+    local_address: &SocketAddr,
+    connections: Connections,
+    receive_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+) -> Result<(), String> {
+    let peer_address = stream
+        .peer_addr()
+        .map_err(|error| format!("Failed to get peer address: {}", error))?;
+
+    set_timeouts(&stream, IO_TIMEOUT).map_err(|error| {
+        format!(
+            "Failed to configure connection from {}: {}",
+            peer_address, error
+        )
+    })?;
+
+    let mut kind = [0; 1];
+    let mut port = [0; 2];
+    let mut nonce: Nonce = [0; 16];
+    stream
+        .read_exact(&mut kind)
+        .and_then(|_| stream.read_exact(&mut port))
+        .and_then(|_| stream.read_exact(&mut nonce))
+        .map_err(|error| format!("Failed to read handshake from {}: {}", peer_address, error))?;
+
+    let claimed_address = SocketAddr::new(peer_address.ip(), u16::from_be_bytes(port));
+
+    match kind[0] {
+        CONNECT => {}
+        CONFIRM => {
+            // The other side is asking whether we opened the connection it received with this
+            // nonce, which only the node listening at our address can know
+            let opened = connections
+                .lock()
+                .unwrap()
+                .get(&claimed_address)
+                .is_some_and(|connection| connection.nonce == Some(nonce));
+
+            return stream.write_all(&[opened as u8]).map_err(|error| {
+                format!(
+                    "Failed to confirm connection to {}: {}",
+                    peer_address, error
+                )
+            });
+        }
+        kind => {
+            return Err(format!(
+                "Dropping connection from {} with unknown handshake {}",
+                peer_address, kind
+            ))
+        }
+    }
+
+    let address = if confirm_connection(&claimed_address, local_address, &nonce) {
+        claimed_address
+    } else {
+        debug_log(format!(
+            "Could not confirm {} listens on {}, using its connection's address",
+            peer_address, claimed_address
+        ));
+
+        peer_address
+    };
+
+    stream
+        .set_read_timeout(Some(IDLE_TIMEOUT))
+        .map_err(|error| {
+            format!(
+                "Failed to configure connection from {}: {}",
+                peer_address, error
+            )
+        })?;
+
+    // A live connection to the address is kept rather than replaced, so this one is only known
+    // by its own address and just carries messages in
+    let (key, connection) = match register_connection(&stream, &address, None, &connections)? {
+        Some(connection) => (address, connection),
+        None => match register_connection(&stream, &peer_address, None, &connections)? {
+            Some(connection) => (peer_address, connection),
+            None => {
+                return Err(format!(
+                    "Dropping connection from {}, its address is already in use",
+                    peer_address
+                ))
+            }
+        },
+    };
+
+    read_messages(stream, address, key, connection, connections, receive_tx);
+
+    Ok(())
+}
+
+/// Asks the node listening at `address` whether it opened the connection it identified with
+/// `nonce`.
+fn confirm_connection(address: &SocketAddr, local_address: &SocketAddr, nonce: &Nonce) -> bool {
+    let confirm = || -> io::Result<bool> {
+        let mut stream = TcpStream::connect_timeout(address, CONNECT_TIMEOUT)?;
+        set_timeouts(&stream, IO_TIMEOUT)?;
+
+        stream.write_all(&handshake(CONFIRM, local_address.port(), nonce))?;
+
+        let mut opened = [0; 1];
+        stream.read_exact(&mut opened)?;
+
+        Ok(opened[0] == 1)
+    };
+
+    confirm().unwrap_or(false)
+}
+
+/// Registers a connection under the address unless a connection is already registered there, in
+/// which case `None` is returned.
+fn register_connection(
+    stream: &TcpStream,
+    address: &SocketAddr,
+    nonce: Option<Nonce>,
+    connections: &Connections,
+) -> Result<Option<Arc<Connection>>, String> {
+    let writer = stream
+        .try_clone()
+        .map_err(|error| format!("Failed to clone connection to {}: {}", address, error))?;
+
+    let mut connections = connections.lock().unwrap();
+
+    if connections.contains_key(address) {
+        return Ok(None);
+    }
+
+    let connection = Arc::new(Connection {
+        nonce,
+        stream: Mutex::new(writer),
+    });
+
+    connections.insert(*address, connection.clone());
+
+    Ok(Some(connection))
+}
+
+/// Forwards frames from a connection as coming from `address` until it closes or goes idle,
+/// then forgets the connection, which is registered under `key`.
+fn read_messages(
+    mut stream: TcpStream,
+    address: SocketAddr,
+    key: SocketAddr,
+    connection: Arc<Connection>,
+    connections: Connections,
+    receive_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+) {
+    loop {
+        let mut length = [0; 4];
+
+        if stream.read_exact(&mut length).is_err() {
+            break;
+        }
+
+        let length = u32::from_be_bytes(length) as usize;
+
+        if length > MAX_MESSAGE_SIZE {
+            error_log(format!(
+                "Closing connection to {}, received {} byte message exceeding the maximum of {} bytes",
+                address, length, MAX_MESSAGE_SIZE
+            ));
+            break;
+        }
+
+        let mut message = vec![0; length];
+
+        if stream.read_exact(&mut message).is_err() || receive_tx.send((address, message)).is_err()
+        {
+            break;
+        }
+    }
+
+    close_connection(&key, &connection, &connections);
+}
+
+/// Hangs up a connection and removes it, unless it has already been replaced by a newer one.
+fn close_connection(address: &SocketAddr, connection: &Arc<Connection>, connections: &Connections) {
+    let _ = connection.stream.lock().unwrap().shutdown(Shutdown::Both);
+
+    let mut connections = connections.lock().unwrap();
+
+    if connections
+        .get(address)
+        .is_some_and(|current| Arc::ptr_eq(current, connection))
+    {
+        connections.remove(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind() -> TcpTransport {
+        TcpTransport::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_unconfirmed_port_claims_do_not_take_over_an_address() {
+        let node = bind();
+        let peer = bind();
+
+        peer.send(&node.local_address(), b"hello").unwrap();
+        assert_eq!(
+            node.receive(),
+            Some((peer.local_address(), b"hello".to_vec()))
+        );
+
+        // Another process on the peer's host claims the peer's port
+        let mut impostor = TcpStream::connect(node.local_address()).unwrap();
+        let impostor_address = impostor.local_addr().unwrap();
+        impostor
+            .write_all(&handshake(CONNECT, peer.local_address().port(), &[0; 16]))
+            .unwrap();
+        impostor.write_all(&5u32.to_be_bytes()).unwrap();
+        impostor.write_all(b"spoof").unwrap();
+
+        assert_eq!(node.receive(), Some((impostor_address, b"spoof".to_vec())));
+
+        // Messages for the peer still reach the peer
+        node.send(&peer.local_address(), b"reply").unwrap();
+        assert_eq!(
+            peer.receive(),
+            Some((node.local_address(), b"reply".to_vec()))
+        );
+
+        node.close();
+        peer.close();
+    }
+
+    #[test]
+    fn test_peer_that_stops_reading_is_dropped() {
+        let node = bind();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // The peer accepts the connection but never reads from it
+        let accept_thread = thread::spawn(move || listener.accept().unwrap());
+
+        let message = vec![0; MAX_MESSAGE_SIZE];
+        let mut result = Ok(());
+
+        for _ in 0..1000 {
+            result = node.send(&address, &message);
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        assert!(result.is_err());
+        assert!(node.connections.lock().unwrap().is_empty());
+
+        accept_thread.join().unwrap();
+        node.close();
+    }
+}
@@ -0,0 +1,96 @@
+use super::{wakeup_address, Transport};
+use crate::error_log;
+use crate::fragments::{split_message, Reassembler, MAX_DATAGRAM_SIZE};
+use crate::node_id::NodeId;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Sends messages as UDP datagrams, splitting anything larger than `MAX_DATAGRAM_SIZE` into
+/// fragments that are reassembled on receipt.
+pub struct UdpTransport {
+    closed: AtomicBool,
+    local_address: SocketAddr,
+    reassembler: Mutex<Reassembler>,
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn bind(bind_address: &SocketAddr) -> Result<Self, String> {
+        let socket = UdpSocket::bind(bind_address).map_err(|error| {
+            format!(
+                "Failed to bind to address {}: {}. Is the port already in use?",
+                bind_address, error
+            )
+        })?;
+
+        let local_address = socket
+            .local_addr()
+            .map_err(|error| format!("Failed to get local address: {}", error))?;
+
+        Ok(Self {
+            closed: AtomicBool::new(false),
+            local_address,
+            reassembler: Mutex::new(Reassembler::new()),
+            socket,
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    fn send(&self, address: &SocketAddr, message: &[u8]) -> Result<(), String> {
+        // Fragments are grouped by a random message ID, so a message can be reassembled
+        // without the transport knowing anything about its contents
+        for datagram in split_message(&NodeId::random(), message)? {
+            self.socket
+                .send_to(&datagram, address)
+                .map_err(|error| format!("Failed to send datagram to {}: {}", address, error))?;
+        }
+
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<(SocketAddr, Vec<u8>)> {
+        // One byte over the limit so that oversized datagrams can be detected and dropped
+        let mut buffer = [0; MAX_DATAGRAM_SIZE + 1];
+        let mut reassembler = self.reassembler.lock().unwrap();
+
+        loop {
+            let result = self.socket.recv_from(&mut buffer);
+
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            let (amt, src) = match result {
+                Ok((0, _)) => continue,
+                Ok(received) => received,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    error_log(format!("Failed to receive datagram: {}", error));
+                    continue;
+                }
+            };
+
+            match reassembler.add_datagram(&src, &buffer[..amt]) {
+                Ok(Some(message)) => return Some((src, message)),
+                Ok(None) => {}
+                Err(error) => error_log(error),
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+
+        // Wake the receiving thread with an empty datagram to our own socket
+        let _ = self
+            .socket
+            .send_to(&[], wakeup_address(&self.local_address));
+    }
+}
@@ -1,27 +1,373 @@
+use crate::clock::Clock;
+use crate::node_id::{Key, NodeId};
+use crate::storage::{CompactedLog, Compaction, Storage, ValueMetadata};
+use crate::structures;
 use std::collections::HashMap;
+use std::sync::Arc;
+
+pub const DEFAULT_TTL: u64 = 24 * 60 * 60;
+/// The longest a value is kept without being republished, whatever TTL it was sent with.
+pub const MAX_TTL: u64 = DEFAULT_TTL;
+// How far ahead of our clock a publisher's clock may be
+const MAX_CLOCK_SKEW: u64 = 5 * 60;
+pub const REPLICATE_INTERVAL: u64 = 60 * 60;
+pub const REPUBLISH_INTERVAL: u64 = 23 * 60 * 60;
 
 pub struct ValueStore {
-    values: HashMap<String, Vec<u8>>,
+    clock: Arc<dyn Clock>,
+    storage: Box<dyn Storage>,
 }
 
 impl ValueStore {
-    pub fn new(values: HashMap<String, Vec<u8>>) -> Result<Self, String> {
-        Ok(Self { values })
+    pub fn new(storage: Box<dyn Storage>, clock: Arc<dyn Clock>) -> Result<Self, String> {
+        let mut value_store = Self { clock, storage };
+
+        value_store.purge_expired()?;
+
+        Ok(value_store)
     }
 
-    pub fn store(&mut self, key: &str, value: &[u8]) {
-        self.values.insert(key.to_string(), value.to_vec());
+    /// Stores a value unless it has already expired or we hold a more recent publication of it.
+    /// TTLs are capped at `MAX_TTL`, and values published further in the future than clocks can
+    /// drift apart are rejected, so a peer cannot keep a value forever or shadow later
+    /// republishes. Returns whether the value was stored.
+    pub fn store(&mut self, key: &Key, value: &structures::Value) -> Result<bool, String> {
+        let now = self.clock.unix_timestamp();
+
+        if value.published_at > now.saturating_add(MAX_CLOCK_SKEW) {
+            return Ok(false);
+        }
+
+        let value = structures::Value {
+            ttl: value.ttl.min(MAX_TTL),
+            ..value.clone()
+        };
+
+        if value.expires_at() <= now {
+            return Ok(false);
+        }
+
+        if let Some(existing) = self.storage.metadata(key) {
+            if existing.published_at > value.published_at {
+                return Ok(false);
+            }
+        }
+
+        self.storage.put(
+            key,
+            &structures::StoredValue {
+                stored_at: now,
+                value,
+            },
+        )?;
+
+        Ok(true)
     }
 
-    pub fn retrieve(&self, key: &str) -> Option<&Vec<u8>> {
-        self.values.get(key)
+    pub fn retrieve(&self, key: &Key) -> Result<Option<structures::Value>, String> {
+        match self.storage.metadata(key) {
+            Some(metadata) if metadata.expires_at() > self.clock.unix_timestamp() => Ok(self
+                .storage
+                .get(key)?
+                .map(|stored_value| stored_value.value)),
+            _ => Ok(None),
+        }
     }
 
     pub fn len(&self) -> usize {
-        self.values.len()
+        self.storage.len()
+    }
+
+    /// Reads every unexpired value into memory, for saving them to a single state file.
+    pub fn values(&self) -> Result<HashMap<Key, structures::StoredValue>, String> {
+        let now = self.clock.unix_timestamp();
+        let mut values = HashMap::new();
+
+        for (key, metadata) in self.storage.entries() {
+            if metadata.expires_at() <= now {
+                continue;
+            }
+
+            if let Some(stored_value) = self.storage.get(&key)? {
+                values.insert(key, stored_value);
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Removes expired values, returning how many were removed.
+    pub fn purge_expired(&mut self) -> Result<usize, String> {
+        let now = self.clock.unix_timestamp();
+        let mut count = 0;
+
+        for (key, metadata) in self.storage.entries() {
+            if metadata.expires_at() <= now {
+                self.storage.remove(&key)?;
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Makes the values stored so far durable.
+    pub fn sync(&self) -> Result<(), String> {
+        self.storage.sync()
+    }
+
+    /// Plans reclaiming the space the storage holds for replaced and removed values, if it is
+    /// worth it.
+    pub fn start_compaction(&self) -> Result<Option<Compaction>, String> {
+        self.storage.start_compaction()
+    }
+
+    pub fn finish_compaction(&mut self, compacted_log: CompactedLog) -> Result<(), String> {
+        self.storage.finish_compaction(compacted_log)
+    }
+
+    /// Returns values we originally published that are due to be published again, renewing
+    /// their publication time so they are not returned again until the next interval.
+    pub fn take_due_for_republish(
+        &mut self,
+        local_node_id: &NodeId,
+    ) -> Result<Vec<(Key, structures::Value)>, String> {
+        let now = self.clock.unix_timestamp();
+
+        let mut due = self.take_due(|metadata| {
+            metadata.publisher == *local_node_id
+                && now.saturating_sub(metadata.published_at) >= REPUBLISH_INTERVAL
+        })?;
+
+        for (_, stored_value) in &mut due {
+            stored_value.value.published_at = now;
+        }
+
+        self.renew(due, now)
+    }
+
+    /// Returns values published by other nodes that have not been stored or replicated within
+    /// the last `REPLICATE_INTERVAL`, marking them as replicated.
+    pub fn take_due_for_replication(
+        &mut self,
+        local_node_id: &NodeId,
+    ) -> Result<Vec<(Key, structures::Value)>, String> {
+        let now = self.clock.unix_timestamp();
+
+        let due = self.take_due(|metadata| {
+            metadata.publisher != *local_node_id
+                && metadata.expires_at() > now
+                && now.saturating_sub(metadata.stored_at) >= REPLICATE_INTERVAL
+        })?;
+
+        self.renew(due, now)
+    }
+
+    fn take_due(
+        &self,
+        is_due: impl Fn(&ValueMetadata) -> bool,
+    ) -> Result<Vec<(Key, structures::StoredValue)>, String> {
+        let mut due = Vec::new();
+
+        for (key, metadata) in self.storage.entries() {
+            if !is_due(&metadata) {
+                continue;
+            }
+
+            if let Some(stored_value) = self.storage.get(&key)? {
+                due.push((key, stored_value));
+            }
+        }
+
+        Ok(due)
+    }
+
+    /// Saves the values as stored now and returns them.
+    fn renew(
+        &mut self,
+        due: Vec<(Key, structures::StoredValue)>,
+        now: u64,
+    ) -> Result<Vec<(Key, structures::Value)>, String> {
+        due.into_iter()
+            .map(|(key, mut stored_value)| {
+                stored_value.stored_at = now;
+                self.storage.put(&key, &stored_value)?;
+
+                Ok((key, stored_value.value))
+            })
+            .collect()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::storage::MemoryStorage;
+    use std::time::Duration;
+
+    const NOW: u64 = 1_700_000_000;
+
+    const LOCAL_ID: &str = "0000000000000000000000000000000000000000";
+    const REMOTE_ID: &str = "ffffffffffffffffffffffffffffffffffffffff";
+
+    fn id(value: &str) -> NodeId {
+        value.parse().unwrap()
+    }
+
+    fn clock() -> Arc<ManualClock> {
+        Arc::new(ManualClock::new(NOW))
+    }
+
+    fn value_store(
+        values: HashMap<Key, structures::StoredValue>,
+        clock: Arc<ManualClock>,
+    ) -> ValueStore {
+        ValueStore::new(Box::new(MemoryStorage::new(values)), clock).unwrap()
+    }
+
+    fn value(publisher: &str, age: u64, ttl: u64) -> structures::Value {
+        structures::Value {
+            data: b"value".to_vec(),
+            published_at: NOW - age,
+            publisher: id(publisher),
+            ttl,
+        }
+    }
+
+    #[test]
+    fn test_store_rejects_expired_and_older_values() {
+        let mut value_store = value_store(HashMap::new(), clock());
+        let key = id("0123456789abcdef0123456789abcdef01234567");
+
+        assert!(!value_store.store(&key, &value(REMOTE_ID, 120, 60)).unwrap());
+        assert!(value_store.store(&key, &value(REMOTE_ID, 10, 60)).unwrap());
+        assert!(!value_store.store(&key, &value(REMOTE_ID, 20, 60)).unwrap());
+        assert_eq!(value_store.len(), 1);
+    }
+
+    #[test]
+    fn test_store_caps_ttl_and_rejects_future_publications() {
+        let mut value_store = value_store(HashMap::new(), clock());
+        let key = id("0123456789abcdef0123456789abcdef01234567");
+
+        assert!(value_store
+            .store(&key, &value(REMOTE_ID, 0, u64::MAX))
+            .unwrap());
+        assert_eq!(value_store.retrieve(&key).unwrap().unwrap().ttl, MAX_TTL);
+
+        let mut future = value(REMOTE_ID, 0, 60);
+        future.published_at = NOW + MAX_CLOCK_SKEW + 1;
+
+        assert!(!value_store.store(&key, &future).unwrap());
+
+        // A publisher whose clock is slightly ahead is still accepted
+        future.published_at = NOW + MAX_CLOCK_SKEW;
+
+        assert!(value_store.store(&key, &future).unwrap());
+    }
+
+    #[test]
+    fn test_expired_values_are_purged() {
+        let key = id("0123456789abcdef0123456789abcdef01234567");
+        let expired_key = id("1123456789abcdef0123456789abcdef01234567");
+
+        let mut values = HashMap::new();
+        values.insert(
+            key,
+            structures::StoredValue {
+                stored_at: NOW,
+                value: value(REMOTE_ID, 10, 60),
+            },
+        );
+        values.insert(
+            expired_key,
+            structures::StoredValue {
+                stored_at: NOW,
+                value: value(REMOTE_ID, 120, 60),
+            },
+        );
+
+        let value_store = value_store(values, clock());
+
+        assert_eq!(value_store.len(), 1);
+        assert!(value_store.retrieve(&key).unwrap().is_some());
+        assert!(value_store.retrieve(&expired_key).unwrap().is_none());
+        assert!(!value_store.values().unwrap().contains_key(&expired_key));
+    }
+
+    #[test]
+    fn test_values_expire_as_clock_advances() {
+        let clock = clock();
+        let mut value_store = value_store(HashMap::new(), clock.clone());
+        let key = id("0123456789abcdef0123456789abcdef01234567");
+
+        assert!(value_store.store(&key, &value(REMOTE_ID, 0, 60)).unwrap());
+
+        clock.advance(Duration::from_secs(59));
+        assert!(value_store.retrieve(&key).unwrap().is_some());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(value_store.retrieve(&key).unwrap().is_none());
+        assert_eq!(value_store.purge_expired().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_take_due_for_republish_only_returns_own_values() {
+        let mut value_store = value_store(HashMap::new(), clock());
+        let own_key = id("0123456789abcdef0123456789abcdef01234567");
+        let remote_key = id("1123456789abcdef0123456789abcdef01234567");
+
+        value_store
+            .store(&own_key, &value(LOCAL_ID, REPUBLISH_INTERVAL, DEFAULT_TTL))
+            .unwrap();
+        value_store
+            .store(
+                &remote_key,
+                &value(REMOTE_ID, REPUBLISH_INTERVAL, DEFAULT_TTL),
+            )
+            .unwrap();
+
+        let due = value_store.take_due_for_republish(&id(LOCAL_ID)).unwrap();
+
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0, own_key);
+        assert!(value_store
+            .take_due_for_republish(&id(LOCAL_ID))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_take_due_for_replication_skips_recently_stored_values() {
+        let remote_key = id("1123456789abcdef0123456789abcdef01234567");
+        let recent_key = id("2123456789abcdef0123456789abcdef01234567");
+
+        let mut values = HashMap::new();
+        values.insert(
+            remote_key,
+            structures::StoredValue {
+                stored_at: NOW - REPLICATE_INTERVAL,
+                value: value(REMOTE_ID, 10, DEFAULT_TTL),
+            },
+        );
+        values.insert(
+            recent_key,
+            structures::StoredValue {
+                stored_at: NOW,
+                value: value(REMOTE_ID, 10, DEFAULT_TTL),
+            },
+        );
+
+        let mut value_store = value_store(values, clock());
+
+        let due = value_store.take_due_for_replication(&id(LOCAL_ID)).unwrap();
 
-    pub fn values(&self) -> HashMap<String, Vec<u8>> {
-        self.values.clone()
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0, remote_key);
+        assert!(value_store
+            .take_due_for_replication(&id(LOCAL_ID))
+            .unwrap()
+            .is_empty());
     }
 }